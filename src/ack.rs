@@ -0,0 +1,189 @@
+//! Acknowledgements from the receiver back to the sender.
+//!
+//! The receiver periodically sends the newest sequence number it has received along with
+//! a bitfield of the 64 preceding ones, like many game networking protocols do.
+//! Since each acknowledgement covers a window of datagrams, a few of them can be lost
+//! without the sender losing track of what has arrived.
+//! A cleared bit works as a negative acknowledgement once a datagram falls out of the window.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use crate::protocol::seq_newer;
+
+/// The number of sequence numbers preceding the latest one covered by an acknowledgement.
+pub const ACK_WINDOW: u32 = 64;
+
+/// The number of sent datagrams to keep track of until they are acknowledged or deemed lost.
+const MAX_IN_FLIGHT: usize = 1024;
+
+/// The receiver side state of which datagrams have been received.
+#[derive(Default)]
+pub struct AckWindow {
+    latest: Option<(u32, Instant)>,
+    /// Bit `i` is set if `latest - 1 - i` has been received.
+    mask: u64,
+    /// Whether something has been received since the last acknowledgement.
+    dirty: bool,
+}
+
+impl AckWindow {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, seq: u32) {
+        self.dirty = true;
+        let Some((latest, _)) = self.latest else {
+            self.latest = Some((seq, Instant::now()));
+            return;
+        };
+        if seq_newer(seq, latest) {
+            let shift = seq.wrapping_sub(latest);
+            self.mask = if shift <= ACK_WINDOW {
+                // The old latest becomes bit `shift - 1`
+                (self.mask << (shift - 1) << 1) | 1 << (shift - 1)
+            } else {
+                0
+            };
+            self.latest = Some((seq, Instant::now()));
+        } else {
+            let offset = latest.wrapping_sub(seq);
+            if 0 < offset && offset <= ACK_WINDOW {
+                self.mask |= 1 << (offset - 1);
+            }
+        }
+    }
+
+    /// Returns the fields of an acknowledgement, `(latest, mask, ack_delay)`, if anything new
+    /// has been received since the last call.
+    pub fn take_ack(&mut self) -> Option<(u32, u64, Duration)> {
+        if !self.dirty {
+            return None;
+        }
+        self.dirty = false;
+        let (latest, received) = self.latest?;
+        Some((latest, self.mask, received.elapsed()))
+    }
+}
+
+/// Returns the sequence numbers acknowledged by `latest` and `mask`, newest first.
+pub fn acked_seqs(latest: u32, mask: u64) -> impl Iterator<Item = u32> {
+    std::iter::once(latest).chain(
+        (0..ACK_WINDOW)
+            .filter(move |i| mask & (1 << i) != 0)
+            .map(move |i| latest.wrapping_sub(i + 1)),
+    )
+}
+
+struct InFlight {
+    seq: u32,
+    sent: Instant,
+    acked: bool,
+}
+
+/// A snapshot of the link quality to a peer as seen by the sender.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LinkStats {
+    /// The number of datagrams acknowledged
+    pub acked: usize,
+    /// The number of datagrams that fell out of the acknowledgement window without being acked
+    pub lost: usize,
+    /// The exponential moving average of the loss ratio
+    pub loss_rate: f64,
+    /// The smoothed round-trip time
+    pub rtt: Option<Duration>,
+    /// The mean deviation of the round-trip time
+    pub rtt_var: Duration,
+}
+
+/// The sender side tracker of the datagrams in flight to a peer.
+#[derive(Default)]
+pub struct LinkTracker {
+    in_flight: VecDeque<InFlight>,
+    stats: LinkStats,
+}
+
+impl LinkTracker {
+    /// The weight of a new sample in the moving average of the loss rate.
+    const LOSS_ALPHA: f64 = 0.01;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn stats(&self) -> LinkStats {
+        self.stats
+    }
+
+    pub fn on_sent(&mut self, seq: u32) {
+        if MAX_IN_FLIGHT <= self.in_flight.len() {
+            let oldest = self.in_flight.pop_front().unwrap();
+            self.resolve(oldest.acked);
+        }
+        self.in_flight.push_back(InFlight {
+            seq,
+            sent: Instant::now(),
+            acked: false,
+        });
+    }
+
+    /// Processes an acknowledgement and returns the sequence numbers newly acknowledged by it.
+    pub fn on_ack(&mut self, latest: u32, mask: u64, ack_delay: Duration) -> Vec<u32> {
+        let now = Instant::now();
+        let mut newly_acked = vec![];
+        for seq in acked_seqs(latest, mask) {
+            let Some(entry) = self.in_flight.iter_mut().find(|entry| entry.seq == seq) else {
+                continue;
+            };
+            if entry.acked {
+                continue;
+            }
+            entry.acked = true;
+            newly_acked.push(seq);
+            // Only the latest one has a known delay on the receiver side
+            if seq == latest {
+                let sent = entry.sent;
+                self.add_rtt_sample((now - sent).saturating_sub(ack_delay));
+            }
+        }
+
+        // Datagrams older than the window can never be acknowledged anymore
+        while let Some(front) = self.in_flight.front() {
+            if ACK_WINDOW < latest.wrapping_sub(front.seq) && !seq_newer(front.seq, latest) {
+                let front = self.in_flight.pop_front().unwrap();
+                self.resolve(front.acked);
+            } else {
+                break;
+            }
+        }
+        newly_acked
+    }
+
+    fn resolve(&mut self, acked: bool) {
+        if acked {
+            self.stats.acked += 1;
+        } else {
+            self.stats.lost += 1;
+        }
+        let sample = if acked { 0. } else { 1. };
+        self.stats.loss_rate += (sample - self.stats.loss_rate) * Self::LOSS_ALPHA;
+    }
+
+    /// Updates the smoothed RTT in the same way as TCP (RFC 6298).
+    fn add_rtt_sample(&mut self, sample: Duration) {
+        let stats = &mut self.stats;
+        match stats.rtt {
+            None => {
+                stats.rtt = Some(sample);
+                stats.rtt_var = sample / 2;
+            }
+            Some(rtt) => {
+                stats.rtt_var = (stats.rtt_var * 3 + sample.abs_diff(rtt)) / 4;
+                stats.rtt = Some((rtt * 7 + sample) / 8);
+            }
+        }
+    }
+}
@@ -0,0 +1,210 @@
+//! Host addresses given on the command line.
+
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    str::FromStr,
+};
+
+/// A host given by the user, which can be an IPv4 or IPv6 address, an IPv6 address in brackets,
+/// a socket address with a port, or a hostname to resolve with an optional port.
+/// Hostnames are resolved when parsed, so that a typo is reported right away.
+#[derive(Clone, Debug)]
+pub struct Host {
+    name: String,
+    /// The port given along with the host, which overrides the default one
+    port: Option<u16>,
+    addrs: Vec<IpAddr>,
+}
+
+impl Host {
+    /// The first address of the host, with `default_port` unless the host has its own port.
+    pub fn socket_addr(&self, default_port: u16) -> SocketAddr {
+        SocketAddr::new(self.addrs[0], self.port.unwrap_or(default_port))
+    }
+
+    /// Like [`Self::socket_addr`], but picks an address that a socket bound to `local` can
+    /// reach, preferring the same family. An IPv6 socket bound with
+    /// [`crate::transport::UdpTransport::bind_dual_stack`] reaches IPv4 addresses too, while an
+    /// IPv4 socket cannot reach IPv6 ones. Returns `None` if there is no such address.
+    pub fn socket_addr_from(&self, default_port: u16, local: SocketAddr) -> Option<SocketAddr> {
+        let ip = self
+            .addrs
+            .iter()
+            .find(|ip| ip.is_ipv4() == local.is_ipv4())
+            .or_else(|| self.addrs.first().filter(|_| local.is_ipv6()))?;
+        Some(SocketAddr::new(*ip, self.port.unwrap_or(default_port)))
+    }
+}
+
+impl FromStr for Host {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Err("the host is empty".to_string());
+        }
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Ok(Self {
+                name: s.to_string(),
+                port: Some(addr.port()),
+                addrs: vec![addr.ip()],
+            });
+        }
+        let unbracketed = s
+            .strip_prefix('[')
+            .and_then(|s| s.strip_suffix(']'))
+            .unwrap_or(s);
+        if let Ok(ip) = unbracketed.parse::<IpAddr>() {
+            return Ok(Self {
+                name: s.to_string(),
+                port: None,
+                addrs: vec![ip],
+            });
+        }
+        // An IPv6 address would have been parsed above, so any colon separates a port
+        let (hostname, port) = match s.rsplit_once(':') {
+            Some((hostname, _)) if hostname.contains(':') => {
+                return Err(format!("{s:?} is not an IP address or a hostname"));
+            }
+            Some((hostname, port)) => {
+                let port = port
+                    .parse::<u16>()
+                    .map_err(|_| format!("invalid port number {port:?} in {s:?}"))?;
+                (hostname, Some(port))
+            }
+            None => (s, None),
+        };
+        if hostname.is_empty() {
+            return Err(format!("{s:?} is not an IP address or a hostname"));
+        }
+        let addrs: Vec<_> = (hostname, 0)
+            .to_socket_addrs()
+            .map_err(|e| format!("failed to resolve {hostname:?}: {e}"))?
+            .map(|addr| addr.ip())
+            .collect();
+        if addrs.is_empty() {
+            return Err(format!("{hostname:?} resolved to no addresses"));
+        }
+        Ok(Self {
+            name: s.to_string(),
+            port,
+            addrs,
+        })
+    }
+}
+
+impl fmt::Display for Host {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Host {
+        s.parse().unwrap()
+    }
+
+    fn parse_err(s: &str) -> String {
+        s.parse::<Host>().unwrap_err()
+    }
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn ip_addresses() {
+        let host = parse("10.0.0.1");
+        assert_eq!(host.socket_addr(34254), addr("10.0.0.1:34254"));
+        assert_eq!(host.to_string(), "10.0.0.1");
+
+        assert_eq!(parse("::1").socket_addr(34254), addr("[::1]:34254"));
+        assert_eq!(parse("[::1]").socket_addr(34254), addr("[::1]:34254"));
+        assert_eq!(parse(" [fe80::2] ").to_string(), "[fe80::2]");
+    }
+
+    #[test]
+    fn socket_addresses_override_the_port() {
+        assert_eq!(
+            parse("10.0.0.1:5000").socket_addr(34254),
+            addr("10.0.0.1:5000")
+        );
+        assert_eq!(parse("[::1]:5000").socket_addr(34254), addr("[::1]:5000"));
+        assert_eq!(parse("[::1]:5000").to_string(), "[::1]:5000");
+    }
+
+    #[test]
+    fn hostnames_are_resolved() {
+        let host = parse("localhost");
+        assert!(host.socket_addr(34254).ip().is_loopback());
+        assert_eq!(host.socket_addr(34254).port(), 34254);
+        assert_eq!(host.to_string(), "localhost");
+
+        let host = parse("localhost:5000");
+        assert!(host.socket_addr(34254).ip().is_loopback());
+        assert_eq!(host.socket_addr(34254).port(), 5000);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(parse_err(""), "the host is empty");
+        assert_eq!(parse_err("  "), "the host is empty");
+        assert_eq!(
+            parse_err("localhost:http"),
+            r#"invalid port number "http" in "localhost:http""#
+        );
+        assert_eq!(
+            parse_err("10.0.0.1:70000"),
+            r#"invalid port number "70000" in "10.0.0.1:70000""#
+        );
+        assert_eq!(
+            parse_err(":5000"),
+            r#"":5000" is not an IP address or a hostname"#
+        );
+        assert_eq!(
+            parse_err("[::1"),
+            r#""[::1" is not an IP address or a hostname"#
+        );
+        assert_eq!(
+            parse_err("a:b:c"),
+            r#""a:b:c" is not an IP address or a hostname"#
+        );
+        // The top level domain is reserved to never resolve
+        let err = parse_err("nonexistent.invalid:5000");
+        assert!(
+            err.starts_with(r#"failed to resolve "nonexistent.invalid": "#),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn socket_addr_from_picks_a_reachable_family() {
+        let both = Host {
+            name: "both".to_string(),
+            port: None,
+            addrs: vec!["::1".parse().unwrap(), "127.0.0.1".parse().unwrap()],
+        };
+        let v4_local = addr("0.0.0.0:0");
+        let v6_local = addr("[::]:0");
+        assert_eq!(
+            both.socket_addr_from(34254, v4_local),
+            Some(addr("127.0.0.1:34254"))
+        );
+        assert_eq!(
+            both.socket_addr_from(34254, v6_local),
+            Some(addr("[::1]:34254"))
+        );
+
+        // A dual-stack IPv6 socket reaches IPv4 hosts, but not the other way around
+        assert_eq!(
+            parse("10.0.0.1:5000").socket_addr_from(34254, v6_local),
+            Some(addr("10.0.0.1:5000"))
+        );
+        assert_eq!(parse("[::1]").socket_addr_from(34254, v4_local), None);
+    }
+}
@@ -1,76 +1,219 @@
-use clap::Parser;
+use clap::{CommandFactory, FromArgMatches, Parser};
 use eframe::{
     egui::{self, Color32, Context, Frame, Ui},
     emath::Align2,
     epaint::{pos2, FontId, Pos2, Rect},
 };
 
+use patchjuggler::{
+    addr::Host,
+    config::{self, Config},
+    divergence::{Divergence, DivergenceHistory},
+    impair::{ImpairedTransport, ImpairmentConfig, ImpairmentStats},
+    interp::{InterpolationBuffer, PlayoutMode},
+    lockstep::{LockstepReceiver, LockstepStatus},
+    object::{BoidParams, FindScanner},
+    reckoning::Extrapolator,
+    render_config_file, render_divergence, render_ghosts, render_impairment, render_objects,
+    render_stats,
+    stats::{StatsHistory, StatsSnapshot},
+    sync::{Event, PatchReceiver, ACK_INTERVAL},
+    transport::UdpTransport,
+    ConfigFileAction, Object, ObjectWrap, SortMap, SCALE,
+};
 use std::{
     error::Error,
-    mem::size_of,
-    net::{Ipv4Addr, UdpSocket},
+    fs::File,
+    io::{LineWriter, Write},
+    net::{Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
     sync::{
         atomic::{AtomicBool, AtomicUsize, Ordering},
         Arc, Mutex,
     },
-};
-use zerocopy::FromBytes;
-
-use patchjuggler::{
-    object::{
-        BoidScanner, FindScanner, ALIGNMENT_DIST, GROUP_SEPARATION_DIST, RANDOM_MOTION,
-        SEPARATION_DIST,
-    },
-    render_objects, Object, ObjectWrap, SortMap, UpdateScanner, SCALE,
+    time::{Duration, Instant},
 };
 
 pub const SELECT_RADIUS: f32 = 0.5;
+/// The interval to write the divergence to the log file.
+const LOG_INTERVAL: Duration = Duration::from_secs(1);
+/// The maximum number of lockstep ticks to catch up with in an iteration of the receiver thread,
+/// so that the GUI does not freeze while a late receiver catches up.
+const MAX_LOCKSTEP_STEPS: usize = 20;
 
 struct Shared {
     args: Args,
+    /// The address of the receiver's socket, resolved from the arguments
+    addr: SocketAddr,
+    /// The address of the sender to register to, resolved from the arguments
+    sender_addr: Option<SocketAddr>,
     objs: Mutex<Vec<ObjectWrap>>,
     total_amt: AtomicUsize,
+    /// The number of datagrams rejected by the protocol decoder
+    rejected: AtomicUsize,
+    /// The number of patches dropped because a newer one was already applied
+    stale: AtomicUsize,
+    /// The number of patches dropped because their baselines were not available
+    undecodable: AtomicUsize,
+    stats: Mutex<StatsSnapshot>,
+    interp: Mutex<InterpolationBuffer>,
+    /// Whether the receiver thread extrapolates the replicas between updates
+    extrapolate: AtomicBool,
+    divergence: Mutex<Divergence>,
+    /// The latest true states of objects from the sender, if any
+    ghosts: Mutex<Vec<Option<Object>>>,
+    /// The progress of the lockstep simulation, if the sender runs in the lockstep mode
+    lockstep: Mutex<Option<LockstepStatus>>,
+    /// The sender's parameters of the simulation, once they have arrived
+    params: Mutex<Option<BoidParams>>,
+    /// The numbers of synced and all objects while a snapshot is in progress
+    sync_progress: Mutex<Option<(usize, usize)>>,
+    resync_requested: AtomicBool,
+    /// The number the sender assigned to this receiver, if it has registered
+    client_id: Mutex<Option<u32>>,
     exit_signal: AtomicBool,
     sort_map: Mutex<SortMap>,
     selected_obj: Mutex<Option<usize>>,
     find_result: Mutex<Vec<usize>>,
     use_sort_map: AtomicBool,
+    impairment: Mutex<ImpairmentConfig>,
+    impairment_stats: Mutex<(ImpairmentStats, ImpairmentStats)>,
 }
 
 #[derive(Parser, Clone, Debug)]
-#[clap(author, version, about)]
+// `-h` is taken by the host address, so the help is only available as `--help`
+#[clap(author, version, about, disable_help_flag = true)]
 struct Args {
+    #[clap(long, action = clap::ArgAction::Help, help = "Print help")]
+    help: Option<bool>,
     #[clap(
         short = 'p',
         long,
         default_value = "34254",
-        help = "The port number of the receiver's socket."
+        help = "The port number of the receiver's socket, unless the host gives one."
     )]
     port: u16,
     #[clap(
         short = 'h',
         long,
         default_value = "127.0.0.1",
-        help = "The address of the receiver's socket."
+        help = "The address to bind the receiver's socket to: an IPv4 or IPv6 address, or a hostname, optionally with a port. An IPv6 address such as :: accepts IPv4 senders too."
+    )]
+    host: Host,
+    #[clap(
+        short = 's',
+        long,
+        conflicts_with_all = ["multicast_group", "broadcast"],
+        help = "The address of the sender to register to: an IPv4 or IPv6 address, or a hostname, optionally with a port. If omitted, the receiver waits for a sender that has been told its address"
+    )]
+    sender_host: Option<Host>,
+    #[clap(
+        short = 'S',
+        long,
+        default_value = "34255",
+        help = "The port number of the sender to register to, unless the sender host gives one."
+    )]
+    sender_port: u16,
+    #[clap(
+        long,
+        help = "Join this IPv4 multicast group to receive on the port, instead of listening to the host address"
+    )]
+    multicast_group: Option<Ipv4Addr>,
+    #[clap(
+        long,
+        default_value = "127.0.0.1",
+        help = "The address of the interface to join the multicast group on"
+    )]
+    multicast_interface: Ipv4Addr,
+    #[clap(
+        long,
+        conflicts_with = "multicast_group",
+        help = "Receive broadcast datagrams on the port from any address, instead of listening to the host address"
+    )]
+    broadcast: bool,
+    #[clap(
+        long,
+        help = "The path of a CSV file to log the divergence from the sender's ground truth to"
+    )]
+    divergence_log: Option<PathBuf>,
+    #[clap(
+        long,
+        help = "A TOML or JSON file of the settings to start with, such as one saved from the GUI. The arguments given on the command line override it. The receiver applies the impairment settings"
     )]
-    host: Ipv4Addr,
+    config: Option<PathBuf>,
+    #[clap(flatten)]
+    impairment: ImpairmentConfig,
 }
 
 fn main() -> Result<(), String> {
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    if let Some(path) = &args.config {
+        let config =
+            Config::load(path).map_err(|e| format!("Failed to load the config {path:?}: {e}"))?;
+        config.apply_impairment(&mut args.impairment, &matches);
+    }
+    let addr = args.host.socket_addr(args.port);
+    let sender_addr = args
+        .sender_host
+        .as_ref()
+        .map(|host| {
+            host.socket_addr_from(args.sender_port, addr).ok_or_else(|| {
+                format!(
+                    "--sender-host {host} has no IPv4 address to reach from --host {}; give an IPv6 --host such as ::",
+                    args.host
+                )
+            })
+        })
+        .transpose()?;
+    let divergence_log = args
+        .divergence_log
+        .as_ref()
+        .map(|path| -> std::io::Result<_> {
+            let mut log = LineWriter::new(File::create(path)?);
+            writeln!(log, "time_ms,samples,mean,max,p50,p90,p99")?;
+            Ok(log)
+        })
+        .transpose()
+        .map_err(|e| {
+            format!(
+                "Failed to open the divergence log {:?}: {e}",
+                args.divergence_log.as_ref().unwrap()
+            )
+        })?;
+    let impairment = args.impairment;
     let shared = Arc::new(Shared {
-        args: Args::parse(),
+        args,
+        addr,
+        sender_addr,
         objs: Mutex::new(vec![]),
         total_amt: AtomicUsize::new(0),
+        rejected: AtomicUsize::new(0),
+        stale: AtomicUsize::new(0),
+        undecodable: AtomicUsize::new(0),
+        stats: Mutex::new(StatsSnapshot::default()),
+        interp: Mutex::new(InterpolationBuffer::new()),
+        extrapolate: AtomicBool::new(true),
+        divergence: Mutex::new(Divergence::new()),
+        ghosts: Mutex::new(vec![]),
+        lockstep: Mutex::new(None),
+        params: Mutex::new(None),
+        sync_progress: Mutex::new(None),
+        resync_requested: AtomicBool::new(false),
+        client_id: Mutex::new(None),
         exit_signal: AtomicBool::new(false),
         sort_map: Mutex::new(SortMap::new(0)),
         selected_obj: Mutex::new(None),
         find_result: Mutex::new(vec![]),
         use_sort_map: AtomicBool::new(true),
+        impairment: Mutex::new(impairment),
+        impairment_stats: Mutex::new(Default::default()),
     });
 
     let shared_copy = shared.clone();
-    let thread =
-        std::thread::spawn(move || receiver_thread(shared_copy).map_err(|e| format!("{e}")));
+    let thread = std::thread::spawn(move || {
+        receiver_thread(shared_copy, divergence_log).map_err(|e| format!("{e}"))
+    });
 
     println!("receiver_thread departed!");
 
@@ -82,11 +225,19 @@ fn main() -> Result<(), String> {
 }
 
 fn gui_thread(shared: Arc<Shared>) -> Result<(), Box<dyn Error>> {
-    let mut native_options = eframe::NativeOptions::default();
-
+    let config_path = shared
+        .args
+        .config
+        .as_ref()
+        .map_or(config::DEFAULT_PATH.to_string(), |path| {
+            path.display().to_string()
+        });
     // We insist to use light theme, because the canvas color is designed to work with light background.
-    native_options.follow_system_theme = false;
-    native_options.default_theme = eframe::Theme::Light;
+    let native_options = eframe::NativeOptions {
+        follow_system_theme: false,
+        default_theme: eframe::Theme::Light,
+        ..Default::default()
+    };
 
     Ok(eframe::run_native(
         "receiver GUI",
@@ -98,84 +249,283 @@ fn gui_thread(shared: Arc<Shared>) -> Result<(), Box<dyn Error>> {
                 show_neighbors: true,
                 show_distances: true,
                 show_updates: true,
+                show_ghosts: false,
+                stats: StatsHistory::new(),
+                playout_mode: PlayoutMode::default(),
+                playout_delay: 100.,
+                num_interpolated: 0,
+                divergence: DivergenceHistory::new(),
+                config_path,
+                config_status: String::new(),
             })
         }),
     )?)
 }
 
-fn receiver_thread(shared: Arc<Shared>) -> Result<(), Box<dyn Error>> {
-    let socket = UdpSocket::bind((shared.args.host, shared.args.port))?;
+fn receiver_thread(
+    shared: Arc<Shared>,
+    mut divergence_log: Option<LineWriter<File>>,
+) -> Result<(), Box<dyn Error>> {
+    let args = &shared.args;
+    let udp = if let Some(group) = args.multicast_group {
+        UdpTransport::bind_multicast(group, args.port, args.multicast_interface)?
+    } else if args.broadcast {
+        UdpTransport::bind_broadcast(args.port)?
+    } else {
+        UdpTransport::bind_dual_stack(shared.addr)?
+    };
+    let mut transport = ImpairedTransport::new(udp, args.impairment);
     let mut _t = 0;
+    let mut receiver = PatchReceiver::new();
+    if let Some(addr) = shared.sender_addr {
+        receiver.connect(addr);
+    }
+    let mut lockstep = LockstepReceiver::new();
+    let mut extrapolator = Extrapolator::new();
+    let start = Instant::now();
+    let mut last_log = Instant::now();
     loop {
         if shared.exit_signal.load(Ordering::Relaxed) {
             return Ok(());
         }
-        let mut buf = [0; size_of::<usize>() + size_of::<Object>()];
-        let (amt1, _src) = socket.recv_from(&mut buf)?;
-        let i = usize::read_from(&buf[..size_of::<usize>()]).unwrap();
-        if i == 0 {
-            let num_objects =
-                usize::read_from(&buf[size_of::<usize>()..size_of::<usize>() * 2]).unwrap();
-            shared
-                .objs
-                .lock()
-                .unwrap()
-                .resize(num_objects, ObjectWrap::default());
-            shared.sort_map.lock().unwrap().resize(num_objects);
-            continue;
+
+        transport.set_config(*shared.impairment.lock().unwrap());
+        *shared.impairment_stats.lock().unwrap() = transport.stats();
+
+        if let Some(log) = &mut divergence_log {
+            if LOG_INTERVAL <= last_log.elapsed() {
+                if let Some(stats) = shared.divergence.lock().unwrap().stats() {
+                    writeln!(
+                        log,
+                        "{},{},{},{},{},{},{}",
+                        start.elapsed().as_millis(),
+                        stats.samples,
+                        stats.mean,
+                        stats.max,
+                        stats.p50,
+                        stats.p90,
+                        stats.p99
+                    )?;
+                }
+                last_log = Instant::now();
+            }
+        }
+
+        if shared.resync_requested.swap(false, Ordering::Relaxed) {
+            receiver.request_resync();
+        }
+
+        // Wake up periodically to send acknowledgements even if nothing arrives
+        let event = receiver.poll(&mut transport, ACK_INTERVAL)?;
+
+        shared.total_amt.store(receiver.bytes(), Ordering::Relaxed);
+        shared
+            .rejected
+            .store(receiver.rejected(), Ordering::Relaxed);
+        shared
+            .undecodable
+            .store(receiver.undecodable(), Ordering::Relaxed);
+        *shared.stats.lock().unwrap() = receiver.stats();
+        *shared.sync_progress.lock().unwrap() = receiver.sync_progress();
+        *shared.client_id.lock().unwrap() = receiver.client_id();
+
+        let mut ack_due = false;
+        match event {
+            Some(Event::Update(update)) => {
+                // The states are as of the tick they were sent in, which the replicas should
+                // have been stepped to before they are overwritten, like the sender's shadows
+                shared.interp.lock().unwrap().on_packet(update.timestamp);
+                extrapolate(&shared, &receiver, &mut extrapolator);
+
+                let mut objs = shared.objs.lock().unwrap();
+                if objs.len() != update.num_objects {
+                    shared.sort_map.lock().unwrap().resize(update.num_objects);
+                }
+                let stale = update.apply(&mut objs);
+                shared.stale.fetch_add(stale, Ordering::Relaxed);
+                drop(objs);
+
+                let mut interp = shared.interp.lock().unwrap();
+                interp.resize(update.num_objects);
+                for (index, obj) in update.states {
+                    interp.push(index, update.timestamp, obj);
+                }
+                drop(interp);
+            }
+            Some(Event::Despawn { index }) => {
+                let mut objs = shared.objs.lock().unwrap();
+                if index < objs.len() {
+                    objs.swap_remove(index);
+                }
+                shared.sort_map.lock().unwrap().resize(objs.len());
+                let mut selected_obj = shared.selected_obj.lock().unwrap();
+                // The last object has moved into the place of the removed one
+                if *selected_obj == Some(index) {
+                    *selected_obj = None;
+                } else if *selected_obj == Some(objs.len()) {
+                    *selected_obj = Some(index);
+                }
+                drop(selected_obj);
+                // The neighbors are indices found before the objects moved
+                shared.find_result.lock().unwrap().clear();
+                drop(objs);
+                shared.interp.lock().unwrap().swap_remove(index);
+                shared.divergence.lock().unwrap().swap_remove(index);
+                let mut ghosts = shared.ghosts.lock().unwrap();
+                if index < ghosts.len() {
+                    ghosts.swap_remove(index);
+                }
+            }
+            Some(Event::Truth {
+                num_objects,
+                samples,
+                ..
+            }) => {
+                let objs = shared.objs.lock().unwrap();
+                let mut divergence = shared.divergence.lock().unwrap();
+                divergence.resize(num_objects);
+                divergence.measure(&samples, &objs);
+                drop(divergence);
+                drop(objs);
+
+                let mut ghosts = shared.ghosts.lock().unwrap();
+                ghosts.resize(num_objects, None);
+                for (index, obj) in samples {
+                    if let Some(ghost) = ghosts.get_mut(index) {
+                        *ghost = Some(obj);
+                    }
+                }
+            }
+            Some(Event::LockstepInputs {
+                seed,
+                num_objects,
+                first_tick,
+                inputs,
+            }) => {
+                lockstep.on_inputs(seed, num_objects, first_tick, &inputs);
+                ack_due = true;
+            }
+            Some(Event::Checksum { tick, hash }) => lockstep.on_checksum(tick, hash),
+            Some(Event::Params(params)) => *shared.params.lock().unwrap() = Some(params),
+            None => {}
+        }
+        extrapolate(&shared, &receiver, &mut extrapolator);
+
+        if lockstep.advance(MAX_LOCKSTEP_STEPS) != 0 {
+            let sim = lockstep.sim().unwrap();
+            let mut objs = shared.objs.lock().unwrap();
+            if objs.len() != sim.objs().len() {
+                objs.resize(sim.objs().len(), ObjectWrap::default());
+                shared.sort_map.lock().unwrap().resize(sim.objs().len());
+            }
+            for (wrap, obj) in objs.iter_mut().zip(sim.objs()) {
+                *wrap.as_mut() = *obj;
+            }
         }
-        let buf = Object::ref_from(&buf[size_of::<usize>()..]).unwrap();
-
-        shared.total_amt.fetch_add(amt1, Ordering::Relaxed);
-
-        let mut objs = shared.objs.lock().unwrap();
-        if i - 1 < objs.len() {
-            objs[i - 1] = ObjectWrap::new(*buf);
-            // if i == 0 {
-            //     println!(
-            //         "[{t}]: Received {amt1} bytes from {_src:?}: {i} = {:?}!",
-            //         objs[i]
-            //     );
-            // }
+        // Acknowledge after advancing, so that the sender can skip the inputs already used
+        if ack_due {
+            if let Some(ack) = lockstep.ack() {
+                receiver.reply(&mut transport, &ack)?;
+            }
         }
-        drop(objs);
+        *shared.lockstep.lock().unwrap() = lockstep.status();
 
         _t += 1;
     }
     // Ok(())
 }
 
+/// Steps the replicas up to the current time of the sender's clock, if they are extrapolated.
+/// They are stepped on the same ticks as the sender's dead reckoning, see [`Extrapolator`].
+fn extrapolate(shared: &Shared, receiver: &PatchReceiver, extrapolator: &mut Extrapolator) {
+    if !shared.extrapolate.load(Ordering::Relaxed) {
+        return;
+    }
+    let Some(time) = shared.interp.lock().unwrap().playout_time(0.) else {
+        return;
+    };
+    let mut objs = shared.objs.lock().unwrap();
+    let ids = receiver.entities().ids();
+    // The replicas are resized by the next update
+    if objs.len() != ids.len() {
+        return;
+    }
+    // Predict with the sender's model, which is the default one until it arrives
+    let params = receiver.params().copied().unwrap_or_default();
+    extrapolator.advance(&mut objs, ids, &params, time.max(0.) as u32);
+}
+
 pub struct ReceiverApp {
     shared: Arc<Shared>,
     show_grid: bool,
     show_neighbors: bool,
     show_distances: bool,
     show_updates: bool,
+    show_ghosts: bool,
+    stats: StatsHistory,
+    playout_mode: PlayoutMode,
+    /// The playout delay of the interpolation mode in milliseconds
+    playout_delay: f64,
+    /// The number of objects that had states on both sides of the playout time
+    num_interpolated: usize,
+    divergence: DivergenceHistory,
+    /// The path of the config file to save and load in the GUI
+    config_path: String,
+    /// The result of the last save or load
+    config_status: String,
 }
 
 impl ReceiverApp {
+    /// Saves or loads the impairment settings, which are the only ones the receiver has, and
+    /// returns the result to show.
+    fn config_file(&mut self, action: ConfigFileAction) -> String {
+        let path = Path::new(&self.config_path);
+        let mut impairment = self.shared.impairment.lock().unwrap();
+        let result = match action {
+            ConfigFileAction::Save => Config {
+                impairment: Some(*impairment),
+                ..Config::default()
+            }
+            .save(path)
+            .map(|()| format!("Saved {path:?}")),
+            ConfigFileAction::Load => Config::load(path).map(|config| {
+                if let Some(config) = config.impairment {
+                    *impairment = config;
+                }
+                format!("Loaded {path:?}")
+            }),
+        };
+        result.unwrap_or_else(|e| format!("Failed to {} {path:?}: {e}", action.verb()))
+    }
+
     fn update_objs(&mut self) {
         let mut objs = self.shared.objs.lock().unwrap();
         let mut sort_map = self.shared.sort_map.lock().unwrap();
-        let mut scanner = BoidScanner::new(None, RANDOM_MOTION);
+        // The lockstep simulation runs in the receiver thread, so the replicas are up to date
+        let lockstep = self.shared.lockstep.lock().unwrap().is_some();
+        let interpolate = self.playout_mode == PlayoutMode::Interpolate && !lockstep;
+        // The receiver thread extrapolates on the ticks of the sender's clock
+        self.shared
+            .extrapolate
+            .store(!interpolate && !lockstep, Ordering::Relaxed);
+        if interpolate {
+            let interp = self.shared.interp.lock().unwrap();
+            self.num_interpolated = 0;
+            if let Some(time) = interp.playout_time(self.playout_delay) {
+                for (i, obj) in objs.iter_mut().enumerate() {
+                    if let Some((state, interpolated)) = interp.sample(i, time) {
+                        *obj.as_mut() = state;
+                        self.num_interpolated += interpolated as usize;
+                    }
+                }
+            }
+        }
         if self.shared.use_sort_map.load(Ordering::Relaxed) {
             let mut find_scanner = FindScanner::new(*self.shared.selected_obj.lock().unwrap());
             sort_map.update(&objs);
-            sort_map.scan(&mut objs, &mut scanner);
             sort_map.scan(&mut objs, &mut find_scanner);
             let mut find_result = self.shared.find_result.lock().unwrap();
             *find_result = find_scanner.into_find_result();
-        } else {
-            for i in 0..objs.len() {
-                scanner.start(i, objs[i].as_ref());
-                for (j, obj2) in objs.iter().enumerate() {
-                    if i == j {
-                        continue;
-                    }
-                    scanner.next(j, obj2.as_ref());
-                }
-                scanner.end(i, objs[i].as_mut());
-            }
         }
     }
 
@@ -189,6 +539,15 @@ impl ReceiverApp {
         );
         drop(objs); // Release the mutex ASAP
 
+        if self.show_ghosts {
+            render_ghosts(
+                &self.shared.ghosts.lock().unwrap(),
+                &self.shared.objs.lock().unwrap(),
+                &response,
+                &painter,
+            );
+        }
+
         let to_screen = egui::emath::RectTransform::from_to(
             Rect::from_min_size(Pos2::ZERO, response.rect.size()),
             response.rect,
@@ -225,12 +584,14 @@ impl ReceiverApp {
         }
 
         if self.show_neighbors {
-            if let Some(selected_obj) = *self.shared.selected_obj.lock().unwrap() {
-                let objs = self.shared.objs.lock().unwrap();
+            // Lock the objects first, in the same order as the receiver thread
+            let objs = self.shared.objs.lock().unwrap();
+            let selected_obj = *self.shared.selected_obj.lock().unwrap();
+            if let Some(obj0) = selected_obj.and_then(|index| objs.get(index)) {
                 if let Ok(first_result) = self.shared.find_result.lock() {
-                    let pos0 = objs[selected_obj].as_ref().pos;
-                    for j in first_result.iter() {
-                        let pos_j = objs[*j].as_ref().pos;
+                    let pos0 = obj0.as_ref().pos;
+                    for obj_j in first_result.iter().filter_map(|j| objs.get(*j)) {
+                        let pos_j = obj_j.as_ref().pos;
                         painter.line_segment(
                             [
                                 to_screen.transform_pos(pos2(
@@ -250,12 +611,15 @@ impl ReceiverApp {
         }
 
         if self.show_distances {
-            if let Some(&selected_obj) = self.shared.selected_obj.lock().unwrap().as_ref() {
-                let pos = self.shared.objs.lock().unwrap()[selected_obj].as_ref().pos;
+            let params = self.shared.params.lock().unwrap().unwrap_or_default();
+            let objs = self.shared.objs.lock().unwrap();
+            let selected_obj = *self.shared.selected_obj.lock().unwrap();
+            if let Some(obj) = selected_obj.and_then(|index| objs.get(index)) {
+                let pos = obj.as_ref().pos;
                 for (dist, color) in [
-                    (SEPARATION_DIST, Color32::from_rgb(255, 0, 255)),
-                    (ALIGNMENT_DIST, Color32::from_rgb(0, 127, 127)),
-                    (GROUP_SEPARATION_DIST, Color32::from_rgb(127, 127, 0)),
+                    (params.separation_dist, Color32::from_rgb(255, 0, 255)),
+                    (params.alignment_dist, Color32::from_rgb(0, 127, 127)),
+                    (params.group_separation_dist, Color32::from_rgb(127, 127, 0)),
                 ] {
                     painter.circle_stroke(
                         to_screen.transform_pos(pos2(pos[0] as f32 * SCALE, pos[1] as f32 * SCALE)),
@@ -282,12 +646,29 @@ impl ReceiverApp {
             response.rect.left_top(),
             Align2::LEFT_TOP,
             format!(
-                "Received {} bytes",
-                self.shared.total_amt.load(Ordering::Relaxed)
+                "Received {} bytes, rejected {} datagrams, dropped {} stale and {} undecodable patches",
+                self.shared.total_amt.load(Ordering::Relaxed),
+                self.shared.rejected.load(Ordering::Relaxed),
+                self.shared.stale.load(Ordering::Relaxed),
+                self.shared.undecodable.load(Ordering::Relaxed)
             ),
             FontId::proportional(16.),
             Color32::BLACK,
         );
+
+        if let Some((synced, total)) = *self.shared.sync_progress.lock().unwrap() {
+            painter.text(
+                response.rect.center(),
+                Align2::CENTER_CENTER,
+                if total == 0 {
+                    "Syncing...".to_string()
+                } else {
+                    format!("Syncing... {synced} / {total}")
+                },
+                FontId::proportional(24.),
+                Color32::BLACK,
+            );
+        }
     }
 
     fn ui_panel(&mut self, ui: &mut Ui) {
@@ -295,11 +676,132 @@ impl ReceiverApp {
         ui.checkbox(&mut self.show_neighbors, "Show neighbors");
         ui.checkbox(&mut self.show_distances, "Show distances");
         ui.checkbox(&mut self.show_updates, "Show update as flashing circles");
+        ui.checkbox(&mut self.show_ghosts, "Show sender's truth as ghosts")
+            .on_hover_text("Requires the sender to send the debug truth stream");
         let mut use_sort_map = self.shared.use_sort_map.load(Ordering::Acquire);
         ui.checkbox(&mut use_sort_map, "Use sort map");
         self.shared
             .use_sort_map
             .store(use_sort_map, Ordering::Release);
+
+        ui.separator();
+        ui.heading("Synchronization");
+        if let Some(addr) = self.shared.sender_addr {
+            match *self.shared.client_id.lock().unwrap() {
+                Some(id) => ui.label(format!("Registered to {addr} as client #{id}")),
+                None => ui.label(format!("Registering to {addr}...")),
+            };
+        }
+        if let Some((synced, total)) = *self.shared.sync_progress.lock().unwrap() {
+            let progress = if total == 0 {
+                0.
+            } else {
+                synced as f32 / total as f32
+            };
+            ui.add(egui::ProgressBar::new(progress).text(format!("Syncing {synced} / {total}")));
+        } else {
+            ui.label("In sync");
+        }
+        if ui.button("Request resync").clicked() {
+            self.shared.resync_requested.store(true, Ordering::Relaxed);
+        }
+
+        ui.separator();
+        ui.heading("Playout");
+        ui.radio_value(
+            &mut self.playout_mode,
+            PlayoutMode::Extrapolate,
+            "Extrapolate with the boid model",
+        );
+        ui.radio_value(
+            &mut self.playout_mode,
+            PlayoutMode::Interpolate,
+            "Interpolate with a delay",
+        );
+        ui.add_enabled(
+            self.playout_mode == PlayoutMode::Interpolate,
+            egui::widgets::Slider::new(&mut self.playout_delay, (0.)..=2000.).text("Delay (ms)"),
+        );
+        if self.playout_mode == PlayoutMode::Interpolate {
+            // The view lags the sender by the one-way latency in addition to the playout delay
+            let latency = self
+                .stats
+                .latest
+                .rtt
+                .map_or(0., |rtt| rtt.as_secs_f64() * 1e3 / 2.);
+            ui.label(format!(
+                "Behind real time: {:.0} ms",
+                self.playout_delay + latency
+            ));
+            ui.label(format!(
+                "Interpolated objects: {} / {}",
+                self.num_interpolated,
+                self.shared.objs.lock().unwrap().len()
+            ));
+        }
+
+        ui.separator();
+        ui.heading("Simulation parameters");
+        match *self.shared.params.lock().unwrap() {
+            Some(params) => {
+                egui::Grid::new("params").striped(true).show(ui, |ui| {
+                    for (info, value) in BoidParams::INFO.iter().zip(params.fields()) {
+                        ui.label(info.label);
+                        ui.label(format!("{value:.2e}"));
+                        ui.end_row();
+                    }
+                });
+            }
+            None => {
+                ui.label("Not received from the sender yet");
+            }
+        }
+
+        if let Some(status) = *self.shared.lockstep.lock().unwrap() {
+            ui.separator();
+            ui.heading("Lockstep");
+            ui.label(format!("Tick: {}", status.tick));
+            ui.label(format!(
+                "Received inputs up to: {} ({} behind)",
+                status.received_tick,
+                status.received_tick - status.tick
+            ));
+            ui.label(format!("Verified checksums: {}", status.verified));
+            if let Some(tick) = status.mismatch {
+                ui.colored_label(
+                    Color32::RED,
+                    format!("Checksum mismatch at tick {tick}: the simulation diverged"),
+                );
+            }
+        }
+
+        ui.separator();
+        ui.heading("Network impairment");
+        let stats = *self.shared.impairment_stats.lock().unwrap();
+        render_impairment(ui, &mut self.shared.impairment.lock().unwrap(), stats);
+
+        ui.separator();
+        ui.heading("Config");
+        if let Some(action) = render_config_file(ui, &mut self.config_path, &self.config_status) {
+            self.config_status = self.config_file(action);
+        }
+
+        ui.separator();
+        ui.heading("Statistics");
+        let snapshot = *self.shared.stats.lock().unwrap();
+        self.stats.update(snapshot);
+        render_stats(ui, &self.stats);
+
+        ui.separator();
+        ui.heading("Divergence");
+        let divergence = self.shared.divergence.lock().unwrap();
+        self.divergence.update(divergence.stats());
+        render_divergence(ui, &self.divergence);
+        if let Some(selected) = *self.shared.selected_obj.lock().unwrap() {
+            if let Some(error) = divergence.error(selected) {
+                ui.label(format!("Error of the selected object: {error:.4}"));
+            }
+        }
     }
 }
 
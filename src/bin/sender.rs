@@ -1,71 +1,127 @@
-use clap::Parser;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser};
 use eframe::{
     egui::{self, Color32, Context, Frame, Ui},
     emath::Align2,
     epaint::{pos2, FontId, Pos2, Rect},
 };
+use patchjuggler::{
+    addr::Host,
+    codec::Codec,
+    config::{self, from_command_line, Config},
+    divergence::{DEBUG_TRUTH_INTERVAL, TRUTH_INTERVAL},
+    entity::EntityId,
+    impair::{ImpairedTransport, ImpairmentConfig, ImpairmentStats},
+    lockstep::{LockstepSender, Simulation},
+    object::{BoidParams, BoidScanner},
+    protocol::{Message, DEFAULT_MTU},
+    reckoning::TickClock,
+    render_config_file, render_impairment, render_objects, render_stats,
+    schedule::{Interest, PriorityWeights, POI_RADIUS},
+    stats::{StatsHistory, StatsSnapshot},
+    sync::{ClientInfo, PatchSender, SendOptions, DEFAULT_BANDWIDTH},
+    transport::UdpTransport,
+    ConfigFileAction, Object, SortMap, UpdateScanner, SCALE,
+};
 use rand::prelude::*;
 use std::{
     error::Error,
-    mem::size_of,
-    net::{Ipv4Addr, UdpSocket},
+    net::{Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
     sync::{
         atomic::{AtomicBool, AtomicUsize, Ordering},
         Arc, Mutex,
     },
-};
-use zerocopy::AsBytes;
-
-use patchjuggler::{
-    object::{BoidScanner, ALIGNMENT_DIST, GROUP_SEPARATION_DIST, RANDOM_MOTION, SEPARATION_DIST},
-    render_objects, Object, SortMap, UpdateScanner, SCALE, SPACE_WIDTH,
+    time::Instant,
 };
 
 pub const SELECT_RADIUS: f32 = 0.5;
+/// The error threshold of dead reckoning when it is enabled in the GUI.
+const DEFAULT_ERROR_THRESHOLD: f64 = 0.05;
+/// The number of objects per burst when the limit is enabled in the GUI.
+const DEFAULT_BURST_OBJS: usize = 10;
+
+/// A change of the set of objects requested in the GUI, to be applied by the sender thread.
+enum LifecycleRequest {
+    Spawn(Object),
+    Despawn(EntityId),
+}
+
+/// What clicking on the canvas does.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Tool {
+    Select,
+    Add,
+}
 
 struct Shared {
     args: Args,
+    /// The address of the sender's socket, resolved from the arguments
+    src_addr: SocketAddr,
+    /// The address of the receiver to serve without registration, resolved from the arguments
+    dest_addr: SocketAddr,
     objs: Mutex<Vec<Object>>,
     total_amt: AtomicUsize,
+    total_packets: AtomicUsize,
     exit_signal: AtomicBool,
     sort_map: Mutex<SortMap>,
     selected_obj: Mutex<Option<usize>>,
     find_result: Mutex<Vec<usize>>,
+    /// The ids of the objects in the same order
+    entity_ids: Mutex<Vec<EntityId>>,
+    lifecycle_requests: Mutex<Vec<LifecycleRequest>>,
     use_sort_map: AtomicBool,
-    randomness: Mutex<f64>,
+    impairment: Mutex<ImpairmentConfig>,
+    impairment_stats: Mutex<(ImpairmentStats, ImpairmentStats)>,
+    params: Mutex<BoidParams>,
+    codec: Mutex<Codec>,
+    clients: Mutex<Vec<ClientInfo>>,
+    error_threshold: Mutex<Option<f64>>,
+    bandwidth: Mutex<f64>,
+    /// The maximum number of objects to send in a burst, if any
+    burst_objs: Mutex<Option<usize>>,
+    weights: Mutex<PriorityWeights>,
+    /// The position of the mouse cursor, whose surrounding objects are favored by the scheduler
+    poi: Mutex<Option<[f64; 2]>>,
+    publish_truth: AtomicBool,
+    debug_truth: AtomicBool,
+    /// The current tick and the next tick the slowest receiver needs in the lockstep mode
+    lockstep_ticks: Mutex<Option<(u32, Option<u32>)>>,
 }
 
 #[derive(Parser, Clone, Debug)]
-#[clap(author, version, about)]
+// `-h` is taken by the host address, so the help is only available as `--help`
+#[clap(author, version, about, disable_help_flag = true)]
 struct Args {
+    #[clap(long, action = clap::ArgAction::Help, help = "Print help")]
+    help: Option<bool>,
     #[clap(
         short = 'p',
         long,
         default_value = "34254",
-        help = "The port number to send packets to."
+        help = "The port number of a receiver to serve without registration, unless the host gives one."
     )]
     dest_port: u16,
     #[clap(
         short = 'h',
         long,
         default_value = "127.0.0.1",
-        help = "The host address to send packets to."
+        help = "The address of a receiver to serve without registration: an IPv4 or IPv6 address, or a hostname, optionally with a port such as [::1]:34254. Other receivers can register themselves with a hello."
     )]
-    dest_host: Ipv4Addr,
+    dest_host: Host,
     #[clap(
         short = 'P',
         long,
         default_value = "34255",
-        help = "The port number of the sender's socket."
+        help = "The port number of the sender's socket, unless the host gives one."
     )]
     src_port: u16,
     #[clap(
         short = 'H',
         long,
         default_value = "127.0.0.1",
-        help = "Interval to auto-cleanup cache memory, in seconds."
+        help = "The address to bind the sender's socket to: an IPv4 or IPv6 address, or a hostname, optionally with a port. An IPv6 address such as :: serves IPv4 receivers too."
     )]
-    src_host: Ipv4Addr,
+    src_host: Host,
     #[clap(
         short = 'r',
         long,
@@ -80,41 +136,184 @@ struct Args {
         help = "The number of objects to synchronize"
     )]
     num_objects: usize,
+    #[clap(
+        short = 'B',
+        long,
+        default_value_t = DEFAULT_BANDWIDTH,
+        help = "The budget of bytes per second to send. Objects are sent in the order of priority as far as the budget allows"
+    )]
+    bandwidth: f64,
     #[clap(
         short = 'b',
         long,
-        default_value = "10",
-        help = "The number of objects to send in one burst. Having a low value helps GUI to run smoothly but will have overhead sending patches"
+        help = "The maximum number of objects to send in one burst, regardless of the bandwidth budget. Having a low value helps GUI to run smoothly but will have overhead sending patches"
+    )]
+    burst_objs: Option<usize>,
+    #[clap(
+        short = 'm',
+        long,
+        default_value_t = DEFAULT_MTU,
+        help = "The maximum size of a datagram in bytes, excluding IP and UDP headers. As many objects as fit are packed in a datagram"
     )]
-    burst_objs: usize,
+    mtu: usize,
+    #[clap(
+        short = 'c',
+        long,
+        value_enum,
+        default_value_t = Codec::Full,
+        help = "The codec to encode positions and velocities with"
+    )]
+    codec: Codec,
+    #[clap(
+        short = 'e',
+        long,
+        help = "Enable dead reckoning: prioritize the objects whose predicted state on the receiver is off by more than this threshold"
+    )]
+    error_threshold: Option<f64>,
+    #[clap(
+        long,
+        help = "Publish sampled true positions for the receiver to measure the divergence"
+    )]
+    publish_truth: bool,
+    #[clap(
+        long,
+        help = "Send the true states of all objects at a low rate for the receiver to show as ghosts"
+    )]
+    debug_truth: bool,
+    #[clap(
+        long,
+        conflicts_with = "lockstep",
+        help = "Publish to this IPv4 multicast group on the destination port instead of the destination host, for any number of receivers that join it"
+    )]
+    multicast_group: Option<Ipv4Addr>,
+    #[clap(
+        long,
+        default_value = "1",
+        help = "The time to live of multicast datagrams, which is the number of hops they can go through. 1 keeps them in the local network"
+    )]
+    multicast_ttl: u32,
+    #[clap(
+        long,
+        default_value = "127.0.0.1",
+        help = "The address of the interface to send multicast datagrams through. The default loopback interface reaches only the receivers on this host"
+    )]
+    multicast_interface: Ipv4Addr,
+    #[clap(
+        long,
+        conflicts_with_all = ["lockstep", "multicast_group"],
+        help = "Publish to the destination host as a broadcast address, such as 255.255.255.255, for any number of receivers listening to the port"
+    )]
+    broadcast: bool,
+    #[clap(
+        long,
+        help = "Run the simulation in deterministic lockstep with the receiver, sending only the inputs of each tick instead of the object states"
+    )]
+    lockstep: bool,
+    #[clap(
+        long,
+        help = "The seed of the lockstep simulation, which the receiver gets from the sender. Random if omitted"
+    )]
+    seed: Option<u64>,
+    #[clap(
+        long,
+        help = "A TOML or JSON file of the settings to start with, such as one saved from the GUI. The arguments given on the command line override it"
+    )]
+    config: Option<PathBuf>,
+    #[clap(flatten)]
+    impairment: ImpairmentConfig,
+}
+
+impl Args {
+    /// Overrides the arguments with the settings in a config file, except for the ones given
+    /// on the command line.
+    fn apply_config(&mut self, config: &Config, matches: &ArgMatches) {
+        let given = |id| from_command_line(matches, id);
+        if let (Some(num_objects), false) = (config.num_objects, given("num_objects")) {
+            self.num_objects = num_objects;
+        }
+        if let (Some(rate), false) = (config.rate, given("rate")) {
+            self.rate = rate;
+        }
+        if let (Some(bandwidth), false) = (config.bandwidth, given("bandwidth")) {
+            self.bandwidth = bandwidth;
+        }
+        if let (Some(burst_objs), false) = (config.burst_objs, given("burst_objs")) {
+            self.burst_objs = Some(burst_objs);
+        }
+        if let (Some(mtu), false) = (config.mtu, given("mtu")) {
+            self.mtu = mtu;
+        }
+        if let (Some(codec), false) = (config.codec, given("codec")) {
+            self.codec = codec;
+        }
+        config.apply_impairment(&mut self.impairment, matches);
+    }
 }
 
 fn main() -> Result<(), String> {
-    let args = Args::parse();
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let mut params = BoidParams::default();
+    if let Some(path) = &args.config {
+        let config =
+            Config::load(path).map_err(|e| format!("Failed to load the config {path:?}: {e}"))?;
+        args.apply_config(&config, &matches);
+        params = config.params.unwrap_or_default();
+    }
+    let (src_addr, dest_addr) = resolve_addrs(&args)?;
     let mut rng = rand::thread_rng();
     let num_objects = args.num_objects;
-    let objs = (0..num_objects)
-        .map(|_| {
-            Object::new(
-                [
-                    rng.gen::<f64>() * SPACE_WIDTH,
-                    rng.gen::<f64>() * SPACE_WIDTH,
-                ],
-                [rng.gen::<u8>(), rng.gen(), rng.gen()],
-            )
-        })
-        .collect();
+    let seed = *args.seed.get_or_insert_with(|| rng.gen());
+    let objs = if args.lockstep {
+        Simulation::new(seed, num_objects).objs().to_vec()
+    } else {
+        (0..num_objects)
+            .map(|_| {
+                Object::new(
+                    [
+                        rng.gen::<f64>() * params.space_width,
+                        rng.gen::<f64>() * params.space_width,
+                    ],
+                    [rng.gen::<u8>(), rng.gen(), rng.gen()],
+                )
+            })
+            .collect()
+    };
     let sort_map = SortMap::new(num_objects);
+    let codec = args.codec;
+    let impairment = args.impairment;
+    let error_threshold = args.error_threshold;
+    let bandwidth = args.bandwidth;
+    let burst_objs = args.burst_objs;
+    let publish_truth = args.publish_truth;
+    let debug_truth = args.debug_truth;
     let shared = Arc::new(Shared {
         args,
+        src_addr,
+        dest_addr,
         objs: Mutex::new(objs),
         total_amt: AtomicUsize::new(0),
+        total_packets: AtomicUsize::new(0),
         exit_signal: AtomicBool::new(false),
         sort_map: Mutex::new(sort_map),
         selected_obj: Mutex::new(None),
         find_result: Mutex::new(vec![]),
+        entity_ids: Mutex::new(vec![]),
+        lifecycle_requests: Mutex::new(vec![]),
         use_sort_map: AtomicBool::new(true),
-        randomness: Mutex::new(RANDOM_MOTION),
+        impairment: Mutex::new(impairment),
+        impairment_stats: Mutex::new(Default::default()),
+        params: Mutex::new(params),
+        codec: Mutex::new(codec),
+        clients: Mutex::new(vec![]),
+        error_threshold: Mutex::new(error_threshold),
+        bandwidth: Mutex::new(bandwidth),
+        burst_objs: Mutex::new(burst_objs),
+        weights: Mutex::new(PriorityWeights::default()),
+        poi: Mutex::new(None),
+        publish_truth: AtomicBool::new(publish_truth),
+        debug_truth: AtomicBool::new(debug_truth),
+        lockstep_ticks: Mutex::new(None),
     });
 
     let shared_copy = shared.clone();
@@ -132,12 +331,48 @@ fn main() -> Result<(), String> {
     thread.join().unwrap()
 }
 
-fn gui_thread(shared: Arc<Shared>) -> Result<(), Box<dyn Error>> {
-    let mut native_options = eframe::NativeOptions::default();
+/// Resolves the addresses of the sender's socket and the receiver, and checks that the socket
+/// can reach the receiver.
+fn resolve_addrs(args: &Args) -> Result<(SocketAddr, SocketAddr), String> {
+    let src_addr = args.src_host.socket_addr(args.src_port);
+    let dest_addr = args
+        .dest_host
+        .socket_addr_from(args.dest_port, src_addr)
+        .ok_or_else(|| {
+            format!(
+                "--dest-host {} has no IPv4 address to reach from --src-host {}; give an IPv6 --src-host such as ::",
+                args.dest_host, args.src_host
+            )
+        })?;
+    if (args.multicast_group.is_some() || args.broadcast) && src_addr.is_ipv6() {
+        return Err(format!(
+            "--src-host {} is an IPv6 address, but multicast and broadcast are only supported over IPv4",
+            args.src_host
+        ));
+    }
+    if args.broadcast && dest_addr.is_ipv6() {
+        return Err(format!(
+            "--dest-host {} must be an IPv4 broadcast address with --broadcast",
+            args.dest_host
+        ));
+    }
+    Ok((src_addr, dest_addr))
+}
 
+fn gui_thread(shared: Arc<Shared>) -> Result<(), Box<dyn Error>> {
+    let config_path = shared
+        .args
+        .config
+        .as_ref()
+        .map_or(config::DEFAULT_PATH.to_string(), |path| {
+            path.display().to_string()
+        });
     // We insist to use light theme, because the canvas color is designed to work with light background.
-    native_options.follow_system_theme = false;
-    native_options.default_theme = eframe::Theme::Light;
+    let native_options = eframe::NativeOptions {
+        follow_system_theme: false,
+        default_theme: eframe::Theme::Light,
+        ..Default::default()
+    };
 
     Ok(eframe::run_native(
         "sender GUI",
@@ -145,9 +380,14 @@ fn gui_thread(shared: Arc<Shared>) -> Result<(), Box<dyn Error>> {
         Box::new(|_cc| {
             Box::new(SenderApp {
                 shared,
+                stats: StatsHistory::new(),
                 show_grid: true,
                 show_neighbors: true,
                 show_distances: true,
+                selected_client: None,
+                tool: Tool::Select,
+                config_path,
+                config_status: String::new(),
             })
         }),
     )?)
@@ -186,64 +426,169 @@ impl UpdateScanner for FindScanner {
 
 fn sender_thread(shared: Arc<Shared>) -> Result<(), Box<dyn Error>> {
     std::thread::sleep(std::time::Duration::from_millis(1000));
-    let socket = UdpSocket::bind((shared.args.src_host, shared.args.src_port))?;
+    let mut transport = ImpairedTransport::new(
+        UdpTransport::bind_dual_stack(shared.src_addr)?,
+        shared.args.impairment,
+    );
     let mut t = 0;
-    let mut n = 0;
     let mut rng = rand::thread_rng();
+    let mut sender = PatchSender::new();
+    let mut clock = TickClock::new();
+    let mut last_truth = Instant::now();
+    let mut last_debug_truth = Instant::now();
+    let mut lockstep = shared
+        .args
+        .lockstep
+        .then(|| LockstepSender::new(shared.args.seed.unwrap_or(0), shared.args.num_objects));
+    for obj in shared.objs.lock().unwrap().iter() {
+        sender.spawn(obj);
+    }
+    *shared.entity_ids.lock().unwrap() = sender.entities().ids().to_vec();
+    let dest = shared.dest_addr;
+    if let Some(group) = shared.args.multicast_group {
+        transport
+            .inner()
+            .set_multicast_sender(shared.args.multicast_ttl, shared.args.multicast_interface)?;
+        sender.add_group(SocketAddr::from((group, dest.port())));
+    } else if shared.args.broadcast {
+        transport.inner().socket().set_broadcast(true)?;
+        sender.add_group(dest);
+    } else {
+        sender.add_client(dest);
+    }
     loop {
-        let addr = (shared.args.dest_host, shared.args.dest_port);
-
         std::thread::sleep(std::time::Duration::from_millis(shared.args.rate));
 
         if shared.exit_signal.load(Ordering::Relaxed) {
             return Ok(());
         }
 
+        transport.set_config(*shared.impairment.lock().unwrap());
+        *shared.impairment_stats.lock().unwrap() = transport.stats();
+
         let mut objs = shared.objs.lock().unwrap();
         let mut sort_map = shared.sort_map.lock().unwrap();
+        let requests = std::mem::take(&mut *shared.lifecycle_requests.lock().unwrap());
+        if !requests.is_empty() && lockstep.is_none() {
+            for request in requests {
+                apply_lifecycle_request(&shared, &mut sender, &mut objs, request);
+            }
+            sort_map.resize(objs.len());
+            *shared.entity_ids.lock().unwrap() = sender.entities().ids().to_vec();
+        }
         // let hash_table = vec![HashEntry::default(); objs.len()];
         // let start_offsets = vec![usize::MAX; objs.len()];
-        let mut scanner = BoidScanner::new(Some(&mut rng), *shared.randomness.lock().unwrap());
-        if shared.use_sort_map.load(Ordering::Relaxed) {
+        let params = *shared.params.lock().unwrap();
+        sender.set_params(&params);
+        let mut checksum = None;
+        if let Some(lockstep) = &mut lockstep {
+            checksum = lockstep.step(&params);
+            objs.copy_from_slice(lockstep.sim().objs());
             let mut find_scanner = FindScanner::new(*shared.selected_obj.lock().unwrap());
             sort_map.update(&objs);
-            sort_map.scan(&mut objs, &mut scanner);
             sort_map.scan(&mut objs, &mut find_scanner);
-            let mut find_result = shared.find_result.lock().unwrap();
-            *find_result = find_scanner.find_result;
+            *shared.find_result.lock().unwrap() = find_scanner.find_result;
         } else {
-            for i in 0..objs.len() {
-                scanner.start(i, &objs[i]);
-                for (j, obj2) in objs.iter().enumerate() {
-                    if i == j {
-                        continue;
+            let use_sort_map = shared.use_sort_map.load(Ordering::Relaxed);
+            // Step on the ticks the receivers extrapolate on, whatever the rate is
+            for _ in 0..clock.advance(sender.time()) {
+                let mut scanner = BoidScanner::new(Some(&mut rng), &params);
+                if use_sort_map {
+                    sort_map.update(&objs);
+                    sort_map.scan(&mut objs, &mut scanner);
+                    continue;
+                }
+                for i in 0..objs.len() {
+                    scanner.start(i, &objs[i]);
+                    for (j, obj2) in objs.iter().enumerate() {
+                        if i == j {
+                            continue;
+                        }
+                        scanner.next(j, obj2);
                     }
-                    scanner.next(j, obj2);
+                    scanner.end(i, &mut objs[i]);
                 }
-                scanner.end(i, &mut objs[i]);
             }
-            shared.find_result.lock().unwrap().clear();
+            if use_sort_map {
+                let mut find_scanner = FindScanner::new(*shared.selected_obj.lock().unwrap());
+                sort_map.update(&objs);
+                sort_map.scan(&mut objs, &mut find_scanner);
+                let mut find_result = shared.find_result.lock().unwrap();
+                *find_result = find_scanner.find_result;
+            } else {
+                shared.find_result.lock().unwrap().clear();
+            }
         }
 
-        let mut amt = 0;
+        match sender.poll(&mut transport) {
+            Ok(msgs) => {
+                for (src, msg) in msgs {
+                    let Some(lockstep) = &mut lockstep else {
+                        continue;
+                    };
+                    match msg {
+                        // The receiver may have restarted, and then it starts over from tick 0
+                        Message::Hello => lockstep.forget(src),
+                        Message::LockstepAck { next_tick } => lockstep.on_ack(src, next_tick),
+                        _ => {}
+                    }
+                }
+            }
+            Err(e) => eprintln!("Failed to receive: {e}"),
+        }
 
-        // First, send the number of objects to allocate
-        let mut buf = [0u8; size_of::<usize>() + size_of::<usize>()];
-        0usize.write_to(&mut buf[..size_of::<usize>()]);
-        objs.len().write_to(&mut buf[size_of::<usize>()..]);
-        amt += socket.send_to(&buf, &addr)?;
+        let options = SendOptions {
+            mtu: shared.args.mtu,
+            codec: *shared.codec.lock().unwrap(),
+            bandwidth: *shared.bandwidth.lock().unwrap(),
+            burst_objs: *shared.burst_objs.lock().unwrap(),
+            weights: *shared.weights.lock().unwrap(),
+            interest: Interest {
+                poi: *shared.poi.lock().unwrap(),
+                selected: *shared.selected_obj.lock().unwrap(),
+            },
+            error_threshold: *shared.error_threshold.lock().unwrap(),
+        };
+        let amt = if let Some(lockstep) = &lockstep {
+            let mut amt = 0;
+            for addr in sender.client_addrs().collect::<Vec<_>>() {
+                amt += sender.send(
+                    &mut transport,
+                    addr,
+                    &lockstep.inputs_message(addr, shared.args.mtu),
+                )?;
+                if let Some(checksum) = &checksum {
+                    amt += sender.send(&mut transport, addr, checksum)?;
+                }
+            }
+            amt
+        } else {
+            sender.send_burst(&mut transport, &objs, &options)?
+        };
 
-        for (i, obj) in objs.iter().enumerate().skip(n).take(shared.args.burst_objs) {
-            let mut buf = [0u8; size_of::<usize>() + size_of::<Object>()];
-            (i + 1).write_to(&mut buf[..size_of::<usize>()]);
-            obj.write_to(&mut buf[size_of::<usize>()..]);
-            amt += socket.send_to(&buf, &addr)?;
+        if shared.publish_truth.load(Ordering::Relaxed) && TRUTH_INTERVAL <= last_truth.elapsed() {
+            sender.send_truth(&mut transport, &objs, shared.args.mtu)?;
+            last_truth = Instant::now();
         }
-        n += shared.args.burst_objs;
-        if objs.len() <= n {
-            n = 0;
+        if shared.debug_truth.load(Ordering::Relaxed)
+            && DEBUG_TRUTH_INTERVAL <= last_debug_truth.elapsed()
+        {
+            sender.send_full_truth(&mut transport, &objs, shared.args.mtu)?;
+            last_debug_truth = Instant::now();
         }
 
+        *shared.clients.lock().unwrap() = sender.clients();
+        shared
+            .total_packets
+            .store(sender.packets(), Ordering::Relaxed);
+        *shared.lockstep_ticks.lock().unwrap() = lockstep.as_ref().map(|lockstep| {
+            let slowest = sender
+                .client_addrs()
+                .map(|addr| lockstep.next_needed(addr))
+                .min();
+            (lockstep.sim().tick(), slowest)
+        });
+
         // Don't print to terminal too often
         if t % 100 == 0 {
             println!("[{t}] Sent {amt} bytes!");
@@ -256,15 +601,127 @@ fn sender_thread(shared: Arc<Shared>) -> Result<(), Box<dyn Error>> {
     }
 }
 
+fn apply_lifecycle_request(
+    shared: &Shared,
+    sender: &mut PatchSender,
+    objs: &mut Vec<Object>,
+    request: LifecycleRequest,
+) {
+    match request {
+        LifecycleRequest::Spawn(obj) => {
+            sender.spawn(&obj);
+            objs.push(obj);
+        }
+        LifecycleRequest::Despawn(id) => {
+            let Some(index) = sender.despawn(id) else {
+                return;
+            };
+            objs.swap_remove(index);
+            let mut selected_obj = shared.selected_obj.lock().unwrap();
+            // The last object has moved into the place of the removed one
+            if *selected_obj == Some(index) {
+                *selected_obj = None;
+            } else if *selected_obj == Some(objs.len()) {
+                *selected_obj = Some(index);
+            }
+        }
+    }
+}
+
 pub struct SenderApp {
     shared: Arc<Shared>,
+    stats: StatsHistory,
     show_grid: bool,
     show_neighbors: bool,
     show_distances: bool,
+    /// The id of the client whose link statistics are shown
+    selected_client: Option<u32>,
+    tool: Tool,
+    /// The path of the config file to save and load in the GUI
+    config_path: String,
+    /// The result of the last save or load
+    config_status: String,
 }
 
 impl SenderApp {
+    /// Saves or loads the settings, and returns the result to show. Loading applies the
+    /// settings that can change at runtime.
+    fn config_file(&mut self, action: ConfigFileAction) -> String {
+        let path = Path::new(&self.config_path);
+        let shared = &self.shared;
+        // The sender thread locks objs before the others, so it must not be locked after them
+        let num_objects = shared.objs.lock().unwrap().len();
+        let mut params = shared.params.lock().unwrap();
+        let mut bandwidth = shared.bandwidth.lock().unwrap();
+        let mut burst_objs = shared.burst_objs.lock().unwrap();
+        let mut codec = shared.codec.lock().unwrap();
+        let mut impairment = shared.impairment.lock().unwrap();
+        let result = match action {
+            ConfigFileAction::Save => Config {
+                num_objects: Some(num_objects),
+                rate: Some(shared.args.rate),
+                bandwidth: Some(*bandwidth),
+                burst_objs: *burst_objs,
+                mtu: Some(shared.args.mtu),
+                codec: Some(*codec),
+                params: Some(*params),
+                impairment: Some(*impairment),
+            }
+            .save(path)
+            .map(|()| format!("Saved {path:?}")),
+            ConfigFileAction::Load => Config::load(path).map(|config| {
+                *params = config.params.unwrap_or(*params);
+                *bandwidth = config.bandwidth.unwrap_or(*bandwidth);
+                *burst_objs = config.burst_objs.or(*burst_objs);
+                *codec = config.codec.unwrap_or(*codec);
+                *impairment = config.impairment.unwrap_or(*impairment);
+                let startup_only: Vec<_> = [
+                    ("num_objects", config.num_objects.is_some()),
+                    ("rate", config.rate.is_some()),
+                    ("mtu", config.mtu.is_some()),
+                ]
+                .into_iter()
+                .filter_map(|(key, given)| given.then_some(key))
+                .collect();
+                if startup_only.is_empty() {
+                    format!("Loaded {path:?}")
+                } else {
+                    format!(
+                        "Loaded {path:?}; {} only apply with --config at startup",
+                        startup_only.join(", ")
+                    )
+                }
+            }),
+        };
+        result.unwrap_or_else(|e| format!("Failed to {} {path:?}: {e}", action.verb()))
+    }
+
+    /// The client selected in the list, or the first one if none is.
+    fn client(&self) -> Option<ClientInfo> {
+        let clients = self.shared.clients.lock().unwrap();
+        clients
+            .iter()
+            .find(|client| Some(client.id) == self.selected_client)
+            .or(clients.first())
+            .copied()
+    }
+
+    fn update_stats(&mut self) {
+        let link = self.client().map(|client| client.link).unwrap_or_default();
+        self.stats.update(StatsSnapshot {
+            packets: self.shared.total_packets.load(Ordering::Relaxed),
+            bytes: self.shared.total_amt.load(Ordering::Relaxed),
+            loss_rate: Some(link.loss_rate),
+            duplicates: None,
+            out_of_order: None,
+            rtt: link.rtt,
+            jitter: link.rtt.map(|_| link.rtt_var),
+        });
+    }
+
     fn render(&mut self, ui: &mut Ui) {
+        self.update_stats();
+
         let (response, painter) = render_objects(
             &self.shared.objs.lock().unwrap(),
             *self.shared.selected_obj.lock().unwrap(),
@@ -282,7 +739,31 @@ impl SenderApp {
             Rect::from_min_size(Pos2::ZERO, response.rect.size()),
         );
 
-        if response.clicked() {
+        let poi = response
+            .hover_pos()
+            .map(|scr_pos| from_screen.transform_pos(scr_pos) / SCALE);
+        *self.shared.poi.lock().unwrap() = poi.map(|pos| [pos.x as f64, pos.y as f64]);
+        if let Some(pos) = poi {
+            painter.circle_stroke(
+                to_screen.transform_pos(pos * SCALE),
+                POI_RADIUS as f32 * SCALE,
+                (1., Color32::from_rgba_unmultiplied(0, 0, 0, 63)),
+            );
+        }
+
+        let lockstep = self.shared.args.lockstep;
+        if response.clicked() && self.tool == Tool::Add && !lockstep {
+            if let Some(scr_pos) = response.interact_pointer_pos() {
+                let pos = from_screen.transform_pos(scr_pos) / SCALE;
+                let mut rng = rand::thread_rng();
+                let obj = Object::new([pos.x as f64, pos.y as f64], rng.gen());
+                self.shared
+                    .lifecycle_requests
+                    .lock()
+                    .unwrap()
+                    .push(LifecycleRequest::Spawn(obj));
+            }
+        } else if response.clicked() {
             if let Some(scr_pos) = response.interact_pointer_pos() {
                 let pos = from_screen.transform_pos(scr_pos) / SCALE;
                 let closest_obj = self.shared.objs.lock().unwrap().iter().enumerate().fold(
@@ -307,8 +788,9 @@ impl SenderApp {
         }
 
         if self.show_neighbors {
+            // Lock the objects first, so that the selection is not changed by a despawn
+            let objs = self.shared.objs.lock().unwrap();
             if let Some(selected_obj) = *self.shared.selected_obj.lock().unwrap() {
-                let objs = self.shared.objs.lock().unwrap();
                 if let Ok(first_result) = self.shared.find_result.lock() {
                     let pos0 = objs[selected_obj].pos;
                     for j in first_result.iter() {
@@ -332,12 +814,14 @@ impl SenderApp {
         }
 
         if self.show_distances {
+            let objs = self.shared.objs.lock().unwrap();
+            let params = *self.shared.params.lock().unwrap();
             if let Some(&selected_obj) = self.shared.selected_obj.lock().unwrap().as_ref() {
-                let pos = self.shared.objs.lock().unwrap()[selected_obj].pos;
+                let pos = objs[selected_obj].pos;
                 for (dist, color) in [
-                    (SEPARATION_DIST, Color32::from_rgb(255, 0, 255)),
-                    (ALIGNMENT_DIST, Color32::from_rgb(0, 127, 127)),
-                    (GROUP_SEPARATION_DIST, Color32::from_rgb(127, 127, 0)),
+                    (params.separation_dist, Color32::from_rgb(255, 0, 255)),
+                    (params.alignment_dist, Color32::from_rgb(0, 127, 127)),
+                    (params.group_separation_dist, Color32::from_rgb(127, 127, 0)),
                 ] {
                     painter.circle_stroke(
                         to_screen.transform_pos(pos2(pos[0] as f32 * SCALE, pos[1] as f32 * SCALE)),
@@ -361,8 +845,9 @@ impl SenderApp {
             response.rect.left_top(),
             Align2::LEFT_TOP,
             format!(
-                "Sent {} bytes",
-                self.shared.total_amt.load(Ordering::Relaxed)
+                "Sent {} bytes ({:.0} bytes/s)",
+                self.shared.total_amt.load(Ordering::Relaxed),
+                self.stats.bytes_per_sec.last().unwrap_or(0.)
             ),
             FontId::proportional(16.),
             Color32::BLACK,
@@ -378,10 +863,223 @@ impl SenderApp {
         self.shared
             .use_sort_map
             .store(use_sort_map, Ordering::Release);
-        ui.label("Randomness:");
-        let mut randomness = self.shared.randomness.lock().unwrap();
-        ui.add(egui::widgets::Slider::new(&mut *randomness, (0.)..=0.1));
-        drop(randomness);
+        ui.label("Codec:");
+        let mut codec = self.shared.codec.lock().unwrap();
+        ui.radio_value(&mut *codec, Codec::Full, "Full (f64)");
+        ui.radio_value(&mut *codec, Codec::Quantized, "Quantized (16 bit)");
+        drop(codec);
+
+        ui.collapsing("Boid parameters", |ui| {
+            let mut params = self.shared.params.lock().unwrap();
+            for (info, value) in BoidParams::INFO.iter().zip(params.fields_mut()) {
+                ui.add(
+                    egui::widgets::Slider::new(value, (0.)..=info.max)
+                        .logarithmic(info.logarithmic)
+                        .text(info.label),
+                );
+            }
+            if ui.button("Reset to defaults").clicked() {
+                *params = BoidParams::default();
+            }
+        });
+
+        ui.separator();
+        ui.heading("Objects");
+        ui.label(format!(
+            "Objects: {}",
+            self.shared.entity_ids.lock().unwrap().len()
+        ));
+        // The lockstep simulation has a fixed set of objects
+        ui.add_enabled_ui(!self.shared.args.lockstep, |ui| self.render_tools(ui));
+
+        if let Some((tick, slowest)) = *self.shared.lockstep_ticks.lock().unwrap() {
+            ui.separator();
+            ui.heading("Lockstep");
+            ui.label(format!("Seed: {}", self.shared.args.seed.unwrap_or(0)));
+            ui.label(format!("Tick: {tick}"));
+            if let Some(next_needed) = slowest {
+                ui.label(format!(
+                    "Acknowledged by the slowest client: {next_needed} ({} behind)",
+                    tick - next_needed
+                ));
+            }
+        }
+
+        ui.separator();
+        ui.heading("Scheduling");
+        ui.label("Bandwidth (bytes/s):");
+        ui.add(
+            egui::widgets::Slider::new(&mut *self.shared.bandwidth.lock().unwrap(), (1e3)..=1e6)
+                .logarithmic(true),
+        );
+        let mut burst_objs = self.shared.burst_objs.lock().unwrap();
+        let mut capped = burst_objs.is_some();
+        ui.checkbox(&mut capped, "Limit objects per burst");
+        let mut max_objects = burst_objs.unwrap_or(DEFAULT_BURST_OBJS);
+        ui.add_enabled(
+            capped,
+            egui::widgets::Slider::new(&mut max_objects, 1..=1000)
+                .logarithmic(true)
+                .text("Objects per burst"),
+        );
+        *burst_objs = capped.then_some(max_objects);
+        drop(burst_objs);
+        ui.collapsing("Priority weights", |ui| {
+            let mut guard = self.shared.weights.lock().unwrap();
+            let weights = &mut *guard;
+            for (value, label) in [
+                (&mut weights.staleness, "Staleness"),
+                (&mut weights.error, "Error"),
+                (&mut weights.proximity, "Proximity to cursor"),
+                (&mut weights.selected, "Selected"),
+            ] {
+                ui.add(
+                    egui::widgets::Slider::new(value, (0.)..=100.)
+                        .logarithmic(true)
+                        .text(label),
+                );
+            }
+        });
+
+        ui.separator();
+        ui.heading("Dead reckoning");
+        let mut error_threshold = self.shared.error_threshold.lock().unwrap();
+        let mut enabled = error_threshold.is_some();
+        ui.checkbox(&mut enabled, "Prioritize diverged objects");
+        let mut threshold = error_threshold.unwrap_or(DEFAULT_ERROR_THRESHOLD);
+        ui.add_enabled(
+            enabled,
+            egui::widgets::Slider::new(&mut threshold, (0.)..=1.)
+                .logarithmic(true)
+                .text("Error threshold"),
+        );
+        *error_threshold = enabled.then_some(threshold);
+        if let (true, Some(client)) = (enabled, self.client()) {
+            ui.label(format!("Diverged objects: {}", client.diverged));
+        }
+        drop(error_threshold);
+
+        let mut publish_truth = self.shared.publish_truth.load(Ordering::Acquire);
+        ui.checkbox(&mut publish_truth, "Publish ground truth");
+        self.shared
+            .publish_truth
+            .store(publish_truth, Ordering::Release);
+        let mut debug_truth = self.shared.debug_truth.load(Ordering::Acquire);
+        ui.checkbox(&mut debug_truth, "Send debug truth stream");
+        self.shared
+            .debug_truth
+            .store(debug_truth, Ordering::Release);
+
+        ui.separator();
+        ui.heading("Network impairment");
+        let stats = *self.shared.impairment_stats.lock().unwrap();
+        render_impairment(ui, &mut self.shared.impairment.lock().unwrap(), stats);
+
+        ui.separator();
+        ui.heading("Config");
+        if let Some(action) = render_config_file(ui, &mut self.config_path, &self.config_status) {
+            self.config_status = self.config_file(action);
+        }
+
+        ui.separator();
+        ui.heading("Clients");
+        self.render_clients(ui);
+
+        ui.separator();
+        ui.heading("Statistics");
+        render_stats(ui, &self.stats);
+        if let Some(client) = self.client() {
+            ui.label(format!("Client #{} at {}", client.id, client.addr));
+            ui.label(format!(
+                "Acked datagrams: {}, lost: {}",
+                client.link.acked, client.link.lost
+            ));
+            ui.label(format!(
+                "Confirmed objects: {} / {}",
+                client.confirmed,
+                self.shared.objs.lock().unwrap().len()
+            ));
+            if let Some(sent) = client.snapshot_progress {
+                ui.label(format!(
+                    "Sending a snapshot: {sent} / {}",
+                    self.shared.objs.lock().unwrap().len()
+                ));
+            }
+        }
+    }
+
+    /// Shows the tools to add and delete objects, which exercise the lifecycle events.
+    fn render_tools(&mut self, ui: &mut Ui) {
+        ui.horizontal(|ui| {
+            ui.label("Click to:");
+            ui.radio_value(&mut self.tool, Tool::Select, "Select");
+            ui.radio_value(&mut self.tool, Tool::Add, "Add an object");
+        });
+        let selected = *self.shared.selected_obj.lock().unwrap();
+        let id = selected.and_then(|index| {
+            let entity_ids = self.shared.entity_ids.lock().unwrap();
+            entity_ids.get(index).copied()
+        });
+        match id {
+            Some(id) => ui.label(format!("Selected: #{id}")),
+            None => ui.label("Selected: none"),
+        };
+        let delete = ui
+            .add_enabled(id.is_some(), egui::Button::new("Delete selected"))
+            .on_hover_text("Or press the Delete key")
+            .clicked()
+            || !ui.ctx().wants_keyboard_input()
+                && ui.input(|input| input.key_pressed(egui::Key::Delete));
+        if let (true, Some(id)) = (delete, id) {
+            self.shared
+                .lifecycle_requests
+                .lock()
+                .unwrap()
+                .push(LifecycleRequest::Despawn(id));
+        }
+    }
+
+    /// Lists the clients with their link statistics, one of which can be selected to show
+    /// the details.
+    fn render_clients(&mut self, ui: &mut Ui) {
+        let clients = self.shared.clients.lock().unwrap().clone();
+        if clients.is_empty() {
+            ui.label("No clients");
+            return;
+        }
+        egui::Grid::new("clients").striped(true).show(ui, |ui| {
+            ui.label("");
+            ui.label("Address");
+            ui.label("RTT");
+            ui.label("Loss");
+            ui.label("Sent");
+            ui.label("Idle");
+            ui.end_row();
+            for client in &clients {
+                let selected = self.selected_client.unwrap_or(clients[0].id) == client.id;
+                if ui
+                    .radio(selected, format!("#{}", client.id))
+                    .on_hover_text(if client.group {
+                        "Multicast group or broadcast address"
+                    } else if client.fixed {
+                        "Given on the command line"
+                    } else {
+                        "Registered with a hello"
+                    })
+                    .clicked()
+                {
+                    self.selected_client = Some(client.id);
+                }
+                ui.label(client.addr.to_string());
+                ui.label(client.link.rtt.map_or("-".to_string(), |rtt| {
+                    format!("{:.1} ms", rtt.as_secs_f64() * 1e3)
+                }));
+                ui.label(format!("{:.1} %", client.link.loss_rate * 100.));
+                ui.label(format!("{} B", client.bytes));
+                ui.label(format!("{:.1} s", client.idle.as_secs_f64()));
+                ui.end_row();
+            }
+        });
     }
 }
 
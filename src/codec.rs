@@ -0,0 +1,147 @@
+//! Codecs for the numeric fields of an object on the wire.
+//!
+//! [`Codec::Quantized`] trades precision for bandwidth by sending positions as 16-bit
+//! fixed point numbers in the range of `[0, POS_RANGE]`, and velocities as 16-bit signed
+//! fixed point numbers in the range of `[-VELO_RANGE, VELO_RANGE]`.
+
+use crate::object::BoidParams;
+
+/// The bound of velocity in the quantized codec. It is fixed to twice the highest
+/// [`BoidParams::max_speed`] the GUI can set because both ends must agree on it. Objects can
+/// exceed the maximum speed for a while because the speed is adapted gradually, so we leave some
+/// margin, but a faster velocity is clamped.
+pub const VELO_RANGE: f64 = 2. * BoidParams::MAX_SPEED_BOUND;
+
+/// The bound of position in the quantized codec. It is fixed to the largest
+/// [`BoidParams::space_width`] the GUI can set because both ends must agree on it.
+pub const POS_RANGE: f64 = BoidParams::SPACE_WIDTH_BOUND;
+
+/// The maximum error of a position reconstructed from the quantized codec.
+pub const POS_PRECISION: f64 = POS_RANGE / u16::MAX as f64 / 2.;
+
+/// The maximum error of a velocity within `VELO_RANGE` reconstructed from the quantized codec.
+pub const VELO_PRECISION: f64 = VELO_RANGE / i16::MAX as f64 / 2.;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Codec {
+    /// Lossless 64-bit floating point numbers.
+    #[default]
+    Full,
+    /// 16-bit fixed point numbers.
+    Quantized,
+}
+
+impl Codec {
+    /// The number of bytes of a numeric field.
+    pub fn field_size(&self) -> usize {
+        match self {
+            Self::Full => 8,
+            Self::Quantized => 2,
+        }
+    }
+
+    /// Encodes position and velocity fields, in the order of `[pos.x, pos.y, velo.x, velo.y]`,
+    /// into integers of [`Self::field_size`] bytes.
+    pub fn encode(&self, fields: [f64; 4]) -> [u64; 4] {
+        match self {
+            Self::Full => fields.map(f64::to_bits),
+            Self::Quantized => [
+                quantize_pos(fields[0]) as u64,
+                quantize_pos(fields[1]) as u64,
+                quantize_velo(fields[2]) as u16 as u64,
+                quantize_velo(fields[3]) as u16 as u64,
+            ],
+        }
+    }
+
+    /// The inverse of [`Self::encode`].
+    pub fn decode(&self, fields: [u64; 4]) -> [f64; 4] {
+        match self {
+            Self::Full => fields.map(f64::from_bits),
+            Self::Quantized => [
+                dequantize_pos(fields[0] as u16),
+                dequantize_pos(fields[1] as u16),
+                dequantize_velo(fields[2] as u16 as i16),
+                dequantize_velo(fields[3] as u16 as i16),
+            ],
+        }
+    }
+}
+
+fn quantize_pos(v: f64) -> u16 {
+    (v / POS_RANGE * u16::MAX as f64)
+        .round()
+        .clamp(0., u16::MAX as f64) as u16
+}
+
+fn dequantize_pos(v: u16) -> f64 {
+    v as f64 / u16::MAX as f64 * POS_RANGE
+}
+
+fn quantize_velo(v: f64) -> i16 {
+    (v / VELO_RANGE * i16::MAX as f64)
+        .round()
+        .clamp(-(i16::MAX as f64), i16::MAX as f64) as i16
+}
+
+fn dequantize_velo(v: i16) -> f64 {
+    v as f64 / i16::MAX as f64 * VELO_RANGE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Evenly spaced values over `[min, max]`, including both ends.
+    fn sweep(min: f64, max: f64) -> impl Iterator<Item = f64> {
+        const STEPS: u32 = 10007;
+        (0..=STEPS).map(move |i| min + (max - min) * i as f64 / STEPS as f64)
+    }
+
+    #[test]
+    fn full_is_lossless() {
+        let fields = [0.1, POS_RANGE, -0.0, f64::MIN_POSITIVE];
+        let decoded = Codec::Full.decode(Codec::Full.encode(fields));
+        assert_eq!(decoded.map(f64::to_bits), fields.map(f64::to_bits));
+    }
+
+    #[test]
+    fn quantized_error_is_bounded() {
+        for (pos, velo) in sweep(0., POS_RANGE).zip(sweep(-VELO_RANGE, VELO_RANGE)) {
+            let fields = [pos, POS_RANGE - pos, velo, -velo];
+            let encoded = Codec::Quantized.encode(fields);
+            assert!(encoded.iter().all(|v| *v <= u16::MAX as u64));
+            let decoded = Codec::Quantized.decode(encoded);
+            for axis in 0..2 {
+                assert!((decoded[axis] - fields[axis]).abs() <= POS_PRECISION * (1. + 1e-9));
+                assert!(
+                    (decoded[2 + axis] - fields[2 + axis]).abs() <= VELO_PRECISION * (1. + 1e-9)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn quantized_keeps_the_bounds_and_the_sign() {
+        let decoded = Codec::Quantized.decode(Codec::Quantized.encode([
+            0.,
+            POS_RANGE,
+            VELO_RANGE,
+            -VELO_RANGE,
+        ]));
+        assert_eq!(decoded, [0., POS_RANGE, VELO_RANGE, -VELO_RANGE]);
+        let decoded = Codec::Quantized.decode(Codec::Quantized.encode([1., 1., 1e-3, -1e-3]));
+        assert!(0. < decoded[2] && decoded[3] < 0.);
+    }
+
+    #[test]
+    fn quantized_clamps_out_of_range_values() {
+        let decoded = Codec::Quantized.decode(Codec::Quantized.encode([
+            -1.,
+            POS_RANGE * 2.,
+            VELO_RANGE * 3.,
+            -VELO_RANGE * 3.,
+        ]));
+        assert_eq!(decoded, [0., POS_RANGE, VELO_RANGE, -VELO_RANGE]);
+    }
+}
@@ -0,0 +1,371 @@
+//! Experiment setups saved to and loaded from TOML or JSON files.
+//!
+//! A file can give any subset of the settings, e.g.
+//!
+//! ```toml
+//! num_objects = 2000
+//! mtu = 500
+//! burst_objs = 50
+//!
+//! [params]
+//! cohesion = 2e-4
+//! space_width = 20.0
+//!
+//! [impairment]
+//! loss = 0.1
+//! ```
+//!
+//! The omitted settings are left to the command line arguments, or their defaults, except that
+//! the omitted keys in `[params]` and `[impairment]` take their default values, so that the
+//! section as a whole is reproducible. Each binary applies the settings it has, so one file can
+//! be shared by the sender and the receiver.
+
+use std::{fmt, path::Path};
+
+use clap::{parser::ValueSource, ArgMatches, ValueEnum};
+use serde_json::{Map, Value};
+
+use crate::{
+    codec::Codec,
+    impair::{ImpairmentConfig, MAX_DELAY},
+    object::BoidParams,
+};
+
+/// The file the GUI saves to and loads from unless `--config` gives one.
+pub const DEFAULT_PATH: &str = "config.toml";
+
+/// The keys of the top level table.
+const KEYS: [&str; 8] = [
+    "num_objects",
+    "rate",
+    "bandwidth",
+    "burst_objs",
+    "mtu",
+    "codec",
+    "params",
+    "impairment",
+];
+
+/// The keys of the `[impairment]` table, which are the names of the fields of
+/// [`ImpairmentConfig`].
+const IMPAIRMENT_KEYS: [&str; 7] = [
+    "loss",
+    "duplicate",
+    "reorder",
+    "corrupt",
+    "latency",
+    "jitter",
+    "seed",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Toml,
+    Json,
+}
+
+impl Format {
+    /// Tells the format from the extension of a path, which is TOML unless it is `.json`.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension() {
+            Some(ext) if ext.eq_ignore_ascii_case("json") => Self::Json,
+            _ => Self::Toml,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(std::io::Error),
+    /// The file is not a valid TOML or JSON document
+    Syntax(String),
+    /// The value of a key is invalid, where the key is a dotted path such as `params.cohesion`
+    Key {
+        key: String,
+        message: String,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{e}"),
+            Self::Syntax(e) => write!(f, "syntax error: {e}"),
+            Self::Key { key, message } => write!(f, "invalid key `{key}`: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl ConfigError {
+    fn key(key: &str, message: impl Into<String>) -> Self {
+        Self::Key {
+            key: key.to_string(),
+            message: message.into(),
+        }
+    }
+}
+
+/// The settings of an experiment, each of which is `None` if not given.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Config {
+    pub num_objects: Option<usize>,
+    /// The interval of sending in milliseconds
+    pub rate: Option<u64>,
+    /// The budget of bytes per second
+    pub bandwidth: Option<f64>,
+    /// The maximum number of objects to send in a burst
+    pub burst_objs: Option<usize>,
+    pub mtu: Option<usize>,
+    pub codec: Option<Codec>,
+    pub params: Option<BoidParams>,
+    pub impairment: Option<ImpairmentConfig>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let text = std::fs::read_to_string(path).map_err(ConfigError::Io)?;
+        Self::parse(&text, Format::from_path(path))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), ConfigError> {
+        let text = self.to_string(Format::from_path(path))?;
+        std::fs::write(path, text).map_err(ConfigError::Io)
+    }
+
+    pub fn parse(text: &str, format: Format) -> Result<Self, ConfigError> {
+        let value: Value = match format {
+            Format::Toml => toml::from_str(text)
+                .map_err(|e| ConfigError::Syntax(e.to_string().trim_end().into()))?,
+            Format::Json => {
+                serde_json::from_str(text).map_err(|e| ConfigError::Syntax(e.to_string()))?
+            }
+        };
+        let Value::Object(table) = value else {
+            return Err(ConfigError::Syntax(
+                "expected a table at the top level".into(),
+            ));
+        };
+        let mut config = Self::default();
+        for (key, value) in table {
+            match key.as_str() {
+                "num_objects" => config.num_objects = Some(integer(&key, &value)?),
+                "rate" => config.rate = Some(integer(&key, &value)?),
+                "bandwidth" => config.bandwidth = Some(number(&key, &value)?),
+                "burst_objs" => config.burst_objs = Some(integer(&key, &value)?),
+                "mtu" => config.mtu = Some(integer(&key, &value)?),
+                "codec" => config.codec = Some(codec(&key, &value)?),
+                "params" => config.params = Some(parse_params(value)?),
+                "impairment" => config.impairment = Some(parse_impairment(value)?),
+                _ => return Err(unknown_key(&key, &KEYS)),
+            }
+        }
+        Ok(config)
+    }
+
+    /// Fails if a value cannot be represented in the format, which is a seed beyond `i64::MAX`
+    /// in TOML.
+    pub fn to_string(&self, format: Format) -> Result<String, ConfigError> {
+        let mut table = Map::new();
+        if let Some(num_objects) = self.num_objects {
+            table.insert("num_objects".into(), num_objects.into());
+        }
+        if let Some(rate) = self.rate {
+            table.insert("rate".into(), rate.into());
+        }
+        if let Some(bandwidth) = self.bandwidth {
+            table.insert("bandwidth".into(), bandwidth.into());
+        }
+        if let Some(burst_objs) = self.burst_objs {
+            table.insert("burst_objs".into(), burst_objs.into());
+        }
+        if let Some(mtu) = self.mtu {
+            table.insert("mtu".into(), mtu.into());
+        }
+        if let Some(codec) = self.codec {
+            let name = codec.to_possible_value().unwrap().get_name().to_string();
+            table.insert("codec".into(), name.into());
+        }
+        if let Some(params) = self.params {
+            let params = BoidParams::INFO
+                .iter()
+                .zip(params.fields())
+                .map(|(info, value)| (info.key.to_string(), value.into()))
+                .collect();
+            table.insert("params".into(), Value::Object(params));
+        }
+        if let Some(impairment) = self.impairment {
+            let mut values = Map::new();
+            for (key, value) in IMPAIRMENT_KEYS.iter().zip([
+                impairment.loss,
+                impairment.duplicate,
+                impairment.reorder,
+                impairment.corrupt,
+                impairment.latency,
+                impairment.jitter,
+            ]) {
+                values.insert(key.to_string(), value.into());
+            }
+            // TOML has no null, so an omitted seed means a random one
+            if let Some(seed) = impairment.seed {
+                if format == Format::Toml && i64::try_from(seed).is_err() {
+                    return Err(ConfigError::key(
+                        "impairment.seed",
+                        format!("{seed} is too large for TOML, whose integers are 64-bit signed"),
+                    ));
+                }
+                values.insert("seed".into(), seed.into());
+            }
+            table.insert("impairment".into(), Value::Object(values));
+        }
+        let value = Value::Object(table);
+        match format {
+            Format::Toml => {
+                toml::to_string_pretty(&value).map_err(|e| ConfigError::Syntax(e.to_string()))
+            }
+            Format::Json => serde_json::to_string_pretty(&value)
+                .map(|text| text + "\n")
+                .map_err(|e| ConfigError::Syntax(e.to_string())),
+        }
+    }
+
+    /// Overrides `impairment` with the one in the file, except for the values given on the
+    /// command line.
+    pub fn apply_impairment(&self, impairment: &mut ImpairmentConfig, matches: &ArgMatches) {
+        let Some(config) = self.impairment else {
+            return;
+        };
+        for (id, value, config_value) in [
+            ("loss", &mut impairment.loss, config.loss),
+            ("duplicate", &mut impairment.duplicate, config.duplicate),
+            ("reorder", &mut impairment.reorder, config.reorder),
+            ("corrupt", &mut impairment.corrupt, config.corrupt),
+            ("latency", &mut impairment.latency, config.latency),
+            ("jitter", &mut impairment.jitter, config.jitter),
+        ] {
+            if !from_command_line(matches, id) {
+                *value = config_value;
+            }
+        }
+        if !from_command_line(matches, "impair_seed") {
+            impairment.seed = config.seed;
+        }
+    }
+}
+
+/// Whether an argument was given on the command line, which takes precedence over the file.
+pub fn from_command_line(matches: &ArgMatches, id: &str) -> bool {
+    matches.value_source(id) == Some(ValueSource::CommandLine)
+}
+
+fn parse_params(value: Value) -> Result<BoidParams, ConfigError> {
+    let mut params = BoidParams::default();
+    let keys = BoidParams::INFO.map(|info| info.key);
+    for (key, value) in table("params", value)? {
+        let path = format!("params.{key}");
+        let Some(index) = keys.iter().position(|k| *k == key) else {
+            return Err(unknown_key(&path, &keys));
+        };
+        *params.fields_mut()[index] = number(&path, &value)?;
+    }
+    Ok(params)
+}
+
+fn parse_impairment(value: Value) -> Result<ImpairmentConfig, ConfigError> {
+    let mut impairment = ImpairmentConfig::default();
+    for (key, value) in table("impairment", value)? {
+        let path = format!("impairment.{key}");
+        match key.as_str() {
+            "loss" => impairment.loss = probability(&path, &value)?,
+            "duplicate" => impairment.duplicate = probability(&path, &value)?,
+            "reorder" => impairment.reorder = probability(&path, &value)?,
+            "corrupt" => impairment.corrupt = probability(&path, &value)?,
+            "latency" => impairment.latency = delay(&path, &value)?,
+            "jitter" => impairment.jitter = delay(&path, &value)?,
+            "seed" if value.is_null() => impairment.seed = None,
+            "seed" => impairment.seed = Some(integer(&path, &value)?),
+            _ => return Err(unknown_key(&path, &IMPAIRMENT_KEYS)),
+        }
+    }
+    Ok(impairment)
+}
+
+fn table(key: &str, value: Value) -> Result<Map<String, Value>, ConfigError> {
+    match value {
+        Value::Object(table) => Ok(table),
+        value => Err(ConfigError::key(
+            key,
+            format!("expected a table, found {value}"),
+        )),
+    }
+}
+
+fn unknown_key(key: &str, expected: &[&str]) -> ConfigError {
+    ConfigError::key(
+        key,
+        format!("unknown key, expected one of {}", expected.join(", ")),
+    )
+}
+
+/// A finite number that is not negative, which all the real valued settings are.
+fn number(key: &str, value: &Value) -> Result<f64, ConfigError> {
+    value
+        .as_f64()
+        .filter(|v| v.is_finite() && 0. <= *v)
+        .ok_or_else(|| {
+            ConfigError::key(
+                key,
+                format!("expected a non-negative number, found {value}"),
+            )
+        })
+}
+
+fn probability(key: &str, value: &Value) -> Result<f64, ConfigError> {
+    number(key, value).ok().filter(|v| *v <= 1.).ok_or_else(|| {
+        ConfigError::key(
+            key,
+            format!("expected a probability in [0, 1], found {value}"),
+        )
+    })
+}
+
+fn delay(key: &str, value: &Value) -> Result<f64, ConfigError> {
+    number(key, value)
+        .ok()
+        .filter(|v| *v <= MAX_DELAY)
+        .ok_or_else(|| {
+            ConfigError::key(
+                key,
+                format!("expected milliseconds in [0, {MAX_DELAY}], found {value}"),
+            )
+        })
+}
+
+fn integer<T: TryFrom<u64>>(key: &str, value: &Value) -> Result<T, ConfigError> {
+    value
+        .as_u64()
+        .and_then(|v| T::try_from(v).ok())
+        .ok_or_else(|| {
+            ConfigError::key(
+                key,
+                format!("expected a non-negative integer, found {value}"),
+            )
+        })
+}
+
+fn codec(key: &str, value: &Value) -> Result<Codec, ConfigError> {
+    let names: Vec<_> = Codec::value_variants()
+        .iter()
+        .map(|codec| codec.to_possible_value().unwrap().get_name().to_string())
+        .collect();
+    value
+        .as_str()
+        .and_then(|name| Codec::from_str(name, true).ok())
+        .ok_or_else(|| {
+            ConfigError::key(
+                key,
+                format!("expected one of {}, found {value}", names.join(", ")),
+            )
+        })
+}
@@ -0,0 +1,329 @@
+//! Delta compression of object states against acknowledged baselines.
+//!
+//! Similar to the snapshot delta encoding of Quake 3, the receiver acknowledges the
+//! datagrams it has received, and the sender encodes each object's state as a difference
+//! against the newest state of that object the receiver is known to have (the baseline).
+//! If there is no such baseline, the state is encoded against an all-zero object, which
+//! is equivalent to sending the full state.
+//!
+//! The difference of a `f64` field is the XOR of its bit pattern with the baseline's.
+//! Since objects move little between updates, the sign, exponent and higher mantissa bits
+//! tend to be the same, so the XOR has leading zero bytes that are not sent.
+//! It is lossless, so the baselines on both sides stay bit-identical.
+//!
+//! With [`Codec::Quantized`], the XOR is taken between the quantized integers instead.
+//! The sender should then keep the reconstructed state (see [`ObjectDelta::apply`]) as the
+//! baseline, not the original one, so that it matches what the receiver has.
+
+use std::collections::VecDeque;
+
+use crate::{
+    codec::Codec,
+    protocol::{seq_newer, ProtocolError},
+    wire::{WireReader, WireWriter},
+    Object,
+};
+
+/// The number of most recent states of each object the receiver keeps as baselines.
+/// The sender never refers to a baseline older than this many sends of the object.
+pub const BASELINE_HISTORY: usize = 16;
+
+/// The number of sent datagrams the sender remembers to match with acknowledgements.
+const SENT_HISTORY: usize = 1024;
+
+const FLAG_BASELINE: u8 = 1;
+const FLAG_COLOR: u8 = 2;
+const FLAG_QUANTIZED: u8 = 4;
+
+fn fields(obj: &Object) -> [f64; 4] {
+    [obj.pos[0], obj.pos[1], obj.velo[0], obj.velo[1]]
+}
+
+/// The state of an object encoded as a difference against a baseline.
+#[derive(Clone, Copy, Debug)]
+pub struct ObjectDelta {
+    /// The sequence number of the datagram that carried the baseline, or `None` for a full state.
+    pub base_seq: Option<u32>,
+    codec: Codec,
+    fields: [u64; 4],
+    color: Option<[u8; 3]>,
+}
+
+impl ObjectDelta {
+    pub fn new(obj: &Object, base: Option<(u32, &Object)>, codec: Codec) -> Self {
+        let base_obj = base.map(|(_, base)| *base).unwrap_or_default();
+        let base_fields = codec.encode(fields(&base_obj));
+        let mut ret = Self {
+            base_seq: base.map(|(seq, _)| seq),
+            codec,
+            fields: codec.encode(fields(obj)),
+            color: (base.is_none() || obj.color != base_obj.color).then_some(obj.color),
+        };
+        for (delta, base) in ret.fields.iter_mut().zip(base_fields.iter()) {
+            *delta ^= base;
+        }
+        ret
+    }
+
+    /// Reconstructs the object state from the baseline, which should be the object
+    /// carried by the datagram `base_seq`, or `None` for a full state.
+    pub fn apply(&self, base: Option<&Object>) -> Object {
+        let base = base.copied().unwrap_or_default();
+        let mut encoded = self.codec.encode(fields(&base));
+        for (v, delta) in encoded.iter_mut().zip(self.fields.iter()) {
+            *v ^= delta;
+        }
+        let v = self.codec.decode(encoded);
+        let mut obj = Object::new([v[0], v[1]], self.color.unwrap_or(base.color));
+        obj.velo = [v[2], v[3]];
+        obj
+    }
+
+    fn field_len(delta: u64) -> usize {
+        8 - delta.leading_zeros() as usize / 8
+    }
+
+    pub fn wire_size(&self) -> usize {
+        1 + self.base_seq.map_or(0, |_| 4)
+            + 2
+            + self
+                .fields
+                .iter()
+                .copied()
+                .map(Self::field_len)
+                .sum::<usize>()
+            + self.color.map_or(0, |_| 3)
+    }
+
+    pub(crate) fn write(&self, writer: &mut WireWriter) {
+        let mut flags = 0;
+        if self.base_seq.is_some() {
+            flags |= FLAG_BASELINE;
+        }
+        if self.color.is_some() {
+            flags |= FLAG_COLOR;
+        }
+        if self.codec == Codec::Quantized {
+            flags |= FLAG_QUANTIZED;
+        }
+        writer.u8(flags);
+        if let Some(base_seq) = self.base_seq {
+            writer.u32(base_seq);
+        }
+        // The byte lengths of the fields are packed into nibbles
+        let lens = self
+            .fields
+            .iter()
+            .enumerate()
+            .fold(0u16, |acc, (i, delta)| {
+                acc | (Self::field_len(*delta) as u16) << (i * 4)
+            });
+        writer.u16(lens);
+        for delta in self.fields {
+            writer.bytes(&delta.to_le_bytes()[..Self::field_len(delta)]);
+        }
+        if let Some(color) = self.color {
+            writer.bytes(&color);
+        }
+    }
+
+    pub(crate) fn read(reader: &mut WireReader) -> Result<Self, ProtocolError> {
+        let flags = reader.u8()?;
+        let base_seq = if flags & FLAG_BASELINE != 0 {
+            Some(reader.u32()?)
+        } else {
+            None
+        };
+        let codec = if flags & FLAG_QUANTIZED != 0 {
+            Codec::Quantized
+        } else {
+            Codec::Full
+        };
+        let lens = reader.u16()?;
+        let mut fields = [0u64; 4];
+        for (i, field) in fields.iter_mut().enumerate() {
+            let len = ((lens >> (i * 4)) & 0xf) as usize;
+            if codec.field_size() < len {
+                return Err(ProtocolError::Malformed(
+                    "delta field longer than the codec's",
+                ));
+            }
+            let mut bytes = [0u8; 8];
+            bytes[..len].copy_from_slice(reader.bytes(len)?);
+            *field = u64::from_le_bytes(bytes);
+        }
+        let color = if flags & FLAG_COLOR != 0 {
+            let bytes = reader.bytes(3)?;
+            Some([bytes[0], bytes[1], bytes[2]])
+        } else {
+            None
+        };
+        Ok(Self {
+            base_seq,
+            codec,
+            fields,
+            color,
+        })
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Baseline {
+    seq: u32,
+    obj: Object,
+    /// The ordinal of the send of this object that carried the baseline.
+    send_no: usize,
+}
+
+#[derive(Default)]
+struct SentDatagram {
+    seq: u32,
+    objs: Vec<(usize, Object, usize)>,
+}
+
+/// The sender side history of what has been sent and acknowledged by a receiver.
+#[derive(Default)]
+pub struct BaselineHistory {
+    sent: VecDeque<SentDatagram>,
+    baselines: Vec<Option<Baseline>>,
+    /// The number of times each object has been sent.
+    sends: Vec<usize>,
+}
+
+impl BaselineHistory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Makes room for new objects at the end, or forgets the ones beyond `num_objects`.
+    pub fn resize(&mut self, num_objects: usize) {
+        if num_objects < self.baselines.len() {
+            for datagram in &mut self.sent {
+                datagram.objs.retain(|(index, _, _)| *index < num_objects);
+            }
+        }
+        self.baselines.resize(num_objects, None);
+        self.sends.resize(num_objects, 0);
+    }
+
+    /// Forgets the object `index` and moves the last one into its place, following
+    /// [`crate::entity::EntityMap::remove`].
+    pub fn swap_remove(&mut self, index: usize) {
+        let last = self.baselines.len() - 1;
+        for datagram in &mut self.sent {
+            datagram.objs.retain(|(i, _, _)| *i != index);
+            for (i, _, _) in &mut datagram.objs {
+                if *i == last {
+                    *i = index;
+                }
+            }
+        }
+        self.baselines.swap_remove(index);
+        self.sends.swap_remove(index);
+    }
+
+    /// Returns the newest acknowledged state of the object that the receiver is guaranteed
+    /// to still have in its [`BaselineStore`].
+    pub fn baseline(&self, index: usize) -> Option<(u32, &Object)> {
+        let baseline = self.baselines.get(index)?.as_ref()?;
+        if BASELINE_HISTORY < self.sends[index] - baseline.send_no {
+            return None;
+        }
+        Some((baseline.seq, &baseline.obj))
+    }
+
+    /// Returns the sequence number of the datagram that carried the newest state of the object
+    /// confirmed to have arrived at the receiver.
+    pub fn confirmed(&self, index: usize) -> Option<u32> {
+        Some(self.baselines.get(index)?.as_ref()?.seq)
+    }
+
+    /// Returns the number of objects of which some state has been confirmed.
+    pub fn num_confirmed(&self) -> usize {
+        self.baselines.iter().filter(|b| b.is_some()).count()
+    }
+
+    /// Records the objects sent in the datagram `seq`, with their states at the time.
+    pub fn on_sent(&mut self, seq: u32, objs: impl Iterator<Item = (usize, Object)>) {
+        let objs = objs
+            .filter_map(|(index, obj)| {
+                let sends = self.sends.get_mut(index)?;
+                let send_no = *sends;
+                *sends += 1;
+                Some((index, obj, send_no))
+            })
+            .collect();
+        if SENT_HISTORY <= self.sent.len() {
+            self.sent.pop_front();
+        }
+        self.sent.push_back(SentDatagram { seq, objs });
+    }
+
+    /// Promotes the objects in the acknowledged datagram `seq` to baselines.
+    pub fn on_ack(&mut self, seq: u32) {
+        let Some(datagram) = self.sent.iter().find(|sent| sent.seq == seq) else {
+            return;
+        };
+        for &(index, obj, send_no) in &datagram.objs {
+            let baseline = &mut self.baselines[index];
+            if baseline.is_none_or(|baseline| seq_newer(seq, baseline.seq)) {
+                *baseline = Some(Baseline { seq, obj, send_no });
+            }
+        }
+    }
+}
+
+/// The receiver side store of the most recently received states of each object.
+#[derive(Default)]
+pub struct BaselineStore {
+    states: Vec<VecDeque<(u32, Object)>>,
+}
+
+impl BaselineStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Makes room for new objects at the end, or forgets the ones beyond `num_objects`.
+    pub fn resize(&mut self, num_objects: usize) {
+        self.states.resize(num_objects, VecDeque::new());
+    }
+
+    /// Forgets the object `index` and moves the last one into its place.
+    pub fn swap_remove(&mut self, index: usize) {
+        self.states.swap_remove(index);
+    }
+
+    /// Decodes a delta of the object `index` against the baseline it refers to.
+    /// Returns `None` if the baseline is not available.
+    pub fn decode(&self, index: usize, delta: &ObjectDelta) -> Option<Object> {
+        let Some(base_seq) = delta.base_seq else {
+            return Some(delta.apply(None));
+        };
+        let (_, base) = self
+            .states
+            .get(index)?
+            .iter()
+            .find(|(seq, _)| *seq == base_seq)?;
+        Some(delta.apply(Some(base)))
+    }
+
+    /// Records the state of an object received in the datagram `seq`.
+    /// Only the [`BASELINE_HISTORY`] newest states are kept, even if they arrive out of order.
+    pub fn insert(&mut self, index: usize, seq: u32, obj: Object) {
+        let Some(states) = self.states.get_mut(index) else {
+            return;
+        };
+        if states.iter().any(|(s, _)| *s == seq) {
+            return;
+        }
+        let pos = states
+            .iter()
+            .position(|(s, _)| seq_newer(*s, seq))
+            .unwrap_or(states.len());
+        states.insert(pos, (seq, obj));
+        if BASELINE_HISTORY < states.len() {
+            states.pop_front();
+        }
+    }
+}
@@ -0,0 +1,141 @@
+//! Measurement of how far the receiver's replicas are from the sender's truth.
+//!
+//! The sender periodically sends the true positions of a rotating sample of objects
+//! ([`crate::protocol::Message::Truth`]), and the receiver compares them with its replicas
+//! as they are displayed at the time of arrival. The error therefore includes the latency
+//! and the playout delay, which is what the viewer actually sees.
+
+use std::time::{Duration, Instant};
+
+use crate::{
+    stats::{TimeSeries, SAMPLE_INTERVAL},
+    Object,
+};
+
+/// The interval for the sender to send the true positions, if enabled.
+pub const TRUTH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The interval for the sender to send the true states of all objects as a debug stream,
+/// if enabled.
+pub const DEBUG_TRUTH_INTERVAL: Duration = Duration::from_millis(200);
+
+/// How long the error of an object counts toward the aggregate after it was measured.
+pub const MAX_AGE: Duration = Duration::from_secs(5);
+
+/// The aggregate position error over the recently measured objects.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DivergenceStats {
+    /// The number of objects measured within [`MAX_AGE`]
+    pub samples: usize,
+    pub mean: f64,
+    pub max: f64,
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+}
+
+/// The latest measured position error of each object.
+#[derive(Default)]
+pub struct Divergence {
+    errors: Vec<Option<(Instant, f64)>>,
+}
+
+impl Divergence {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn resize(&mut self, num_objects: usize) {
+        self.errors.resize(num_objects, None);
+    }
+
+    /// Forgets the object `index` and moves the last one into its place.
+    pub fn swap_remove(&mut self, index: usize) {
+        if index < self.errors.len() {
+            self.errors.swap_remove(index);
+        }
+    }
+
+    /// Compares the true positions with the replicas.
+    pub fn measure(&mut self, samples: &[(usize, Object)], replicas: &[impl AsRef<Object>]) {
+        let now = Instant::now();
+        for (index, truth) in samples {
+            let pos = truth.pos;
+            let (Some(error), Some(replica)) = (self.errors.get_mut(*index), replicas.get(*index))
+            else {
+                continue;
+            };
+            let replica = replica.as_ref().pos;
+            let dist = ((replica[0] - pos[0]).powi(2) + (replica[1] - pos[1]).powi(2)).sqrt();
+            *error = Some((now, dist));
+        }
+    }
+
+    /// The last measured error of an object, if any.
+    pub fn error(&self, index: usize) -> Option<f64> {
+        self.errors.get(index)?.map(|(_, error)| error)
+    }
+
+    /// Aggregates the errors measured within [`MAX_AGE`], or returns `None` if there is none.
+    pub fn stats(&self) -> Option<DivergenceStats> {
+        let now = Instant::now();
+        let mut errors: Vec<f64> = self
+            .errors
+            .iter()
+            .flatten()
+            .filter(|(time, _)| now - *time < MAX_AGE)
+            .map(|(_, error)| *error)
+            .collect();
+        if errors.is_empty() {
+            return None;
+        }
+        errors.sort_by(f64::total_cmp);
+        let percentile = |p: f64| errors[((errors.len() - 1) as f64 * p).round() as usize];
+        Some(DivergenceStats {
+            samples: errors.len(),
+            mean: errors.iter().sum::<f64>() / errors.len() as f64,
+            max: *errors.last().unwrap(),
+            p50: percentile(0.5),
+            p90: percentile(0.9),
+            p99: percentile(0.99),
+        })
+    }
+}
+
+/// Periodically sampled aggregate errors, to be rendered with [`crate::render_divergence`].
+pub struct DivergenceHistory {
+    last_sample: Instant,
+    pub latest: Option<DivergenceStats>,
+    pub mean: TimeSeries,
+    pub max: TimeSeries,
+}
+
+impl Default for DivergenceHistory {
+    fn default() -> Self {
+        Self {
+            last_sample: Instant::now(),
+            latest: None,
+            mean: TimeSeries::default(),
+            max: TimeSeries::default(),
+        }
+    }
+}
+
+impl DivergenceHistory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Updates the latest values and appends to the time series if [`SAMPLE_INTERVAL`]
+    /// has passed since the last sample.
+    pub fn update(&mut self, stats: Option<DivergenceStats>) {
+        self.latest = stats;
+        let now = Instant::now();
+        if now - self.last_sample < SAMPLE_INTERVAL {
+            return;
+        }
+        self.mean.push(stats.map_or(0., |stats| stats.mean));
+        self.max.push(stats.map_or(0., |stats| stats.max));
+        self.last_sample = now;
+    }
+}
@@ -0,0 +1,126 @@
+//! Stable identities of objects, independent of their positions in the vectors.
+//!
+//! Both ends keep the objects in dense vectors for the simulation and rendering, but the wire
+//! protocol refers to them by [`EntityId`]s, which the sender assigns and never reuses.
+//! Each end maps the ids to its own slots with an [`EntityMap`]. Removing an entity moves the
+//! last one into its slot like [`Vec::swap_remove`], so every vector indexed by slots has to be
+//! updated with the same operation.
+//!
+//! Objects are added and removed with [`LifecycleEvent`]s, which the sender delivers over
+//! a reliable channel, see [`crate::reliable`].
+
+use std::collections::HashMap;
+
+use crate::{
+    protocol::ProtocolError,
+    wire::{WireReader, WireWriter},
+    Object,
+};
+
+pub type EntityId = u32;
+
+/// A change of the set of objects.
+#[derive(Clone, Copy, Debug)]
+pub enum LifecycleEvent {
+    /// A new object with its initial state, including the color.
+    Spawn {
+        id: EntityId,
+        obj: Object,
+    },
+    Despawn {
+        id: EntityId,
+    },
+}
+
+impl LifecycleEvent {
+    const SPAWN: u8 = 0;
+    const DESPAWN: u8 = 1;
+
+    pub fn id(&self) -> EntityId {
+        match self {
+            Self::Spawn { id, .. } | Self::Despawn { id } => *id,
+        }
+    }
+
+    pub(crate) fn write(&self, writer: &mut WireWriter) {
+        match self {
+            Self::Spawn { id, obj } => {
+                writer.u8(Self::SPAWN);
+                writer.u32(*id);
+                writer.object(obj);
+            }
+            Self::Despawn { id } => {
+                writer.u8(Self::DESPAWN);
+                writer.u32(*id);
+            }
+        }
+    }
+
+    pub(crate) fn read(reader: &mut WireReader) -> Result<Self, ProtocolError> {
+        match reader.u8()? {
+            Self::SPAWN => Ok(Self::Spawn {
+                id: reader.u32()?,
+                obj: reader.object()?,
+            }),
+            Self::DESPAWN => Ok(Self::Despawn { id: reader.u32()? }),
+            _ => Err(ProtocolError::Malformed("unknown lifecycle event")),
+        }
+    }
+}
+
+/// A bidirectional map between entity ids and slots.
+#[derive(Clone, Debug, Default)]
+pub struct EntityMap {
+    ids: Vec<EntityId>,
+    slots: HashMap<EntityId, usize>,
+}
+
+impl EntityMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    /// The ids of the entities in the order of their slots.
+    pub fn ids(&self) -> &[EntityId] {
+        &self.ids
+    }
+
+    pub fn id(&self, slot: usize) -> Option<EntityId> {
+        self.ids.get(slot).copied()
+    }
+
+    pub fn slot(&self, id: EntityId) -> Option<usize> {
+        self.slots.get(&id).copied()
+    }
+
+    pub fn contains(&self, id: EntityId) -> bool {
+        self.slots.contains_key(&id)
+    }
+
+    /// Adds an entity in a new slot at the end and returns it, or returns the slot it already has.
+    pub fn insert(&mut self, id: EntityId) -> usize {
+        *self.slots.entry(id).or_insert_with(|| {
+            self.ids.push(id);
+            self.ids.len() - 1
+        })
+    }
+
+    /// Removes an entity and moves the last one into its slot, like [`Vec::swap_remove`].
+    /// Returns the slot it had, or `None` if it did not exist.
+    pub fn remove(&mut self, id: EntityId) -> Option<usize> {
+        let slot = self.slots.remove(&id)?;
+        self.ids.swap_remove(slot);
+        if let Some(moved) = self.ids.get(slot) {
+            self.slots.insert(*moved, slot);
+        }
+        Some(slot)
+    }
+}
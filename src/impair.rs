@@ -0,0 +1,434 @@
+//! A network impairment simulator to demonstrate the effects of an unreliable network
+//! even if the sender and the receiver are running on the same machine.
+//!
+//! [`ImpairedTransport`] wraps a [`Transport`] and drops, duplicates, delays, reorders and
+//! corrupts datagrams in both directions according to an [`ImpairmentConfig`].
+
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    io,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::transport::Transport;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, clap::Args)]
+pub struct ImpairmentConfig {
+    #[clap(
+        long = "impair-loss",
+        default_value = "0",
+        value_parser = parse_probability,
+        help = "The probability to drop a datagram"
+    )]
+    pub loss: f64,
+    #[clap(
+        long = "impair-duplicate",
+        default_value = "0",
+        value_parser = parse_probability,
+        help = "The probability to deliver a datagram twice"
+    )]
+    pub duplicate: f64,
+    #[clap(
+        long = "impair-reorder",
+        default_value = "0",
+        value_parser = parse_probability,
+        help = "The probability to hold back a datagram so that it arrives after later ones"
+    )]
+    pub reorder: f64,
+    #[clap(
+        long = "impair-corrupt",
+        default_value = "0",
+        value_parser = parse_probability,
+        help = "The probability to flip a random bit in a datagram"
+    )]
+    pub corrupt: f64,
+    #[clap(
+        long = "impair-latency",
+        default_value = "0",
+        value_parser = parse_delay,
+        help = "The mean latency added to datagrams, in milliseconds"
+    )]
+    pub latency: f64,
+    #[clap(
+        long = "impair-jitter",
+        default_value = "0",
+        value_parser = parse_delay,
+        help = "The maximum deviation from the latency, in milliseconds. The latency is uniformly distributed in [latency - jitter, latency + jitter]"
+    )]
+    pub jitter: f64,
+    #[clap(
+        id = "impair_seed",
+        long = "impair-seed",
+        help = "The seed of the random number generator for impairments, to reproduce the same sequence of events"
+    )]
+    pub seed: Option<u64>,
+}
+
+/// The upper bound of the latency and the jitter in milliseconds, which keeps the delays within
+/// what [`Duration`] and the random number generator can handle.
+pub const MAX_DELAY: f64 = 60_000.;
+
+fn parse_probability(s: &str) -> Result<f64, String> {
+    let value: f64 = s.parse().map_err(|e| format!("{e}"))?;
+    if (0. ..=1.).contains(&value) {
+        Ok(value)
+    } else {
+        Err("expected a probability in [0, 1]".into())
+    }
+}
+
+fn parse_delay(s: &str) -> Result<f64, String> {
+    let value: f64 = s.parse().map_err(|e| format!("{e}"))?;
+    if (0. ..=MAX_DELAY).contains(&value) {
+        Ok(value)
+    } else {
+        Err(format!("expected milliseconds in [0, {MAX_DELAY}]"))
+    }
+}
+
+/// The counters of the events that happened to datagrams.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ImpairmentStats {
+    pub passed: usize,
+    pub dropped: usize,
+    pub duplicated: usize,
+    pub reordered: usize,
+    pub corrupted: usize,
+}
+
+struct Delayed {
+    deliver_at: Instant,
+    /// The order of arrival to break ties of `deliver_at`, so that the order is deterministic.
+    order: u64,
+    buf: Vec<u8>,
+    addr: SocketAddr,
+}
+
+impl PartialEq for Delayed {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for Delayed {}
+
+impl PartialOrd for Delayed {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Delayed {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.deliver_at, self.order).cmp(&(other.deliver_at, other.order))
+    }
+}
+
+/// A queue of datagrams that applies impairments on the way through.
+/// It does not do any I/O by itself, so it can be driven by a synthetic clock.
+pub struct Impairment {
+    config: ImpairmentConfig,
+    rng: StdRng,
+    queue: BinaryHeap<Reverse<Delayed>>,
+    order: u64,
+    stats: ImpairmentStats,
+}
+
+impl Impairment {
+    /// The additional delay of a reordered datagram, in milliseconds.
+    const REORDER_DELAY: f64 = 50.;
+
+    pub fn new(config: ImpairmentConfig) -> Self {
+        let rng = match config.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        Self {
+            config,
+            rng,
+            queue: BinaryHeap::new(),
+            order: 0,
+            stats: ImpairmentStats::default(),
+        }
+    }
+
+    pub fn config(&self) -> &ImpairmentConfig {
+        &self.config
+    }
+
+    /// Changes the config without resetting the random number generator.
+    pub fn set_config(&mut self, config: ImpairmentConfig) {
+        self.config = config;
+    }
+
+    pub fn stats(&self) -> ImpairmentStats {
+        self.stats
+    }
+
+    /// Schedules zero or more copies of a datagram to be delivered.
+    pub fn push(&mut self, buf: &[u8], addr: SocketAddr, now: Instant) {
+        let config = self.config;
+        if self.rng.gen::<f64>() < config.loss {
+            self.stats.dropped += 1;
+            return;
+        }
+        let copies = if self.rng.gen::<f64>() < config.duplicate {
+            self.stats.duplicated += 1;
+            2
+        } else {
+            1
+        };
+        self.stats.passed += 1;
+        for _ in 0..copies {
+            let mut buf = buf.to_vec();
+            if !buf.is_empty() && self.rng.gen::<f64>() < config.corrupt {
+                let byte = self.rng.gen_range(0..buf.len());
+                buf[byte] ^= 1 << self.rng.gen_range(0..8);
+                self.stats.corrupted += 1;
+            }
+            let mut delay = config.latency;
+            if 0. < config.jitter {
+                delay += self.rng.gen_range(-config.jitter..=config.jitter);
+            }
+            if self.rng.gen::<f64>() < config.reorder {
+                delay += Self::REORDER_DELAY + config.jitter;
+                self.stats.reordered += 1;
+            }
+            let deliver_at = now + Duration::from_secs_f64(delay.max(0.) * 1e-3);
+            self.queue.push(Reverse(Delayed {
+                deliver_at,
+                order: self.order,
+                buf,
+                addr,
+            }));
+            self.order += 1;
+        }
+    }
+
+    /// Pops a datagram that is due by `now`.
+    pub fn pop_due(&mut self, now: Instant) -> Option<(Vec<u8>, SocketAddr)> {
+        if now < self.next_due()? {
+            return None;
+        }
+        let Reverse(delayed) = self.queue.pop()?;
+        Some((delayed.buf, delayed.addr))
+    }
+
+    /// The time the next datagram is due, if any.
+    pub fn next_due(&self) -> Option<Instant> {
+        self.queue.peek().map(|Reverse(delayed)| delayed.deliver_at)
+    }
+}
+
+/// A [`Transport`] with impairments applied to both outgoing and incoming datagrams.
+pub struct ImpairedTransport<T> {
+    inner: T,
+    outbound: Impairment,
+    inbound: Impairment,
+    buf: Vec<u8>,
+}
+
+impl<T: Transport> ImpairedTransport<T> {
+    pub fn new(inner: T, config: ImpairmentConfig) -> Self {
+        // Derive different seeds for each direction so that they are not correlated
+        let inbound_config = ImpairmentConfig {
+            seed: config.seed.map(|seed| seed.wrapping_add(1)),
+            ..config
+        };
+        Self {
+            inner,
+            outbound: Impairment::new(config),
+            inbound: Impairment::new(inbound_config),
+            buf: vec![0u8; crate::protocol::MAX_DATAGRAM_SIZE],
+        }
+    }
+
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn set_config(&mut self, config: ImpairmentConfig) {
+        self.outbound.set_config(config);
+        self.inbound.set_config(config);
+    }
+
+    /// Returns the counters of `(outbound, inbound)` impairments.
+    pub fn stats(&self) -> (ImpairmentStats, ImpairmentStats) {
+        (self.outbound.stats(), self.inbound.stats())
+    }
+
+    /// Sends out the outgoing datagrams that are due.
+    fn flush(&mut self) -> io::Result<()> {
+        let now = Instant::now();
+        while let Some((buf, addr)) = self.outbound.pop_due(now) {
+            self.inner.send_to(&buf, addr)?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: Transport> Transport for ImpairedTransport<T> {
+    /// Queues a datagram to be sent. Returns the length of `buf` even if it is dropped,
+    /// because a real network would not tell the sender either.
+    fn send_to(&mut self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        self.outbound.push(buf, addr, Instant::now());
+        self.flush()?;
+        Ok(buf.len())
+    }
+
+    fn recv_from(
+        &mut self,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> io::Result<Option<(usize, SocketAddr)>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            self.flush()?;
+            let now = Instant::now();
+            if let Some((data, addr)) = self.inbound.pop_due(now) {
+                let len = data.len().min(buf.len());
+                buf[..len].copy_from_slice(&data[..len]);
+                return Ok(Some((len, addr)));
+            }
+
+            // Do not block past the time the next delayed datagram is due
+            let mut wait = deadline.saturating_duration_since(now);
+            if let Some(due) = self.inbound.next_due() {
+                wait = wait.min(due.saturating_duration_since(now));
+            }
+
+            match self.inner.recv_from(&mut self.buf, wait)? {
+                Some((len, addr)) => self.inbound.push(&self.buf[..len], addr, Instant::now()),
+                None => {
+                    let now = Instant::now();
+                    let due = self.inbound.next_due().is_some_and(|due| due <= now);
+                    if deadline <= now && !due {
+                        return Ok(None);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr() -> SocketAddr {
+        "127.0.0.1:34254".parse().unwrap()
+    }
+
+    /// Pushes numbered datagrams 1 ms apart and returns the numbers and the delivery times in
+    /// milliseconds in the order of delivery.
+    fn trace(config: ImpairmentConfig, count: u32) -> (Vec<(u32, u64)>, ImpairmentStats) {
+        let mut impairment = Impairment::new(config);
+        let start = Instant::now();
+        for i in 0..count {
+            impairment.push(
+                &i.to_le_bytes(),
+                addr(),
+                start + Duration::from_millis(i as u64),
+            );
+        }
+        let mut delivered = vec![];
+        while let Some(due) = impairment.next_due() {
+            let (buf, _) = impairment.pop_due(due).unwrap();
+            let time = (due - start).as_millis() as u64;
+            delivered.push((u32::from_le_bytes(buf[..4].try_into().unwrap()), time));
+        }
+        (delivered, impairment.stats())
+    }
+
+    fn lossy(seed: u64) -> ImpairmentConfig {
+        ImpairmentConfig {
+            loss: 0.2,
+            duplicate: 0.1,
+            reorder: 0.1,
+            corrupt: 0.,
+            latency: 30.,
+            jitter: 10.,
+            seed: Some(seed),
+        }
+    }
+
+    #[test]
+    fn seeded_runs_are_reproducible() {
+        let (trace1, stats1) = trace(lossy(7), 1000);
+        let (trace2, stats2) = trace(lossy(7), 1000);
+        assert_eq!(trace1, trace2);
+        assert_eq!(stats1.passed, stats2.passed);
+        assert_eq!(stats1.dropped, stats2.dropped);
+        assert_eq!(stats1.duplicated, stats2.duplicated);
+        assert_eq!(stats1.reordered, stats2.reordered);
+
+        let (trace3, _) = trace(lossy(8), 1000);
+        assert_ne!(trace1, trace3);
+    }
+
+    #[test]
+    fn probabilities_are_applied() {
+        let (delivered, stats) = trace(lossy(1), 10000);
+        assert_eq!(stats.passed + stats.dropped, 10000);
+        assert_eq!(delivered.len(), stats.passed + stats.duplicated);
+        assert!((1800..2200).contains(&stats.dropped), "{stats:?}");
+        assert!((600..1000).contains(&stats.duplicated), "{stats:?}");
+        // Some datagrams overtake others because of the jitter and the reordering
+        assert!(delivered.windows(2).any(|w| w[1].0 < w[0].0));
+    }
+
+    #[test]
+    fn latency_without_jitter_keeps_the_order() {
+        let config = ImpairmentConfig {
+            latency: 25.,
+            seed: Some(0),
+            ..ImpairmentConfig::default()
+        };
+        let (delivered, stats) = trace(config, 100);
+        assert_eq!(stats.passed, 100);
+        let expected: Vec<_> = (0..100).map(|i| (i, i as u64 + 25)).collect();
+        assert_eq!(delivered, expected);
+    }
+
+    #[test]
+    fn nothing_is_due_before_its_time() {
+        let mut impairment = Impairment::new(ImpairmentConfig {
+            latency: 10.,
+            seed: Some(0),
+            ..ImpairmentConfig::default()
+        });
+        let now = Instant::now();
+        impairment.push(b"x", addr(), now);
+        assert!(impairment.pop_due(now + Duration::from_millis(9)).is_none());
+        assert!(impairment
+            .pop_due(now + Duration::from_millis(10))
+            .is_some());
+        assert!(impairment.next_due().is_none());
+    }
+
+    #[test]
+    fn corruption_flips_one_bit() {
+        let mut impairment = Impairment::new(ImpairmentConfig {
+            corrupt: 1.,
+            seed: Some(3),
+            ..ImpairmentConfig::default()
+        });
+        let now = Instant::now();
+        let original = [0x55u8; 32];
+        for _ in 0..100 {
+            impairment.push(&original, addr(), now);
+            let (buf, _) = impairment.pop_due(now).unwrap();
+            let flipped: u32 = buf
+                .iter()
+                .zip(original)
+                .map(|(a, b)| (a ^ b).count_ones())
+                .sum();
+            assert_eq!(flipped, 1);
+        }
+        assert_eq!(impairment.stats().corrupted, 100);
+    }
+}
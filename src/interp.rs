@@ -0,0 +1,118 @@
+//! Interpolation of received states with a playout delay.
+//!
+//! Instead of extrapolating from the latest state, the receiver can keep a short history
+//! of the states of each object stamped with the sender's clock, and render the objects
+//! as they were a fixed delay ago. As long as the delay is longer than the interval between
+//! updates of an object, there are states on both sides of the rendered time to interpolate
+//! between, so the objects move smoothly at the cost of latency.
+
+use std::{collections::VecDeque, time::Instant};
+
+use crate::Object;
+
+/// The span of the history kept for each object, in milliseconds of the sender's clock.
+const HISTORY_SPAN: f64 = 5000.;
+
+/// How the receiver advances the replicas between updates.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PlayoutMode {
+    /// Snap to each received state and run the boid model locally in between.
+    #[default]
+    Extrapolate,
+    /// Interpolate between received states with a playout delay.
+    Interpolate,
+}
+
+/// The time-stamped histories of received states of each object.
+pub struct InterpolationBuffer {
+    start: Instant,
+    /// The smallest observed difference between the local clock and the sender's timestamps,
+    /// in milliseconds. Adding it maps the sender's clock to the earliest local arrival time.
+    offset: Option<f64>,
+    histories: Vec<VecDeque<(f64, Object)>>,
+}
+
+impl Default for InterpolationBuffer {
+    fn default() -> Self {
+        Self {
+            start: Instant::now(),
+            offset: None,
+            histories: vec![],
+        }
+    }
+}
+
+impl InterpolationBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn local_time(&self) -> f64 {
+        self.start.elapsed().as_secs_f64() * 1e3
+    }
+
+    pub fn resize(&mut self, num_objects: usize) {
+        self.histories.resize(num_objects, VecDeque::new());
+    }
+
+    /// Forgets the object `index` and moves the last one into its place.
+    pub fn swap_remove(&mut self, index: usize) {
+        if index < self.histories.len() {
+            self.histories.swap_remove(index);
+        }
+    }
+
+    /// Records the arrival of a datagram stamped with `timestamp` to synchronize the clocks.
+    pub fn on_packet(&mut self, timestamp: u32) {
+        let offset = self.local_time() - timestamp as f64;
+        self.offset = Some(self.offset.map_or(offset, |prev| prev.min(offset)));
+    }
+
+    /// Records the state of an object as of `timestamp` in the sender's clock.
+    pub fn push(&mut self, index: usize, timestamp: u32, obj: Object) {
+        let Some(history) = self.histories.get_mut(index) else {
+            return;
+        };
+        let time = timestamp as f64;
+        // States can arrive out of order, so keep them sorted by time
+        let pos = history
+            .iter()
+            .rposition(|(t, _)| *t <= time)
+            .map_or(0, |pos| pos + 1);
+        if 0 < pos && history[pos - 1].0 == time {
+            return;
+        }
+        history.insert(pos, (time, obj));
+        let newest = history.back().unwrap().0;
+        // Keep at least 2 states to interpolate between, even if they are old
+        while 2 < history.len() && history[0].0 < newest - HISTORY_SPAN {
+            history.pop_front();
+        }
+    }
+
+    /// The time in the sender's clock to render at with a playout delay of `delay` milliseconds,
+    /// or `None` until something is received.
+    pub fn playout_time(&self, delay: f64) -> Option<f64> {
+        Some(self.local_time() - self.offset? - delay)
+    }
+
+    /// Returns the state of an object at `time` in the sender's clock, and whether it is
+    /// interpolated between two states. If `time` is outside of the history, the closest
+    /// state is returned as is.
+    pub fn sample(&self, index: usize, time: f64) -> Option<(Object, bool)> {
+        let history = self.histories.get(index)?;
+        let next = history.iter().position(|(t, _)| time < *t);
+        let (t1, obj1) = match next {
+            Some(0) => return Some((history[0].1, false)),
+            Some(next) => history[next],
+            None => return history.back().map(|(_, obj)| (*obj, false)),
+        };
+        let (t0, obj0) = history[next.unwrap() - 1];
+        let f = (time - t0) / (t1 - t0);
+        let lerp = |a: [f64; 2], b: [f64; 2]| [a[0] + (b[0] - a[0]) * f, a[1] + (b[1] - a[1]) * f];
+        let mut obj = obj1;
+        obj.pos = lerp(obj0.pos, obj1.pos);
+        obj.velo = lerp(obj0.velo, obj1.velo);
+        Some((obj, true))
+    }
+}
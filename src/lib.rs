@@ -1,16 +1,40 @@
+pub mod ack;
+pub mod addr;
+pub mod codec;
+pub mod config;
+pub mod delta;
+pub mod divergence;
+pub mod entity;
+pub mod impair;
+pub mod interp;
+pub mod lockstep;
 pub mod object;
 mod object_wrap;
+pub mod protocol;
+pub mod reckoning;
+pub mod reliable;
 mod render;
+pub mod rng;
+pub mod schedule;
 mod sort_map;
+pub mod stats;
+pub mod sync;
+pub mod transport;
+pub mod wire;
 
 pub use crate::{
     object::Object,
     object_wrap::ObjectWrap,
-    render::render_objects,
+    render::{
+        render_config_file, render_divergence, render_ghosts, render_impairment, render_objects,
+        render_stats, ConfigFileAction,
+    },
     sort_map::{HashEntry, SortMap, UpdateScanner},
 };
 
 pub const DELTA_TIME: f64 = 1. / 20.;
+/// The interval of the simulation steps in milliseconds of the sender's clock.
+pub const TICK_INTERVAL: u32 = 10;
 pub const SPACE_WIDTH: f64 = 10.;
 pub const NUM_OBJS: usize = 1000;
 pub const SCALE: f32 = 50.;
@@ -0,0 +1,600 @@
+//! Deterministic lockstep synchronization, as an alternative to state replication.
+//!
+//! Both peers start from the same initial state generated from a seed and advance the same
+//! deterministic simulation one tick at a time. Only the inputs of each tick travel over the
+//! network, so the bandwidth does not depend on the number of objects.
+//!
+//! The simulation draws its random numbers from a [`Pcg32`] seeded with the shared seed, and
+//! its floating point operations are performed in a fixed order with only basic arithmetic
+//! and `sqrt`, which IEEE 754 defines exactly, so the results are bit-identical on any platform.
+//! The sender periodically sends [`checksum`]s of its state so that the receiver can verify it.
+//!
+//! The sender always sends each receiver the inputs starting from the next tick it has
+//! acknowledged, so lost datagrams are covered by the following ones. It keeps the inputs from
+//! the earliest tick any receiver still needs, which is the first tick until a receiver has
+//! acknowledged some, so a receiver started late can catch up if it is the first one. A receiver
+//! that needs inputs the sender has already dropped cannot follow and has to be restarted along
+//! with the sender.
+//!
+//! The [`BoidParams`] are inputs too, which are only carried by the ticks at which they change,
+//! so that they take effect at the same tick on both peers.
+
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    net::SocketAddr,
+};
+
+use crate::{
+    object::{BoidParams, BoidScanner},
+    protocol::{Message, ProtocolError},
+    rng::Pcg32,
+    wire::{WireReader, WireWriter},
+    Object, SortMap, SPACE_WIDTH,
+};
+
+/// The interval of ticks between checksums.
+pub const CHECKSUM_INTERVAL: u32 = 50;
+/// The maximum number of inputs in a [`Message::LockstepInputs`].
+pub const MAX_INPUTS: usize = 128;
+/// The number of local checksums the receiver keeps to compare with the sender's.
+const CHECKSUM_HISTORY: usize = 64;
+
+/// The inputs to the simulation for a tick.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TickInput {
+    /// The parameters from this tick on, if they have changed
+    pub params: Option<BoidParams>,
+}
+
+impl TickInput {
+    pub fn wire_size(&self) -> usize {
+        1 + self.params.map_or(0, |_| BoidParams::WIRE_SIZE)
+    }
+
+    pub(crate) fn write(&self, writer: &mut WireWriter) {
+        match &self.params {
+            Some(params) => {
+                writer.u8(1);
+                params.write(writer);
+            }
+            None => writer.u8(0),
+        }
+    }
+
+    pub(crate) fn read(reader: &mut WireReader) -> Result<Self, ProtocolError> {
+        let params = match reader.u8()? {
+            0 => None,
+            1 => Some(BoidParams::read(reader)?),
+            _ => return Err(ProtocolError::Malformed("unknown tick input flag")),
+        };
+        Ok(Self { params })
+    }
+}
+
+/// Returns the FNV-1a hash of the bit patterns of the objects' states.
+pub fn checksum(objs: &[impl AsRef<Object>]) -> u64 {
+    let mut hash = 0xcbf29ce484222325u64;
+    for obj in objs {
+        let obj = obj.as_ref();
+        let fields = obj.pos.iter().chain(obj.velo.iter());
+        for byte in fields
+            .flat_map(|v| v.to_bits().to_le_bytes())
+            .chain(obj.color)
+        {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    hash
+}
+
+/// The deterministic simulation shared by both peers.
+pub struct Simulation {
+    seed: u64,
+    tick: u32,
+    objs: Vec<Object>,
+    rng: Pcg32,
+    sort_map: SortMap,
+    params: BoidParams,
+}
+
+impl Simulation {
+    /// Generates the initial state from `seed`.
+    pub fn new(seed: u64, num_objects: usize) -> Self {
+        let mut rng = Pcg32::new(seed);
+        let objs = (0..num_objects)
+            .map(|_| {
+                let pos = [rng.next_f64() * SPACE_WIDTH, rng.next_f64() * SPACE_WIDTH];
+                let color = rng.next_f64().to_bits().to_le_bytes();
+                Object::new(pos, [color[0], color[1], color[2]])
+            })
+            .collect();
+        Self {
+            seed,
+            tick: 0,
+            objs,
+            rng,
+            sort_map: SortMap::new(num_objects),
+            params: BoidParams::default(),
+        }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// The number of steps taken so far.
+    pub fn tick(&self) -> u32 {
+        self.tick
+    }
+
+    pub fn objs(&self) -> &[Object] {
+        &self.objs
+    }
+
+    /// The parameters the last step was taken with, which are the default ones until an input
+    /// changes them.
+    pub fn params(&self) -> &BoidParams {
+        &self.params
+    }
+
+    pub fn step(&mut self, input: &TickInput) {
+        if let Some(params) = input.params {
+            self.params = params;
+        }
+        let mut scanner = BoidScanner::new(Some(&mut self.rng), &self.params);
+        self.sort_map.update(&self.objs);
+        self.sort_map.scan(&mut self.objs, &mut scanner);
+        self.tick += 1;
+    }
+}
+
+/// The sender side of the lockstep mode, which owns the authoritative simulation.
+pub struct LockstepSender {
+    sim: Simulation,
+    /// The inputs from [`Self::first_tick`] on
+    inputs: VecDeque<TickInput>,
+    /// The tick of the first input kept
+    first_tick: u32,
+    /// The next tick each receiver needs the input of
+    next_needed: HashMap<SocketAddr, u32>,
+}
+
+impl LockstepSender {
+    pub fn new(seed: u64, num_objects: usize) -> Self {
+        Self {
+            sim: Simulation::new(seed, num_objects),
+            inputs: VecDeque::new(),
+            first_tick: 0,
+            next_needed: HashMap::new(),
+        }
+    }
+
+    pub fn sim(&self) -> &Simulation {
+        &self.sim
+    }
+
+    /// The next tick the receiver at `addr` needs the input of.
+    pub fn next_needed(&self, addr: SocketAddr) -> u32 {
+        self.next_needed.get(&addr).copied().unwrap_or(0)
+    }
+
+    /// Forgets the progress of the receiver at `addr`, which has restarted or left.
+    pub fn forget(&mut self, addr: SocketAddr) {
+        self.next_needed.remove(&addr);
+        self.drop_acknowledged();
+    }
+
+    /// The number of inputs kept for the receivers.
+    pub fn buffered(&self) -> usize {
+        self.inputs.len()
+    }
+
+    /// Advances the simulation with `params`, and returns a checksum message if it is due.
+    pub fn step(&mut self, params: &BoidParams) -> Option<Message> {
+        let input = TickInput {
+            params: (params != self.sim.params()).then_some(*params),
+        };
+        self.sim.step(&input);
+        self.inputs.push_back(input);
+        let tick = self.sim.tick();
+        tick.is_multiple_of(CHECKSUM_INTERVAL)
+            .then(|| Message::Checksum {
+                tick,
+                hash: checksum(self.sim.objs()),
+            })
+    }
+
+    pub fn on_ack(&mut self, addr: SocketAddr, next_tick: u32) {
+        let next_needed = self.next_needed.entry(addr).or_default();
+        // Acknowledgements can arrive out of order
+        if *next_needed < next_tick && next_tick <= self.sim.tick() {
+            *next_needed = next_tick;
+        }
+        self.drop_acknowledged();
+    }
+
+    /// Drops the inputs every receiver has acknowledged.
+    fn drop_acknowledged(&mut self) {
+        let Some(&min_needed) = self.next_needed.values().min() else {
+            return;
+        };
+        let acknowledged = min_needed.saturating_sub(self.first_tick) as usize;
+        self.inputs.drain(..acknowledged.min(self.inputs.len()));
+        self.first_tick = self.first_tick.max(min_needed);
+    }
+
+    /// Returns the inputs the receiver at `addr` has not acknowledged yet, up to [`MAX_INPUTS`]
+    /// and as many as fit in `mtu`, but at least one. If some of them have been dropped, the
+    /// inputs start from the first one kept.
+    pub fn inputs_message(&self, addr: SocketAddr, mtu: usize) -> Message {
+        let first_tick = self.next_needed(addr).max(self.first_tick);
+        let mut len = Message::INPUTS_OVERHEAD;
+        let inputs = self
+            .inputs
+            .range((first_tick - self.first_tick) as usize..)
+            .take(MAX_INPUTS)
+            .enumerate()
+            .take_while(|(i, input)| {
+                len += input.wire_size();
+                *i == 0 || len <= mtu
+            })
+            .map(|(_, input)| *input)
+            .collect();
+        Message::LockstepInputs {
+            seed: self.sim.seed(),
+            num_objects: self.sim.objs().len(),
+            first_tick,
+            inputs,
+        }
+    }
+}
+
+/// The progress of the lockstep mode on the receiver.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LockstepStatus {
+    pub tick: u32,
+    /// The tick up to which the inputs have been received
+    pub received_tick: u32,
+    /// The number of checksums that matched the sender's
+    pub verified: usize,
+    /// The first tick at which the checksum did not match, if any
+    pub mismatch: Option<u32>,
+}
+
+/// The receiver side of the lockstep mode, which follows the sender's simulation.
+#[derive(Default)]
+pub struct LockstepReceiver {
+    sim: Option<Simulation>,
+    inputs: BTreeMap<u32, TickInput>,
+    /// The local checksums of the recent ticks
+    hashes: VecDeque<(u32, u64)>,
+    /// The sender's checksums of ticks that have not been compared yet
+    remote_hashes: BTreeMap<u32, u64>,
+    verified: usize,
+    mismatch: Option<u32>,
+}
+
+impl LockstepReceiver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn sim(&self) -> Option<&Simulation> {
+        self.sim.as_ref()
+    }
+
+    pub fn status(&self) -> Option<LockstepStatus> {
+        let sim = self.sim.as_ref()?;
+        Some(LockstepStatus {
+            tick: sim.tick(),
+            received_tick: self
+                .inputs
+                .last_key_value()
+                .map_or(sim.tick(), |(tick, _)| tick + 1),
+            verified: self.verified,
+            mismatch: self.mismatch,
+        })
+    }
+
+    pub fn on_inputs(
+        &mut self,
+        seed: u64,
+        num_objects: usize,
+        first_tick: u32,
+        inputs: &[TickInput],
+    ) {
+        let restarted = self
+            .sim
+            .as_ref()
+            .is_none_or(|sim| sim.seed() != seed || sim.objs().len() != num_objects);
+        if restarted {
+            *self = Self::default();
+            self.sim = Some(Simulation::new(seed, num_objects));
+        }
+        let tick = self.sim.as_ref().map_or(0, |sim| sim.tick());
+        for (i, input) in inputs.iter().enumerate() {
+            let input_tick = first_tick + i as u32;
+            if tick <= input_tick {
+                self.inputs.insert(input_tick, *input);
+            }
+        }
+    }
+
+    pub fn on_checksum(&mut self, tick: u32, hash: u64) {
+        self.remote_hashes.insert(tick, hash);
+        if CHECKSUM_HISTORY < self.remote_hashes.len() {
+            self.remote_hashes.pop_first();
+        }
+        self.compare();
+    }
+
+    /// Advances the simulation by up to `max_steps` ticks as far as the inputs are available,
+    /// and returns the number of steps taken.
+    pub fn advance(&mut self, max_steps: usize) -> usize {
+        let Some(sim) = &mut self.sim else {
+            return 0;
+        };
+        let mut steps = 0;
+        while steps < max_steps {
+            let Some(input) = self.inputs.remove(&sim.tick()) else {
+                break;
+            };
+            sim.step(&input);
+            steps += 1;
+            if sim.tick().is_multiple_of(CHECKSUM_INTERVAL) {
+                self.hashes.push_back((sim.tick(), checksum(sim.objs())));
+                if CHECKSUM_HISTORY < self.hashes.len() {
+                    self.hashes.pop_front();
+                }
+            }
+        }
+        self.compare();
+        steps
+    }
+
+    /// Returns the acknowledgement of the inputs received so far.
+    pub fn ack(&self) -> Option<Message> {
+        Some(Message::LockstepAck {
+            next_tick: self.sim.as_ref()?.tick(),
+        })
+    }
+
+    fn compare(&mut self) {
+        let Some(sim) = &self.sim else {
+            return;
+        };
+        let oldest = self.hashes.front().map_or(sim.tick(), |(tick, _)| *tick);
+        while let Some(entry) = self.remote_hashes.first_entry() {
+            let tick = *entry.key();
+            if sim.tick() < tick {
+                break;
+            }
+            let remote = entry.remove();
+            if tick < oldest {
+                continue;
+            }
+            match self.hashes.iter().find(|(t, _)| *t == tick) {
+                Some((_, local)) if *local == remote => self.verified += 1,
+                Some(_) => {
+                    self.mismatch.get_or_insert(tick);
+                }
+                None => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr() -> SocketAddr {
+        "127.0.0.1:34254".parse().unwrap()
+    }
+
+    /// The parameters of each tick, which change now and then.
+    fn params_at(tick: u32) -> BoidParams {
+        BoidParams {
+            randomness: 0.01 * (tick / 40 % 3) as f64,
+            cohesion: if tick < 100 { 1e-4 } else { 1e-3 },
+            ..BoidParams::default()
+        }
+    }
+
+    /// Runs a simulation with [`params_at`] and returns the checksum of the final state.
+    fn replay(seed: u64, num_objects: usize, ticks: u32) -> u64 {
+        let mut sim = Simulation::new(seed, num_objects);
+        for tick in 0..ticks {
+            let params = params_at(tick);
+            sim.step(&TickInput {
+                params: (params != *sim.params()).then_some(params),
+            });
+        }
+        checksum(sim.objs())
+    }
+
+    /// Delivers the inputs of a message and the checksum, if any, to `receiver`.
+    fn deliver(receiver: &mut LockstepReceiver, inputs: Message, checksum: Option<Message>) {
+        let Message::LockstepInputs {
+            seed,
+            num_objects,
+            first_tick,
+            inputs,
+        } = inputs
+        else {
+            panic!("not inputs");
+        };
+        receiver.on_inputs(seed, num_objects, first_tick, &inputs);
+        receiver.advance(usize::MAX);
+        if let Some(Message::Checksum { tick, hash }) = checksum {
+            receiver.on_checksum(tick, hash);
+        }
+    }
+
+    #[test]
+    fn simulation_is_deterministic() {
+        assert_eq!(replay(1, 200, 200), replay(1, 200, 200));
+        assert_ne!(replay(1, 200, 200), replay(2, 200, 200));
+    }
+
+    #[test]
+    fn params_change_the_outcome() {
+        let mut sim1 = Simulation::new(3, 100);
+        let mut sim2 = Simulation::new(3, 100);
+        let input = TickInput {
+            params: Some(BoidParams {
+                separation: 0.1,
+                ..BoidParams::default()
+            }),
+        };
+        for _ in 0..20 {
+            sim1.step(&TickInput::default());
+            sim2.step(&input);
+        }
+        assert_ne!(checksum(sim1.objs()), checksum(sim2.objs()));
+    }
+
+    #[test]
+    fn checksum_covers_every_bit() {
+        let objs = Simulation::new(4, 3).objs().to_vec();
+        let hash = checksum(&objs);
+        let mut changed = objs.clone();
+        changed[2].velo[1] = f64::from_bits(changed[2].velo[1].to_bits() ^ 1);
+        assert_ne!(checksum(&changed), hash);
+        let mut changed = objs.clone();
+        changed[0].color[0] ^= 0x80;
+        assert_ne!(checksum(&changed), hash);
+        // The order of the objects matters
+        let mut swapped = objs.clone();
+        swapped.swap(0, 1);
+        assert_ne!(checksum(&swapped), hash);
+    }
+
+    #[test]
+    fn receiver_follows_and_verifies_the_sender() {
+        let mut sender = LockstepSender::new(5, 100);
+        let mut receiver = LockstepReceiver::new();
+        for tick in 0..300 {
+            let checksum = sender.step(&params_at(tick));
+            // Lose every third message, which the following ones cover
+            if tick % 3 != 0 {
+                deliver(&mut receiver, sender.inputs_message(addr(), 1200), checksum);
+            }
+            if let Some(Message::LockstepAck { next_tick }) = receiver.ack() {
+                sender.on_ack(addr(), next_tick);
+            }
+        }
+        let status = receiver.status().unwrap();
+        assert_eq!(status.tick, 300);
+        assert_eq!(status.mismatch, None);
+        assert!(4 <= status.verified, "{status:?}");
+        let sim = receiver.sim().unwrap();
+        assert_eq!(sim.params(), &params_at(299));
+        assert_eq!(checksum(sim.objs()), replay(5, 100, 300));
+    }
+
+    #[test]
+    fn acknowledged_inputs_are_dropped() {
+        let other: SocketAddr = "127.0.0.1:34255".parse().unwrap();
+        let mut sender = LockstepSender::new(8, 20);
+        let mut receiver = LockstepReceiver::new();
+        let mut slow = LockstepReceiver::new();
+        // The slow receiver has started, but not received anything yet
+        sender.on_ack(other, 0);
+        for tick in 0..1000 {
+            sender.step(&params_at(tick));
+            deliver(&mut receiver, sender.inputs_message(addr(), 1200), None);
+            if let Some(Message::LockstepAck { next_tick }) = receiver.ack() {
+                sender.on_ack(addr(), next_tick);
+            }
+            // The slow receiver only keeps up every hundred ticks
+            if tick % 100 == 99 {
+                while slow.sim().is_none_or(|sim| sim.tick() <= tick) {
+                    deliver(&mut slow, sender.inputs_message(other, 1200), None);
+                    let Some(Message::LockstepAck { next_tick }) = slow.ack() else {
+                        panic!("no ack");
+                    };
+                    sender.on_ack(other, next_tick);
+                }
+            }
+            assert!(sender.buffered() <= 100, "{tick}: {}", sender.buffered());
+        }
+        assert_eq!(
+            checksum(receiver.sim().unwrap().objs()),
+            checksum(sender.sim().objs())
+        );
+
+        // Without the slow receiver, only the inputs the other has not acknowledged are kept
+        sender.forget(other);
+        assert_eq!(sender.buffered(), 0);
+        sender.step(&params_at(1000));
+        assert_eq!(sender.buffered(), 1);
+        let Message::LockstepInputs { first_tick, .. } = sender.inputs_message(other, 1200) else {
+            panic!("not inputs");
+        };
+        assert_eq!(first_tick, 1000);
+    }
+
+    #[test]
+    fn late_receiver_catches_up_within_the_mtu() {
+        let mut sender = LockstepSender::new(6, 50);
+        for tick in 0..500 {
+            sender.step(&params_at(tick));
+        }
+        let mut receiver = LockstepReceiver::new();
+        while receiver.sim().is_none_or(|sim| sim.tick() < 500) {
+            let msg = sender.inputs_message(addr(), 300);
+            assert!(msg.encode(0, 0).len() <= 300);
+            deliver(&mut receiver, msg, None);
+            let Some(Message::LockstepAck { next_tick }) = receiver.ack() else {
+                panic!("no ack");
+            };
+            sender.on_ack(addr(), next_tick);
+        }
+        assert_eq!(
+            checksum(receiver.sim().unwrap().objs()),
+            checksum(sender.sim().objs())
+        );
+    }
+
+    #[test]
+    fn mismatch_is_detected() {
+        let mut sender = LockstepSender::new(7, 50);
+        let mut receiver = LockstepReceiver::new();
+        for tick in 0..CHECKSUM_INTERVAL {
+            let checksum = sender.step(&params_at(tick));
+            deliver(&mut receiver, sender.inputs_message(addr(), 1200), None);
+            if let Some(Message::Checksum { tick, hash }) = checksum {
+                receiver.on_checksum(tick, hash ^ 1);
+            }
+        }
+        assert_eq!(receiver.status().unwrap().mismatch, Some(CHECKSUM_INTERVAL));
+    }
+
+    #[test]
+    fn inputs_round_trip() {
+        let inputs = vec![
+            TickInput::default(),
+            TickInput {
+                params: Some(params_at(100)),
+            },
+        ];
+        let msg = Message::LockstepInputs {
+            seed: 9,
+            num_objects: 10,
+            first_tick: 3,
+            inputs: inputs.clone(),
+        };
+        let (
+            _,
+            Message::LockstepInputs {
+                inputs: decoded, ..
+            },
+        ) = Message::decode(&msg.encode(0, 0)).unwrap()
+        else {
+            panic!("not inputs");
+        };
+        assert_eq!(decoded, inputs);
+    }
+}
@@ -1,20 +1,21 @@
 use eframe::epaint::Color32;
-use rand::{rngs::ThreadRng, Rng};
+use rand::RngCore;
 use zerocopy_derive::{AsBytes, FromBytes, FromZeroes};
 
-use crate::{UpdateScanner, DELTA_TIME, SPACE_WIDTH};
-
-const WALL_REPULSION: f64 = 5e-2;
-const WALL_REPULSION_DIST: f64 = 0.5;
-const MIN_SPEED: f64 = 0.25;
-const MAX_SPEED: f64 = 0.5;
-const SPEED_ADAPT: f64 = 1e-2;
+use crate::{
+    protocol::ProtocolError,
+    rng::unit_f64,
+    wire::{WireReader, WireWriter},
+    UpdateScanner, DELTA_TIME, SPACE_WIDTH,
+};
 
 pub trait AsObject: AsRef<Object> + AsMut<Object> {
     fn get_color(&self) -> Color32;
     fn render_circle(&self) -> Option<Color32>;
 }
 
+/// The zerocopy layout of this struct is host dependent and is not meant to be sent
+/// over the network as is. Use [`crate::wire`] for the portable wire format.
 #[derive(Clone, Copy, Debug, Default, FromZeroes, FromBytes, AsBytes)]
 #[repr(C)]
 pub struct Object {
@@ -34,29 +35,30 @@ impl Object {
         }
     }
 
-    pub fn time_step(&mut self) {
+    pub fn time_step(&mut self, params: &BoidParams) {
         for axis in [0, 1] {
-            if self.pos[axis] < WALL_REPULSION_DIST {
-                self.velo[axis] += WALL_REPULSION;
-            } else if SPACE_WIDTH - WALL_REPULSION_DIST < self.pos[axis] {
-                self.velo[axis] -= WALL_REPULSION;
+            if self.pos[axis] < params.wall_repulsion_dist {
+                self.velo[axis] += params.wall_repulsion;
+            } else if params.space_width - params.wall_repulsion_dist < self.pos[axis] {
+                self.velo[axis] -= params.wall_repulsion;
             }
         }
         let vx = self.velo[0];
         let vy = self.velo[1];
-        let speed2 = vx.powi(2) + vy.powi(2);
-        if 0. < speed2 && speed2 < MIN_SPEED.powi(2) {
+        let speed2 = vx * vx + vy * vy;
+        if 0. < speed2 && speed2 < params.min_speed * params.min_speed {
             let speed = speed2.sqrt();
-            self.velo[0] += vx / speed * SPEED_ADAPT;
-            self.velo[1] += vy / speed * SPEED_ADAPT;
-        } else if MAX_SPEED.powi(2) < speed2 {
+            self.velo[0] += vx / speed * params.speed_adapt;
+            self.velo[1] += vy / speed * params.speed_adapt;
+        } else if params.max_speed * params.max_speed < speed2 {
             let speed = speed2.sqrt();
-            self.velo[0] -= vx / speed * SPEED_ADAPT;
-            self.velo[1] -= vy / speed * SPEED_ADAPT;
+            self.velo[0] -= vx / speed * params.speed_adapt;
+            self.velo[1] -= vy / speed * params.speed_adapt;
         }
 
         for axis in [0, 1] {
-            self.pos[axis] = (self.pos[axis] + DELTA_TIME * self.velo[axis]).clamp(0., SPACE_WIDTH);
+            self.pos[axis] =
+                (self.pos[axis] + DELTA_TIME * self.velo[axis]).clamp(0., params.space_width);
         }
     }
 }
@@ -86,9 +88,12 @@ impl AsObject for Object {
 /// A scanner to update the behavior of objects as boids.
 /// It requires other objects' information, so it is a O(N^2) operation naively, which
 /// turns into O(N*M), where M is the average number of other objects in the SortMap.
+///
+/// The result only depends on the inputs and the numbers drawn from `rng`, so it is
+/// reproducible with a seeded [`crate::rng::Pcg32`].
 pub struct BoidScanner<'a> {
-    rng: Option<&'a mut ThreadRng>,
-    randomness: f64,
+    rng: Option<&'a mut dyn RngCore>,
+    params: &'a BoidParams,
     obj1: Option<Object>,
     force: [f64; 2],
     cohesion: [f64; 2],
@@ -96,10 +101,10 @@ pub struct BoidScanner<'a> {
 }
 
 impl<'a> BoidScanner<'a> {
-    pub fn new(rng: Option<&'a mut ThreadRng>, randomness: f64) -> Self {
+    pub fn new(rng: Option<&'a mut dyn RngCore>, params: &'a BoidParams) -> Self {
         Self {
             rng,
-            randomness,
+            params,
             obj1: None,
             force: [0.; 2],
             cohesion: [0.; 2],
@@ -108,17 +113,190 @@ impl<'a> BoidScanner<'a> {
     }
 }
 
-pub const RANDOM_MOTION: f64 = 5e-3;
-pub const SEPARATION: f64 = 5e-3;
-pub const SEPARATION_DIST: f64 = 0.2;
-pub const PREDICTION_TIME: f64 = 0.;
-pub const ALIGNMENT: f64 = 1e-2;
-pub const ALIGNMENT_DIST: f64 = 0.7;
-pub const COHESION: f64 = 1e-4;
-pub const COHESION_DIST: f64 = 0.7;
-pub const GROUP_SEPARATION: f64 = 2e-3;
-pub const GROUP_SEPARATION_DIST: f64 = 1.5;
-pub const DRAG: f64 = 0.;
+/// The parameters of the boid model, which can be changed at runtime. See BOID.md for the
+/// meaning of the terms. The sender synchronizes them to the receivers, so that their
+/// predictions follow the same model.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BoidParams {
+    /// The magnitude of the random motion, which only applies with a random number generator
+    pub randomness: f64,
+    /// The factor of the separation force ($\alpha$)
+    pub separation: f64,
+    /// The maximum distance of the separation ($R_\alpha$)
+    pub separation_dist: f64,
+    /// How far ahead the separation looks at the positions extrapolated with the velocities
+    pub prediction_time: f64,
+    /// The factor of the alignment ($\beta$)
+    pub alignment: f64,
+    /// The maximum distance of the alignment ($R_\beta$)
+    pub alignment_dist: f64,
+    /// The factor of the cohesion ($\gamma$)
+    pub cohesion: f64,
+    /// The maximum distance of the cohesion ($R_\gamma$)
+    pub cohesion_dist: f64,
+    /// The factor of the group separation ($\delta$)
+    pub group_separation: f64,
+    /// The maximum distance of the group separation ($R_\delta$)
+    pub group_separation_dist: f64,
+    /// The ratio of the velocity lost in each step
+    pub drag: f64,
+    /// The acceleration away from a wall within [`Self::wall_repulsion_dist`]
+    pub wall_repulsion: f64,
+    pub wall_repulsion_dist: f64,
+    /// The speed below which the objects accelerate by [`Self::speed_adapt`]
+    pub min_speed: f64,
+    /// The speed above which the objects decelerate by [`Self::speed_adapt`]
+    pub max_speed: f64,
+    pub speed_adapt: f64,
+    /// The width and height of the square space the objects move in
+    pub space_width: f64,
+}
+
+impl Default for BoidParams {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+impl BoidParams {
+    pub const DEFAULT: Self = Self {
+        randomness: 5e-3,
+        separation: 5e-3,
+        separation_dist: 0.2,
+        prediction_time: 0.,
+        alignment: 1e-2,
+        alignment_dist: 0.7,
+        cohesion: 1e-4,
+        cohesion_dist: 0.7,
+        group_separation: 2e-3,
+        group_separation_dist: 1.5,
+        drag: 0.,
+        wall_repulsion: 5e-2,
+        wall_repulsion_dist: 0.5,
+        min_speed: 0.25,
+        max_speed: 0.5,
+        speed_adapt: 1e-2,
+        space_width: SPACE_WIDTH,
+    };
+
+    /// The upper bound of the slider of [`Self::max_speed`], which the quantized codec relies on.
+    pub const MAX_SPEED_BOUND: f64 = 2.;
+
+    /// The upper bound of the slider of [`Self::space_width`], which the quantized codec
+    /// relies on.
+    pub const SPACE_WIDTH_BOUND: f64 = 4. * SPACE_WIDTH;
+
+    /// The size of the parameters on the wire.
+    pub const WIRE_SIZE: usize = Self::INFO.len() * 8;
+
+    /// The descriptions of the parameters, in the order of [`Self::fields`].
+    pub const INFO: [ParamInfo; 17] = [
+        ParamInfo::new("randomness", "Randomness", 0.1, false),
+        ParamInfo::new("separation", "Separation (α)", 0.1, true),
+        ParamInfo::new("separation_dist", "Separation distance (R_α)", 5., false),
+        ParamInfo::new("prediction_time", "Separation prediction time", 5., false),
+        ParamInfo::new("alignment", "Alignment (β)", 0.1, true),
+        ParamInfo::new("alignment_dist", "Alignment distance (R_β)", 5., false),
+        ParamInfo::new("cohesion", "Cohesion (γ)", 1e-2, true),
+        ParamInfo::new("cohesion_dist", "Cohesion distance (R_γ)", 5., false),
+        ParamInfo::new("group_separation", "Group separation (δ)", 1e-2, true),
+        ParamInfo::new(
+            "group_separation_dist",
+            "Group separation distance (R_δ)",
+            5.,
+            false,
+        ),
+        ParamInfo::new("drag", "Drag", 1., true),
+        ParamInfo::new("wall_repulsion", "Wall repulsion", 1., true),
+        ParamInfo::new("wall_repulsion_dist", "Wall repulsion distance", 5., false),
+        ParamInfo::new("min_speed", "Min speed", 2., false),
+        ParamInfo::new("max_speed", "Max speed", Self::MAX_SPEED_BOUND, false),
+        ParamInfo::new("speed_adapt", "Speed adaptation", 0.1, true),
+        ParamInfo::new("space_width", "Space size", Self::SPACE_WIDTH_BOUND, false),
+    ];
+
+    /// The values of the parameters, in the order of [`Self::INFO`].
+    pub fn fields(&self) -> [f64; 17] {
+        [
+            self.randomness,
+            self.separation,
+            self.separation_dist,
+            self.prediction_time,
+            self.alignment,
+            self.alignment_dist,
+            self.cohesion,
+            self.cohesion_dist,
+            self.group_separation,
+            self.group_separation_dist,
+            self.drag,
+            self.wall_repulsion,
+            self.wall_repulsion_dist,
+            self.min_speed,
+            self.max_speed,
+            self.speed_adapt,
+            self.space_width,
+        ]
+    }
+
+    pub fn fields_mut(&mut self) -> [&mut f64; 17] {
+        [
+            &mut self.randomness,
+            &mut self.separation,
+            &mut self.separation_dist,
+            &mut self.prediction_time,
+            &mut self.alignment,
+            &mut self.alignment_dist,
+            &mut self.cohesion,
+            &mut self.cohesion_dist,
+            &mut self.group_separation,
+            &mut self.group_separation_dist,
+            &mut self.drag,
+            &mut self.wall_repulsion,
+            &mut self.wall_repulsion_dist,
+            &mut self.min_speed,
+            &mut self.max_speed,
+            &mut self.speed_adapt,
+            &mut self.space_width,
+        ]
+    }
+
+    pub(crate) fn write(&self, writer: &mut WireWriter) {
+        for v in self.fields() {
+            writer.f64(v);
+        }
+    }
+
+    pub(crate) fn read(reader: &mut WireReader) -> Result<Self, ProtocolError> {
+        let mut ret = Self::DEFAULT;
+        for v in ret.fields_mut() {
+            *v = reader.f64()?;
+        }
+        Ok(ret)
+    }
+}
+
+/// The description of a parameter in [`BoidParams`].
+#[derive(Clone, Copy, Debug)]
+pub struct ParamInfo {
+    /// The name of the field
+    pub key: &'static str,
+    pub label: &'static str,
+    /// The upper bound of a slider, whose lower bound is 0
+    pub max: f64,
+    /// Whether the value spans orders of magnitude
+    pub logarithmic: bool,
+}
+
+impl ParamInfo {
+    const fn new(key: &'static str, label: &'static str, max: f64, logarithmic: bool) -> Self {
+        Self {
+            key,
+            label,
+            max,
+            logarithmic,
+        }
+    }
+}
 
 impl<'a> UpdateScanner for BoidScanner<'a> {
     fn start(&mut self, _i: usize, obj1: &Object) {
@@ -134,53 +312,59 @@ impl<'a> UpdateScanner for BoidScanner<'a> {
         };
         let dx = obj1.pos[0] - obj2.pos[0];
         let dy = obj1.pos[1] - obj2.pos[1];
-        let dist2 = dx.powi(2) + dy.powi(2);
+        let dist2 = dx * dx + dy * dy;
         if dist2 == 0. {
             return;
         }
         let dist = dist2.sqrt();
+        let params = self.params;
         let predicted_pos = [
-            obj2.pos[0] + PREDICTION_TIME * obj2.velo[0]
+            obj2.pos[0] + params.prediction_time * obj2.velo[0]
                 - obj1.pos[0]
-                - PREDICTION_TIME * obj1.velo[0],
-            obj2.pos[1] + PREDICTION_TIME * obj2.velo[1]
+                - params.prediction_time * obj1.velo[0],
+            obj2.pos[1] + params.prediction_time * obj2.velo[1]
                 - obj1.pos[1]
-                - PREDICTION_TIME * obj1.velo[1],
+                - params.prediction_time * obj1.velo[1],
         ];
-        let predicted_dist2 = predicted_pos[0].powi(2) + predicted_pos[1].powi(2);
-        if predicted_dist2 < SEPARATION_DIST.powi(2) {
+        let predicted_dist2 =
+            predicted_pos[0] * predicted_pos[0] + predicted_pos[1] * predicted_pos[1];
+        let separation_dist = params.separation_dist;
+        if predicted_dist2 < separation_dist * separation_dist {
             let predicted_dist = predicted_dist2.sqrt();
             self.force[0] +=
-                SEPARATION * dx / predicted_dist * (1. - predicted_dist / SEPARATION_DIST);
+                params.separation * dx / predicted_dist * (1. - predicted_dist / separation_dist);
             self.force[1] +=
-                SEPARATION * dy / predicted_dist * (1. - predicted_dist / SEPARATION_DIST);
+                params.separation * dy / predicted_dist * (1. - predicted_dist / separation_dist);
         }
-        if dist < ALIGNMENT_DIST {
-            self.force[0] += (obj2.velo[0] - obj1.velo[0]) * ALIGNMENT;
-            self.force[1] += (obj2.velo[1] - obj1.velo[1]) * ALIGNMENT;
+        if dist < params.alignment_dist {
+            self.force[0] += (obj2.velo[0] - obj1.velo[0]) * params.alignment;
+            self.force[1] += (obj2.velo[1] - obj1.velo[1]) * params.alignment;
         }
-        if dist < COHESION_DIST {
-            self.cohesion[0] += COHESION * dx / dist;
-            self.cohesion[1] += COHESION * dy / dist;
+        if dist < params.cohesion_dist {
+            self.cohesion[0] += params.cohesion * dx / dist;
+            self.cohesion[1] += params.cohesion * dy / dist;
             self.cohesion_count += 1;
-        } else if dist < GROUP_SEPARATION_DIST {
-            self.force[0] += GROUP_SEPARATION * dx / dist * (1. - dist / GROUP_SEPARATION_DIST);
-            self.force[1] += GROUP_SEPARATION * dy / dist * (1. - dist / GROUP_SEPARATION_DIST);
+        } else if dist < params.group_separation_dist {
+            let group_separation_dist = params.group_separation_dist;
+            self.force[0] +=
+                params.group_separation * dx / dist * (1. - dist / group_separation_dist);
+            self.force[1] +=
+                params.group_separation * dy / dist * (1. - dist / group_separation_dist);
         }
     }
 
     fn end(&mut self, _i: usize, obj: &mut Object) {
         for axis in [0, 1] {
-            obj.velo[axis] += self.force[axis] - obj.velo[axis] * DRAG;
+            obj.velo[axis] += self.force[axis] - obj.velo[axis] * self.params.drag;
             if let Some(rng) = &mut self.rng {
-                obj.velo[axis] += (rng.gen::<f64>() - 0.5) * self.randomness;
+                obj.velo[axis] += (unit_f64(*rng) - 0.5) * self.params.randomness;
             }
             if 0 < self.cohesion_count {
                 obj.velo[axis] += self.cohesion[axis] / self.cohesion_count as f64;
             }
         }
 
-        obj.time_step();
+        obj.time_step(self.params);
     }
 }
 
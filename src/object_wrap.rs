@@ -1,24 +1,45 @@
 use eframe::epaint::Color32;
 
-use crate::{object::AsObject, Object};
+use crate::{object::AsObject, protocol::seq_newer, Object};
 
 #[derive(Clone, Copy)]
 pub struct ObjectWrap {
     obj: Object,
     updated: std::time::Instant,
+    /// The sequence number of the last patch applied to this object, if any.
+    seq: Option<u32>,
 }
 
 impl ObjectWrap {
-    pub fn new(obj: Object) -> Self {
+    pub fn new(obj: Object, seq: u32) -> Self {
         Self {
             obj,
             updated: std::time::Instant::now(),
+            seq: Some(seq),
         }
     }
 
     pub fn updated(&self) -> std::time::Instant {
         self.updated
     }
+
+    pub fn seq(&self) -> Option<u32> {
+        self.seq
+    }
+
+    /// Applies a patch with the sequence number `seq`, unless a newer (or the same) one
+    /// has already been applied. Returns whether the patch was applied.
+    pub fn apply(&mut self, obj: Object, seq: u32) -> bool {
+        if let Some(last) = self.seq {
+            if !seq_newer(seq, last) {
+                return false;
+            }
+        }
+        self.obj = obj;
+        self.updated = std::time::Instant::now();
+        self.seq = Some(seq);
+        true
+    }
 }
 
 impl AsRef<Object> for ObjectWrap {
@@ -38,6 +59,7 @@ impl Default for ObjectWrap {
         Self {
             obj: Object::default(),
             updated: std::time::Instant::now(),
+            seq: None,
         }
     }
 }
@@ -0,0 +1,842 @@
+//! The wire protocol shared by the sender and the receiver.
+//!
+//! Every datagram starts with a fixed size header:
+//!
+//! ```txt
+//! +-------+---------+----------+-------------+-----+-----------+---------+
+//! | magic | version | msg type | payload len | seq | timestamp | payload |
+//! |  4 B  |   1 B   |   1 B    |     2 B     | 4 B |    4 B    |   ...   |
+//! +-------+---------+----------+-------------+-----+-----------+---------+
+//! ```
+//!
+//! so that a receiver can reject foreign or incompatible datagrams instead of
+//! interpreting arbitrary bytes as objects.
+//!
+//! `seq` is incremented by the sender for every datagram and wraps around, so it should
+//! be compared with [`seq_newer`]. `timestamp` is the sender's clock in milliseconds
+//! since it started.
+//!
+//! The payloads of the messages that must not be applied if corrupted, [`Message::Reliable`]
+//! and [`Message::ReliableAck`], end with a [`crate::wire::checksum`] of the rest.
+//! All multi-byte fields are little-endian; see [`crate::wire`] for the encoding.
+
+use crate::{
+    delta::ObjectDelta,
+    entity::{EntityId, LifecycleEvent},
+    lockstep::TickInput,
+    object::BoidParams,
+    wire::{checksum, WireReader, WireWriter, OBJECT_WIRE_SIZE},
+    Object,
+};
+
+pub const MAGIC: [u8; 4] = *b"PJGL";
+pub const VERSION: u8 = 19;
+pub const HEADER_SIZE: usize = 16;
+/// The largest payload that fits in a single UDP datagram over IPv4.
+pub const MAX_DATAGRAM_SIZE: usize = 65507;
+/// The largest possible size of a [`Patch`] on the wire: a full state with a baseline
+/// reference and field lengths.
+const MAX_PATCH_SIZE: usize = 4 + 1 + 4 + 2 + OBJECT_WIRE_SIZE;
+/// The default maximum datagram size, small enough to avoid IP fragmentation on most links.
+pub const DEFAULT_MTU: usize = 1200;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MessageType {
+    /// States of a number of objects.
+    Patches = 2,
+    /// Acknowledgement of received `Patches` from the receiver to the sender.
+    Ack = 3,
+    /// A request from the receiver to measure the round-trip time.
+    Ping = 4,
+    /// A reply to `Ping` from the sender.
+    Pong = 5,
+    /// True states of objects from the sender, to measure the divergence and for debugging.
+    Truth = 6,
+    /// The seed and the inputs of ticks in the lockstep mode.
+    LockstepInputs = 7,
+    /// The next tick the receiver needs the input of in the lockstep mode.
+    LockstepAck = 8,
+    /// The checksum of the state at a tick in the lockstep mode.
+    Checksum = 9,
+    /// A request from the receiver for a full snapshot of all objects.
+    ResyncRequest = 10,
+    /// Full states of a range of objects as a part of a snapshot.
+    Snapshot = 11,
+    /// A registration of a receiver to the sender.
+    Hello = 12,
+    /// A reply to `Hello` from the sender.
+    Welcome = 13,
+    /// Messages of a reliable and ordered channel.
+    Reliable = 14,
+    /// Acknowledgement of `Reliable` messages.
+    ReliableAck = 15,
+}
+
+impl TryFrom<u8> for MessageType {
+    type Error = ProtocolError;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            2 => Ok(Self::Patches),
+            3 => Ok(Self::Ack),
+            4 => Ok(Self::Ping),
+            5 => Ok(Self::Pong),
+            6 => Ok(Self::Truth),
+            7 => Ok(Self::LockstepInputs),
+            8 => Ok(Self::LockstepAck),
+            9 => Ok(Self::Checksum),
+            10 => Ok(Self::ResyncRequest),
+            11 => Ok(Self::Snapshot),
+            12 => Ok(Self::Hello),
+            13 => Ok(Self::Welcome),
+            14 => Ok(Self::Reliable),
+            15 => Ok(Self::ReliableAck),
+            _ => Err(ProtocolError::UnknownMessageType(value)),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ProtocolError {
+    BadMagic([u8; 4]),
+    UnsupportedVersion(u8),
+    UnknownMessageType(u8),
+    Truncated { expected: usize, actual: usize },
+    Malformed(&'static str),
+}
+
+impl std::fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::BadMagic(magic) => write!(f, "bad magic number {magic:02x?}"),
+            Self::UnsupportedVersion(version) => {
+                write!(
+                    f,
+                    "unsupported protocol version {version} (expected {VERSION})"
+                )
+            }
+            Self::UnknownMessageType(ty) => write!(f, "unknown message type {ty}"),
+            Self::Truncated { expected, actual } => {
+                write!(
+                    f,
+                    "truncated datagram: expected {expected} bytes, got {actual}"
+                )
+            }
+            Self::Malformed(reason) => write!(f, "malformed datagram: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub msg_type: MessageType,
+    pub payload_len: u16,
+    pub seq: u32,
+    pub timestamp: u32,
+}
+
+impl Header {
+    fn write(&self, writer: &mut WireWriter) {
+        writer.bytes(&MAGIC);
+        writer.u8(VERSION);
+        writer.u8(self.msg_type as u8);
+        writer.u16(self.payload_len);
+        writer.u32(self.seq);
+        writer.u32(self.timestamp);
+    }
+
+    pub fn read(buf: &[u8]) -> Result<Self, ProtocolError> {
+        let mut reader = WireReader::new(buf, 0);
+        let magic = reader.bytes(MAGIC.len())?;
+        if magic != MAGIC {
+            return Err(ProtocolError::BadMagic([
+                magic[0], magic[1], magic[2], magic[3],
+            ]));
+        }
+        let version = reader.u8()?;
+        if version != VERSION {
+            return Err(ProtocolError::UnsupportedVersion(version));
+        }
+        Ok(Self {
+            msg_type: reader.u8()?.try_into()?,
+            payload_len: reader.u16()?,
+            seq: reader.u32()?,
+            timestamp: reader.u32()?,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Patch {
+    pub id: EntityId,
+    pub delta: ObjectDelta,
+}
+
+impl Patch {
+    pub fn wire_size(&self) -> usize {
+        4 + self.delta.wire_size()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Message {
+    Patches {
+        patches: Vec<Patch>,
+    },
+    /// The newest sequence number of `Patches` datagrams the receiver has decoded, and a bitfield
+    /// of the preceding ones. See [`crate::ack`] for details.
+    Ack {
+        latest: u32,
+        mask: u64,
+        /// The time in milliseconds between receiving `latest` and sending this message.
+        ack_delay: u16,
+    },
+    /// `timestamp` is the receiver's clock in milliseconds, which is echoed back in `Pong`.
+    Ping {
+        timestamp: u32,
+    },
+    Pong {
+        timestamp: u32,
+    },
+    /// The true positions and velocities of some objects at the time of the header's
+    /// `timestamp`. The colors are not carried.
+    Truth {
+        samples: Vec<(EntityId, Object)>,
+    },
+    /// The inputs of consecutive ticks starting from `first_tick`. See [`crate::lockstep`].
+    LockstepInputs {
+        seed: u64,
+        num_objects: usize,
+        first_tick: u32,
+        inputs: Vec<TickInput>,
+    },
+    LockstepAck {
+        next_tick: u32,
+    },
+    /// The checksum of the state after `tick` steps, see [`crate::lockstep::checksum`].
+    Checksum {
+        tick: u32,
+        hash: u64,
+    },
+    /// `snapshot_id` is chosen by the receiver for each request, and echoed back in `Snapshot`,
+    /// so that the sender can tell a retry from a duplicate of the request it is serving.
+    ResyncRequest {
+        snapshot_id: u32,
+    },
+    /// The full states of some objects, including the colors, and the total number of objects.
+    /// A snapshot is split into as many datagrams as needed, each of which is acknowledged
+    /// like `Patches`. The objects the receiver does not know are spawned.
+    Snapshot {
+        snapshot_id: u32,
+        num_objects: usize,
+        states: Vec<(EntityId, Object)>,
+    },
+    Hello,
+    /// `client_id` is the number the sender assigned to the receiver, for display.
+    Welcome {
+        client_id: u32,
+    },
+    /// Numbered messages of the stream of `epoch`, and the number of the oldest message the
+    /// sender has not given up on. See [`crate::reliable`] for details.
+    Reliable {
+        epoch: u32,
+        base: u32,
+        messages: Vec<(u32, Vec<u8>)>,
+    },
+    /// The number of the next message the receiver needs in the stream of `epoch`, and a
+    /// bitfield of the following ones it has received, bit `i` standing for `next_seq + 1 + i`.
+    ReliableAck {
+        epoch: u32,
+        next_seq: u32,
+        mask: u32,
+    },
+}
+
+impl Message {
+    /// The size of a [`Message::Patches`] without any patch records.
+    pub const PATCHES_OVERHEAD: usize = HEADER_SIZE + 2;
+    /// The size of a [`Message::Truth`] without any samples.
+    pub const TRUTH_OVERHEAD: usize = HEADER_SIZE + 2;
+    /// The size of a sample in [`Message::Truth`]: an id and 4 `f64`s.
+    pub const TRUTH_SAMPLE_SIZE: usize = 4 + 4 * 8;
+    /// The size of a [`Message::LockstepInputs`] without any inputs.
+    pub const INPUTS_OVERHEAD: usize = HEADER_SIZE + 8 + 4 + 4 + 2;
+    /// The size of a [`Message::Snapshot`] without any states.
+    pub const SNAPSHOT_OVERHEAD: usize = HEADER_SIZE + 4 + 4 + 2;
+    /// The size of a state in [`Message::Snapshot`]: an id and a full object.
+    pub const SNAPSHOT_STATE_SIZE: usize = 4 + OBJECT_WIRE_SIZE;
+    /// The size of a [`Message::Reliable`] without any messages, including the checksum.
+    pub const RELIABLE_OVERHEAD: usize = HEADER_SIZE + 4 + 4 + 2 + 4;
+    /// The size of a message in [`Message::Reliable`] besides the payload: the number and the
+    /// length.
+    pub const RELIABLE_ENTRY_OVERHEAD: usize = 4 + 2;
+
+    pub fn msg_type(&self) -> MessageType {
+        match self {
+            Self::Patches { .. } => MessageType::Patches,
+            Self::Ack { .. } => MessageType::Ack,
+            Self::Ping { .. } => MessageType::Ping,
+            Self::Pong { .. } => MessageType::Pong,
+            Self::Truth { .. } => MessageType::Truth,
+            Self::LockstepInputs { .. } => MessageType::LockstepInputs,
+            Self::LockstepAck { .. } => MessageType::LockstepAck,
+            Self::Checksum { .. } => MessageType::Checksum,
+            Self::ResyncRequest { .. } => MessageType::ResyncRequest,
+            Self::Snapshot { .. } => MessageType::Snapshot,
+            Self::Hello => MessageType::Hello,
+            Self::Welcome { .. } => MessageType::Welcome,
+            Self::Reliable { .. } => MessageType::Reliable,
+            Self::ReliableAck { .. } => MessageType::ReliableAck,
+        }
+    }
+
+    pub fn encode(&self, seq: u32, timestamp: u32) -> Vec<u8> {
+        let mut payload = WireWriter::new();
+        match self {
+            Self::Patches { patches } => {
+                payload.u16(patches.len() as u16);
+                for patch in patches {
+                    payload.u32(patch.id);
+                    patch.delta.write(&mut payload);
+                }
+            }
+            Self::Ack {
+                latest,
+                mask,
+                ack_delay,
+            } => {
+                payload.u32(*latest);
+                payload.u64(*mask);
+                payload.u16(*ack_delay);
+            }
+            Self::Ping { timestamp } | Self::Pong { timestamp } => payload.u32(*timestamp),
+            Self::Truth { samples } => {
+                payload.u16(samples.len() as u16);
+                for (id, obj) in samples {
+                    payload.u32(*id);
+                    for v in obj.pos.iter().chain(obj.velo.iter()) {
+                        payload.f64(*v);
+                    }
+                }
+            }
+            Self::LockstepInputs {
+                seed,
+                num_objects,
+                first_tick,
+                inputs,
+            } => {
+                payload.u64(*seed);
+                payload.u32(*num_objects as u32);
+                payload.u32(*first_tick);
+                payload.u16(inputs.len() as u16);
+                for input in inputs {
+                    input.write(&mut payload);
+                }
+            }
+            Self::LockstepAck { next_tick } => payload.u32(*next_tick),
+            Self::Checksum { tick, hash } => {
+                payload.u32(*tick);
+                payload.u64(*hash);
+            }
+            Self::ResyncRequest { snapshot_id } => payload.u32(*snapshot_id),
+            Self::Snapshot {
+                snapshot_id,
+                num_objects,
+                states,
+            } => {
+                payload.u32(*snapshot_id);
+                payload.u32(*num_objects as u32);
+                payload.u16(states.len() as u16);
+                for (id, obj) in states {
+                    payload.u32(*id);
+                    payload.object(obj);
+                }
+            }
+            Self::Hello => {}
+            Self::Welcome { client_id } => payload.u32(*client_id),
+            Self::Reliable {
+                epoch,
+                base,
+                messages,
+            } => {
+                payload.u32(*epoch);
+                payload.u32(*base);
+                payload.u16(messages.len() as u16);
+                for (seq, message) in messages {
+                    payload.u32(*seq);
+                    payload.u16(message.len() as u16);
+                    payload.bytes(message);
+                }
+                payload.checksum();
+            }
+            Self::ReliableAck {
+                epoch,
+                next_seq,
+                mask,
+            } => {
+                payload.u32(*epoch);
+                payload.u32(*next_seq);
+                payload.u32(*mask);
+                payload.checksum();
+            }
+        }
+        let payload = payload.into_inner();
+        let mut writer = WireWriter::new();
+        Header {
+            msg_type: self.msg_type(),
+            payload_len: payload.len() as u16,
+            seq,
+            timestamp,
+        }
+        .write(&mut writer);
+        writer.bytes(&payload);
+        writer.into_inner()
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<(Header, Self), ProtocolError> {
+        let header = Header::read(buf)?;
+        let payload = &buf[HEADER_SIZE..];
+        if payload.len() < header.payload_len as usize {
+            return Err(ProtocolError::Truncated {
+                expected: HEADER_SIZE + header.payload_len as usize,
+                actual: buf.len(),
+            });
+        }
+        let mut payload = &payload[..header.payload_len as usize];
+        if matches!(
+            header.msg_type,
+            MessageType::Reliable | MessageType::ReliableAck
+        ) {
+            let Some((rest, sum)) = payload.split_last_chunk::<4>() else {
+                return Err(ProtocolError::Truncated {
+                    expected: HEADER_SIZE + 4,
+                    actual: HEADER_SIZE + payload.len(),
+                });
+            };
+            if checksum(rest) != u32::from_le_bytes(*sum) {
+                return Err(ProtocolError::Malformed("checksum mismatch"));
+            }
+            payload = rest;
+        }
+        let mut reader = WireReader::new(payload, HEADER_SIZE);
+        let msg = match header.msg_type {
+            MessageType::Patches => {
+                let num_patches = reader.u16()? as usize;
+                let patches = (0..num_patches)
+                    .map(|_| {
+                        Ok(Patch {
+                            id: reader.u32()?,
+                            delta: ObjectDelta::read(&mut reader)?,
+                        })
+                    })
+                    .collect::<Result<_, _>>()?;
+                Self::Patches { patches }
+            }
+            MessageType::Ack => Self::Ack {
+                latest: reader.u32()?,
+                mask: reader.u64()?,
+                ack_delay: reader.u16()?,
+            },
+            MessageType::Ping => Self::Ping {
+                timestamp: reader.u32()?,
+            },
+            MessageType::Pong => Self::Pong {
+                timestamp: reader.u32()?,
+            },
+            MessageType::Truth => {
+                let num_samples = reader.u16()? as usize;
+                let samples = (0..num_samples)
+                    .map(|_| {
+                        let id = reader.u32()?;
+                        let mut obj = Object::default();
+                        for v in obj.pos.iter_mut().chain(obj.velo.iter_mut()) {
+                            *v = reader.f64()?;
+                        }
+                        Ok((id, obj))
+                    })
+                    .collect::<Result<_, _>>()?;
+                Self::Truth { samples }
+            }
+            MessageType::LockstepInputs => {
+                let seed = reader.u64()?;
+                let num_objects = reader.u32()? as usize;
+                let first_tick = reader.u32()?;
+                let num_inputs = reader.u16()? as usize;
+                let inputs = (0..num_inputs)
+                    .map(|_| TickInput::read(&mut reader))
+                    .collect::<Result<_, _>>()?;
+                Self::LockstepInputs {
+                    seed,
+                    num_objects,
+                    first_tick,
+                    inputs,
+                }
+            }
+            MessageType::LockstepAck => Self::LockstepAck {
+                next_tick: reader.u32()?,
+            },
+            MessageType::Checksum => Self::Checksum {
+                tick: reader.u32()?,
+                hash: reader.u64()?,
+            },
+            MessageType::ResyncRequest => Self::ResyncRequest {
+                snapshot_id: reader.u32()?,
+            },
+            MessageType::Snapshot => {
+                let snapshot_id = reader.u32()?;
+                let num_objects = reader.u32()? as usize;
+                let num_states = reader.u16()? as usize;
+                let states = (0..num_states)
+                    .map(|_| Ok((reader.u32()?, reader.object()?)))
+                    .collect::<Result<_, _>>()?;
+                Self::Snapshot {
+                    snapshot_id,
+                    num_objects,
+                    states,
+                }
+            }
+            MessageType::Hello => Self::Hello,
+            MessageType::Welcome => Self::Welcome {
+                client_id: reader.u32()?,
+            },
+            MessageType::Reliable => {
+                let epoch = reader.u32()?;
+                let base = reader.u32()?;
+                let num_messages = reader.u16()? as usize;
+                let messages = (0..num_messages)
+                    .map(|_| {
+                        let seq = reader.u32()?;
+                        let len = reader.u16()? as usize;
+                        Ok((seq, reader.bytes(len)?.to_vec()))
+                    })
+                    .collect::<Result<_, _>>()?;
+                Self::Reliable {
+                    epoch,
+                    base,
+                    messages,
+                }
+            }
+            MessageType::ReliableAck => Self::ReliableAck {
+                epoch: reader.u32()?,
+                next_seq: reader.u32()?,
+                mask: reader.u32()?,
+            },
+        };
+        Ok((header, msg))
+    }
+}
+
+/// A message of the reliable channel from the sender to a receiver, see [`crate::reliable`].
+#[derive(Clone, Copy, Debug)]
+pub enum ChannelMessage {
+    Lifecycle(LifecycleEvent),
+    /// The current parameters of the simulation, sent whenever they change.
+    Params(BoidParams),
+}
+
+impl ChannelMessage {
+    const LIFECYCLE: u8 = 0;
+    const PARAMS: u8 = 1;
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut writer = WireWriter::new();
+        match self {
+            Self::Lifecycle(event) => {
+                writer.u8(Self::LIFECYCLE);
+                event.write(&mut writer);
+            }
+            Self::Params(params) => {
+                writer.u8(Self::PARAMS);
+                params.write(&mut writer);
+            }
+        }
+        writer.into_inner()
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self, ProtocolError> {
+        let mut reader = WireReader::new(buf, 0);
+        match reader.u8()? {
+            Self::LIFECYCLE => Ok(Self::Lifecycle(LifecycleEvent::read(&mut reader)?)),
+            Self::PARAMS => Ok(Self::Params(BoidParams::read(&mut reader)?)),
+            _ => Err(ProtocolError::Malformed("unknown channel message")),
+        }
+    }
+}
+
+/// Packs patch records into as few datagrams as possible, each of which is no larger than `mtu`.
+pub struct PatchPacker {
+    mtu: usize,
+    len: usize,
+    patches: Vec<Patch>,
+}
+
+impl PatchPacker {
+    pub fn new(mtu: usize) -> Self {
+        Self {
+            mtu: mtu.clamp(
+                Message::PATCHES_OVERHEAD + MAX_PATCH_SIZE,
+                MAX_DATAGRAM_SIZE,
+            ),
+            len: Message::PATCHES_OVERHEAD,
+            patches: vec![],
+        }
+    }
+
+    /// Adds a patch, returning a full message if the patch did not fit in the current one.
+    pub fn push(&mut self, patch: Patch) -> Option<Message> {
+        let ret = if self.mtu < self.len + patch.wire_size() {
+            self.finish()
+        } else {
+            None
+        };
+        self.len += patch.wire_size();
+        self.patches.push(patch);
+        ret
+    }
+
+    /// The number of bytes that pushing the patch would add to the datagrams,
+    /// including the overhead of a new datagram if it does not fit in the current one.
+    pub fn cost(&self, patch: &Patch) -> usize {
+        if self.patches.is_empty() || self.mtu < self.len + patch.wire_size() {
+            Message::PATCHES_OVERHEAD + patch.wire_size()
+        } else {
+            patch.wire_size()
+        }
+    }
+
+    /// Returns the message with the remaining patches, if any.
+    pub fn finish(&mut self) -> Option<Message> {
+        if self.patches.is_empty() {
+            return None;
+        }
+        self.len = Message::PATCHES_OVERHEAD;
+        Some(Message::Patches {
+            patches: std::mem::take(&mut self.patches),
+        })
+    }
+}
+
+/// Returns whether sequence number `a` is newer than `b`, taking wrap-around into account.
+/// A sequence number is considered newer if it is ahead by less than half of the range.
+pub fn seq_newer(a: u32, b: u32) -> bool {
+    a != b && a.wrapping_sub(b) < 1 << 31
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::Codec;
+
+    fn round_trip(msg: &Message) -> Message {
+        let buf = msg.encode(42, 1234);
+        let (header, decoded) = Message::decode(&buf).unwrap();
+        assert_eq!(header.msg_type, msg.msg_type());
+        assert_eq!(header.seq, 42);
+        assert_eq!(header.timestamp, 1234);
+        assert_eq!(header.payload_len as usize, buf.len() - HEADER_SIZE);
+        // Re-encoding yields the same bytes, so nothing is lost in between
+        assert_eq!(decoded.encode(42, 1234), buf);
+        decoded
+    }
+
+    #[test]
+    fn header_layout() {
+        let buf = Message::Welcome { client_id: 7 }.encode(0x04030201, 0x08070605);
+        assert_eq!(&buf[..4], b"PJGL");
+        assert_eq!(buf[4], VERSION);
+        assert_eq!(buf[5], MessageType::Welcome as u8);
+        assert_eq!(&buf[6..8], &4u16.to_le_bytes());
+        assert_eq!(&buf[8..16], &[1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(buf.len(), HEADER_SIZE + 4);
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        let Message::Ack {
+            latest,
+            mask,
+            ack_delay,
+        } = round_trip(&Message::Ack {
+            latest: u32::MAX,
+            mask: 0x8000_0000_0000_0001,
+            ack_delay: 300,
+        })
+        else {
+            panic!("not an ack");
+        };
+        assert_eq!(
+            (latest, mask, ack_delay),
+            (u32::MAX, 0x8000_0000_0000_0001, 300)
+        );
+
+        let mut obj = Object::new([3., 4.], [1, 2, 3]);
+        obj.velo = [0.5, -0.25];
+        let Message::Snapshot {
+            snapshot_id,
+            num_objects,
+            states,
+        } = round_trip(&Message::Snapshot {
+            snapshot_id: 9,
+            num_objects: 100,
+            states: vec![(5, obj), (70000, Object::default())],
+        })
+        else {
+            panic!("not a snapshot");
+        };
+        assert_eq!((snapshot_id, num_objects), (9, 100));
+        assert_eq!(states.len(), 2);
+        assert_eq!(states[0].0, 5);
+        assert_eq!(states[0].1.pos, obj.pos);
+        assert_eq!(states[0].1.velo, obj.velo);
+        assert_eq!(states[0].1.color, obj.color);
+        assert_eq!(states[1].0, 70000);
+
+        let base = Object::new([3., 4.5], [1, 2, 3]);
+        let Message::Patches { patches } = round_trip(&Message::Patches {
+            patches: vec![
+                Patch {
+                    id: 5,
+                    delta: ObjectDelta::new(&obj, None, Codec::Full),
+                },
+                Patch {
+                    id: 70000,
+                    delta: ObjectDelta::new(&obj, Some((7, &base)), Codec::Quantized),
+                },
+            ],
+        }) else {
+            panic!("not patches");
+        };
+        assert_eq!(patches.len(), 2);
+        assert_eq!(patches[0].id, 5);
+        assert_eq!(patches[0].delta.apply(None).pos, obj.pos);
+        assert_eq!(patches[1].id, 70000);
+        assert_eq!(patches[1].delta.base_seq, Some(7));
+
+        let Message::Reliable {
+            epoch,
+            base,
+            messages,
+        } = round_trip(&Message::Reliable {
+            epoch: 3,
+            base: 10,
+            messages: vec![(10, vec![1, 2, 3]), (12, vec![])],
+        })
+        else {
+            panic!("not a reliable message");
+        };
+        assert_eq!((epoch, base), (3, 10));
+        assert_eq!(messages, vec![(10, vec![1, 2, 3]), (12, vec![])]);
+
+        assert!(matches!(round_trip(&Message::Hello), Message::Hello));
+    }
+
+    #[test]
+    fn header_errors() {
+        let buf = Message::Ping { timestamp: 1 }.encode(0, 0);
+
+        let mut bad_magic = buf.clone();
+        bad_magic[0] = b'X';
+        assert_eq!(
+            Message::decode(&bad_magic).unwrap_err(),
+            ProtocolError::BadMagic(*b"XJGL")
+        );
+
+        let mut bad_version = buf.clone();
+        bad_version[4] = VERSION.wrapping_add(1);
+        assert_eq!(
+            Message::decode(&bad_version).unwrap_err(),
+            ProtocolError::UnsupportedVersion(VERSION.wrapping_add(1))
+        );
+
+        let mut bad_type = buf.clone();
+        bad_type[5] = 0xff;
+        assert_eq!(
+            Message::decode(&bad_type).unwrap_err(),
+            ProtocolError::UnknownMessageType(0xff)
+        );
+
+        assert_eq!(
+            Message::decode(&buf[..HEADER_SIZE - 1]).unwrap_err(),
+            ProtocolError::Truncated {
+                expected: HEADER_SIZE,
+                actual: HEADER_SIZE - 1
+            }
+        );
+    }
+
+    #[test]
+    fn truncated_payload() {
+        let buf = Message::Ack {
+            latest: 1,
+            mask: 2,
+            ack_delay: 3,
+        }
+        .encode(0, 0);
+        for len in HEADER_SIZE..buf.len() {
+            assert!(
+                matches!(
+                    Message::decode(&buf[..len]),
+                    Err(ProtocolError::Truncated { .. })
+                ),
+                "decoded {len} bytes"
+            );
+        }
+
+        // A payload length that claims more records than there are
+        let mut buf = Message::Snapshot {
+            snapshot_id: 0,
+            num_objects: 1,
+            states: vec![(0, Object::default())],
+        }
+        .encode(0, 0);
+        buf[HEADER_SIZE + 8] = 2;
+        assert!(matches!(
+            Message::decode(&buf),
+            Err(ProtocolError::Truncated { .. })
+        ));
+    }
+
+    #[test]
+    fn reliable_checksum() {
+        let buf = Message::ReliableAck {
+            epoch: 1,
+            next_seq: 2,
+            mask: 3,
+        }
+        .encode(0, 0);
+        for i in HEADER_SIZE..buf.len() {
+            let mut corrupted = buf.clone();
+            corrupted[i] ^= 0x10;
+            assert_eq!(
+                Message::decode(&corrupted).unwrap_err(),
+                ProtocolError::Malformed("checksum mismatch")
+            );
+        }
+    }
+
+    #[test]
+    fn channel_messages_round_trip() {
+        let params = BoidParams {
+            cohesion: 1.,
+            ..BoidParams::default()
+        };
+        let ChannelMessage::Params(decoded) =
+            ChannelMessage::decode(&ChannelMessage::Params(params).encode()).unwrap()
+        else {
+            panic!("not params");
+        };
+        assert_eq!(decoded, params);
+        assert!(ChannelMessage::decode(&[0xff]).is_err());
+    }
+
+    #[test]
+    fn seq_newer_wraps_around() {
+        assert!(seq_newer(1, 0));
+        assert!(!seq_newer(0, 1));
+        assert!(!seq_newer(5, 5));
+        assert!(seq_newer(2, u32::MAX - 2));
+        assert!(!seq_newer(u32::MAX - 2, 2));
+    }
+}
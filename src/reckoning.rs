@@ -0,0 +1,324 @@
+//! Dead reckoning on the sender side.
+//!
+//! The receiver extrapolates the replicas with the boid model without randomness between
+//! updates, using the parameters synchronized from the sender. The sender runs the same model
+//! on a shadow copy of what the receiver believes, so it can tell how far off the receiver's
+//! view is and send only the objects whose prediction has drifted from the truth.
+//!
+//! Both ends step with an [`Extrapolator`] on the ticks of the sender's clock, on which the
+//! sender steps the simulation too. The receiver estimates the clock from the timestamps of
+//! the datagrams, so the shadow copies are the replicas as long as the datagrams arrive within
+//! the tick they were sent in.
+
+use crate::{
+    entity::EntityId,
+    object::{BoidParams, BoidScanner},
+    Object, SortMap, DELTA_TIME, TICK_INTERVAL,
+};
+
+/// The maximum number of ticks to catch up at once. The time beyond it is skipped.
+const MAX_CATCH_UP: u32 = 100;
+
+/// The weight of the velocity error in [`DeadReckoning::error`], which is the
+/// position error the velocity error would cause in 10 time steps.
+pub const VELO_ERROR_WEIGHT: f64 = 10. * DELTA_TIME;
+
+/// Counts the ticks of [`TICK_INTERVAL`] on the sender's clock.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TickClock {
+    /// The last tick seen, if any
+    tick: Option<u32>,
+}
+
+impl TickClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn tick(&self) -> Option<u32> {
+        self.tick
+    }
+
+    /// Moves to the tick of `time` in milliseconds of the sender's clock, and returns the
+    /// number of ticks passed since the last call, up to [`MAX_CATCH_UP`].
+    /// The first call only sets the tick to start from.
+    pub fn advance(&mut self, time: u32) -> u32 {
+        let target = time / TICK_INTERVAL;
+        let Some(tick) = self.tick else {
+            self.tick = Some(target);
+            return 0;
+        };
+        // The receiver's estimate of the sender's clock can go back a little
+        let steps = target.wrapping_sub(tick);
+        if steps == 0 || u32::MAX / 2 < steps {
+            return 0;
+        }
+        self.tick = Some(target);
+        steps.min(MAX_CATCH_UP)
+    }
+}
+
+/// Steps objects with the boid model without randomness on the ticks of the sender's clock.
+///
+/// The model updates the objects one by one in place, so the result depends on the order of
+/// the objects. Since the slots of the objects differ between the ends, they are stepped in
+/// the order of their entity ids instead.
+#[derive(Default)]
+pub struct Extrapolator {
+    clock: TickClock,
+    sort_map: SortMap,
+    /// The slots of the objects in the order of their ids
+    order: Vec<usize>,
+    ordered: Vec<Object>,
+}
+
+impl Extrapolator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn tick(&self) -> Option<u32> {
+        self.clock.tick()
+    }
+
+    /// Steps `objs`, whose entity ids are `ids`, once for each tick passed up to `time` in
+    /// milliseconds of the sender's clock, and returns the number of steps taken.
+    /// See [`TickClock::advance`].
+    pub fn advance(
+        &mut self,
+        objs: &mut [impl AsRef<Object> + AsMut<Object>],
+        ids: &[EntityId],
+        params: &BoidParams,
+        time: u32,
+    ) -> u32 {
+        let steps = self.clock.advance(time);
+        for _ in 0..steps {
+            self.step(objs, ids, params);
+        }
+        steps
+    }
+
+    /// Steps `objs`, whose entity ids are `ids`, by a time step.
+    pub fn step(
+        &mut self,
+        objs: &mut [impl AsRef<Object> + AsMut<Object>],
+        ids: &[EntityId],
+        params: &BoidParams,
+    ) {
+        debug_assert_eq!(objs.len(), ids.len());
+        if self.ordered.len() != objs.len() {
+            self.sort_map = SortMap::new(objs.len());
+        }
+        self.order.clear();
+        self.order.extend(0..objs.len());
+        self.order.sort_unstable_by_key(|&slot| ids[slot]);
+        self.ordered.clear();
+        self.ordered
+            .extend(self.order.iter().map(|&slot| *objs[slot].as_ref()));
+
+        let mut scanner = BoidScanner::new(None, params);
+        self.sort_map.update(&self.ordered);
+        self.sort_map.scan(&mut self.ordered, &mut scanner);
+
+        for (&slot, obj) in self.order.iter().zip(&self.ordered) {
+            *objs[slot].as_mut() = *obj;
+        }
+    }
+}
+
+/// The shadow copies of the objects as a receiver predicts them.
+#[derive(Default)]
+pub struct DeadReckoning {
+    shadow: Vec<Object>,
+    /// Whether each object has ever been sent
+    sent: Vec<bool>,
+    extrapolator: Extrapolator,
+}
+
+impl DeadReckoning {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn resize(&mut self, num_objects: usize) {
+        self.shadow.resize(num_objects, Object::default());
+        self.sent.resize(num_objects, false);
+    }
+
+    /// Forgets the object `index` and moves the last one into its place.
+    pub fn swap_remove(&mut self, index: usize) {
+        self.shadow.swap_remove(index);
+        self.sent.swap_remove(index);
+    }
+
+    /// Advances the shadow copies, whose entity ids are `ids`, to `time` in milliseconds of
+    /// the sender's clock in the same way as the receiver does.
+    pub fn predict(&mut self, ids: &[EntityId], params: &BoidParams, time: u32) {
+        self.extrapolator
+            .advance(&mut self.shadow, ids, params, time);
+    }
+
+    /// Returns the difference between the receiver's prediction and the actual state, as the
+    /// distance of positions plus the distance of velocities weighted by [`VELO_ERROR_WEIGHT`].
+    /// It is infinite if the object has never been sent.
+    pub fn error(&self, index: usize, obj: &Object) -> f64 {
+        if !self.sent.get(index).copied().unwrap_or(false) {
+            return f64::INFINITY;
+        }
+        let shadow = &self.shadow[index];
+        let dist =
+            |a: [f64; 2], b: [f64; 2]| ((a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2)).sqrt();
+        dist(shadow.pos, obj.pos) + dist(shadow.velo, obj.velo) * VELO_ERROR_WEIGHT
+    }
+
+    /// Records the state the receiver will reconstruct from a sent patch.
+    pub fn on_sent(&mut self, index: usize, obj: Object) {
+        if let Some(shadow) = self.shadow.get_mut(index) {
+            *shadow = obj;
+            self.sent[index] = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{rng::Pcg32, SPACE_WIDTH};
+
+    const NUM_OBJECTS: usize = 200;
+
+    /// The ids of the objects in the sender's slots, in an order unrelated to the slots.
+    fn sender_ids() -> Vec<EntityId> {
+        (0..NUM_OBJECTS as u32)
+            .map(|i| i * 7919 % NUM_OBJECTS as u32)
+            .collect()
+    }
+
+    fn random_objects(rng: &mut Pcg32) -> Vec<Object> {
+        (0..NUM_OBJECTS)
+            .map(|_| {
+                let mut obj = Object::new(
+                    [rng.next_f64() * SPACE_WIDTH, rng.next_f64() * SPACE_WIDTH],
+                    [0; 3],
+                );
+                obj.velo = [rng.next_f64() - 0.5, rng.next_f64() - 0.5];
+                obj
+            })
+            .collect()
+    }
+
+    /// The receiver keeps the objects in the reverse order of the sender.
+    fn receiver_slot(slot: usize) -> usize {
+        NUM_OBJECTS - 1 - slot
+    }
+
+    fn assert_same(a: &Object, b: &Object) {
+        assert_eq!((a.pos, a.velo), (b.pos, b.velo));
+    }
+
+    #[test]
+    fn ticks_follow_the_clock() {
+        let params = BoidParams::default();
+        let ids = sender_ids();
+        let mut objs = random_objects(&mut Pcg32::new(1));
+        let mut extrapolator = Extrapolator::new();
+        let mut advance = |time| extrapolator.advance(&mut objs, &ids, &params, time);
+        assert_eq!(advance(1003), 0);
+        assert_eq!(advance(1009), 0);
+        assert_eq!(advance(1010), 1);
+        assert_eq!(advance(1035), 2);
+        // The clock going back does not step the objects back or forth
+        assert_eq!(advance(1025), 0);
+        assert_eq!(advance(1039), 0);
+        assert_eq!(advance(100_000), MAX_CATCH_UP);
+        assert_eq!(extrapolator.tick(), Some(100_000 / TICK_INTERVAL));
+    }
+
+    #[test]
+    fn the_order_of_slots_does_not_matter() {
+        let params = BoidParams::default();
+        let ids = sender_ids();
+        let objs = random_objects(&mut Pcg32::new(2));
+        let mut sender_objs = objs.clone();
+        let mut receiver_objs = objs.clone();
+        let mut receiver_ids = ids.clone();
+        for slot in 0..NUM_OBJECTS {
+            receiver_objs[receiver_slot(slot)] = objs[slot];
+            receiver_ids[receiver_slot(slot)] = ids[slot];
+        }
+        let mut sender = Extrapolator::new();
+        let mut receiver = Extrapolator::new();
+        for _ in 0..50 {
+            sender.step(&mut sender_objs, &ids, &params);
+            receiver.step(&mut receiver_objs, &receiver_ids, &params);
+        }
+        for slot in 0..NUM_OBJECTS {
+            assert_same(&sender_objs[slot], &receiver_objs[receiver_slot(slot)]);
+        }
+        // The objects have interacted, rather than moved in straight lines
+        assert!(sender_objs
+            .iter()
+            .zip(&objs)
+            .any(|(stepped, initial)| stepped.velo != initial.velo));
+    }
+
+    /// Runs the sender's shadows and the receiver's replicas side by side, with the sender
+    /// sending every 30 ms and the receiver extrapolating every 5 ms of the sender's clock,
+    /// and checks that the shadows are exactly the replicas after each burst.
+    #[test]
+    fn shadows_agree_with_the_receiver() {
+        let params = BoidParams {
+            randomness: 0.01,
+            ..BoidParams::default()
+        };
+        let ids = sender_ids();
+        let mut rng = Pcg32::new(3);
+        let mut truth = random_objects(&mut rng);
+        let mut truth_sort_map = SortMap::new(NUM_OBJECTS);
+        let mut truth_clock = TickClock::new();
+        let mut reckoning = DeadReckoning::new();
+        reckoning.resize(NUM_OBJECTS);
+
+        let mut receiver_ids = ids.clone();
+        for slot in 0..NUM_OBJECTS {
+            receiver_ids[receiver_slot(slot)] = ids[slot];
+        }
+        let mut replicas = vec![Object::default(); NUM_OBJECTS];
+        let mut extrapolator = Extrapolator::new();
+
+        let mut total_sent = 0;
+        let mut bursts = 0;
+        for time in (0..6000).step_by(5) {
+            extrapolator.advance(&mut replicas, &receiver_ids, &params, time);
+            if time % 30 != 0 {
+                continue;
+            }
+            bursts += 1;
+
+            for _ in 0..truth_clock.advance(time) {
+                let mut scanner = BoidScanner::new(Some(&mut rng), &params);
+                truth_sort_map.update(&truth);
+                truth_sort_map.scan(&mut truth, &mut scanner);
+            }
+
+            reckoning.predict(&ids, &params, time);
+            let mut diverged: Vec<_> = (0..NUM_OBJECTS)
+                .map(|slot| (reckoning.error(slot, &truth[slot]), slot))
+                .filter(|(error, _)| 0.05 < *error)
+                .collect();
+            diverged.sort_by(|a, b| b.0.total_cmp(&a.0));
+            // The datagram arrives within the tick it was sent in
+            for &(_, slot) in diverged.iter().take(10) {
+                reckoning.on_sent(slot, truth[slot]);
+                replicas[receiver_slot(slot)] = truth[slot];
+                total_sent += 1;
+            }
+
+            for slot in 0..NUM_OBJECTS {
+                assert_same(&reckoning.shadow[slot], &replicas[receiver_slot(slot)]);
+            }
+        }
+        // Dead reckoning has saved some of the updates
+        assert!(total_sent < bursts * 10, "{total_sent}");
+    }
+}
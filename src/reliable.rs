@@ -0,0 +1,484 @@
+//! A reliable and ordered channel of messages alongside the unreliable stream of patches.
+//!
+//! [`ReliableSender`] numbers each message and sends it in a [`Message::Reliable`] datagram,
+//! together with the other ones due, on the same socket as everything else. A message is sent
+//! again if it has not been acknowledged within the retransmission timeout, which is derived
+//! from the round-trip time like TCP's (RFC 6298) and doubles with each retry up to [`MAX_RTO`],
+//! until an acknowledgement shows that the link is delivering again.
+//!
+//! [`ReliableReceiver`] buffers the messages that arrive ahead of a missing one and delivers
+//! them exactly once in order. It acknowledges the number of the next message it needs, along
+//! with a bitfield of the buffered ones after it, so that those are not sent again. The sender
+//! only sends the messages within [`RECEIVE_WINDOW`] after the oldest one not acknowledged,
+//! which the receiver has room to buffer.
+//!
+//! A stream is identified by an epoch, which the sender can change to start the numbering
+//! over, e.g. when the receiver has restarted. The messages not acknowledged yet are carried
+//! over to the new epoch, so the application must tolerate receiving them twice across epochs.
+//! A sender to a multicast group, which nobody acknowledges, can give up on old messages with
+//! [`ReliableSender::expire`]. Each datagram tells the oldest message the sender still has,
+//! so that the receiver can tell which ones it has missed for good.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use crate::protocol::{seq_newer, Message};
+
+/// The retransmission timeout until the round-trip time is measured.
+pub const INITIAL_RTO: Duration = Duration::from_millis(100);
+/// The lower bound of the retransmission timeout, so that a jittery link is not flooded.
+pub const MIN_RTO: Duration = Duration::from_millis(50);
+/// The upper bound of the retransmission timeout after backing off.
+pub const MAX_RTO: Duration = Duration::from_secs(1);
+/// The number of messages after a missing one that the receiver buffers and acknowledges.
+pub const RECEIVE_WINDOW: u32 = 32;
+
+/// A message that has not been acknowledged yet.
+struct Pending {
+    seq: u32,
+    payload: Vec<u8>,
+    queued: Instant,
+    last_sent: Option<Instant>,
+    /// The number of times it has been sent
+    sends: u32,
+}
+
+/// The sending end of a reliable channel.
+pub struct ReliableSender {
+    epoch: u32,
+    next_seq: u32,
+    /// The messages not acknowledged yet, in the order of the sequence numbers
+    pending: VecDeque<Pending>,
+    rto: Duration,
+}
+
+impl Default for ReliableSender {
+    fn default() -> Self {
+        Self {
+            epoch: 0,
+            next_seq: 0,
+            pending: VecDeque::new(),
+            rto: INITIAL_RTO,
+        }
+    }
+}
+
+impl ReliableSender {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn epoch(&self) -> u32 {
+        self.epoch
+    }
+
+    /// Queues a message, which is sent with the next call to [`Self::message`].
+    pub fn send(&mut self, payload: Vec<u8>) {
+        self.pending.push_back(Pending {
+            seq: self.next_seq,
+            payload,
+            queued: Instant::now(),
+            last_sent: None,
+            sends: 0,
+        });
+        self.next_seq = self.next_seq.wrapping_add(1);
+    }
+
+    /// Starts a new stream numbered from 0 with the messages not acknowledged yet.
+    pub fn restart(&mut self, epoch: u32) {
+        self.epoch = epoch;
+        for (seq, pending) in (0..).zip(&mut self.pending) {
+            pending.seq = seq;
+            pending.last_sent = None;
+            pending.sends = 0;
+        }
+        self.next_seq = self.pending.len() as u32;
+    }
+
+    /// Updates the retransmission timeout with the smoothed round-trip time and its mean
+    /// deviation, see [`crate::ack::LinkStats`].
+    pub fn set_rtt(&mut self, rtt: Duration, rtt_var: Duration) {
+        self.rto = (rtt + rtt_var * 4).clamp(MIN_RTO, MAX_RTO);
+    }
+
+    /// The payloads of the messages not acknowledged yet, in order.
+    pub fn pending(&self) -> impl Iterator<Item = &[u8]> {
+        self.pending.iter().map(|pending| &pending.payload[..])
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Processes a [`Message::ReliableAck`].
+    pub fn on_ack(&mut self, epoch: u32, next_seq: u32, mask: u32) {
+        if epoch != self.epoch {
+            return;
+        }
+        let len = self.pending.len();
+        self.pending.retain(|pending| {
+            let offset = pending.seq.wrapping_sub(next_seq);
+            let buffered = 0 < offset && offset <= RECEIVE_WINDOW && mask & 1 << (offset - 1) != 0;
+            !seq_newer(next_seq, pending.seq) && !buffered
+        });
+        if self.pending.len() < len {
+            // Stop backing off
+            for pending in &mut self.pending {
+                pending.sends = pending.sends.min(1);
+            }
+        }
+    }
+
+    /// Gives up on the messages queued more than `lifetime` ago, for a receiver which does not
+    /// acknowledge.
+    pub fn expire(&mut self, lifetime: Duration) {
+        while self
+            .pending
+            .front()
+            .is_some_and(|pending| lifetime <= pending.queued.elapsed())
+        {
+            self.pending.pop_front();
+        }
+    }
+
+    /// Returns the messages due to be sent or sent again that fit in `mtu`, if any.
+    /// A message larger than `mtu` is sent alone.
+    pub fn message(&mut self, mtu: usize) -> Option<Message> {
+        let now = Instant::now();
+        let rto = self.rto;
+        let base = self.base();
+        let mut len = Message::RELIABLE_OVERHEAD;
+        let mut messages = vec![];
+        for pending in &mut self.pending {
+            if RECEIVE_WINDOW < pending.seq.wrapping_sub(base) {
+                break;
+            }
+            let due = pending.last_sent.is_none_or(|time| {
+                let timeout = rto.saturating_mul(1 << (pending.sends - 1).min(16));
+                timeout.min(MAX_RTO) <= now - time
+            });
+            if !due {
+                continue;
+            }
+            len += Message::RELIABLE_ENTRY_OVERHEAD + pending.payload.len();
+            if !messages.is_empty() && (mtu < len || u16::MAX as usize <= messages.len()) {
+                break;
+            }
+            pending.last_sent = Some(now);
+            pending.sends += 1;
+            messages.push((pending.seq, pending.payload.clone()));
+        }
+        if messages.is_empty() {
+            return None;
+        }
+        Some(Message::Reliable {
+            epoch: self.epoch,
+            base,
+            messages,
+        })
+    }
+
+    /// The number of the oldest message not acknowledged yet, or the next one if there is none.
+    fn base(&self) -> u32 {
+        self.pending
+            .front()
+            .map_or(self.next_seq, |pending| pending.seq)
+    }
+}
+
+/// The receiving end of a reliable channel.
+#[derive(Default)]
+pub struct ReliableReceiver {
+    epoch: Option<u32>,
+    next_seq: u32,
+    /// The messages that arrived ahead of a missing one
+    buffer: HashMap<u32, Vec<u8>>,
+    /// The messages in order, to be returned by [`Self::recv`]
+    ready: VecDeque<Vec<u8>>,
+    /// The number of messages the sender gave up on before they arrived
+    lost: usize,
+}
+
+impl ReliableReceiver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The epoch of the stream being received, if any.
+    pub fn epoch(&self) -> Option<u32> {
+        self.epoch
+    }
+
+    /// The number of messages the sender gave up on before they arrived.
+    pub fn lost(&self) -> usize {
+        self.lost
+    }
+
+    /// Switches to the stream of `epoch`, which is numbered from 0. It is up to the application
+    /// to decide which epochs to follow, since a delayed datagram can be of an old one.
+    pub fn restart(&mut self, epoch: u32) {
+        self.epoch = Some(epoch);
+        self.next_seq = 0;
+        self.buffer.clear();
+    }
+
+    /// Processes a [`Message::Reliable`]. Returns the number of messages newly found to be lost,
+    /// or `None` if it is not of the current epoch.
+    pub fn on_message(
+        &mut self,
+        epoch: u32,
+        base: u32,
+        messages: Vec<(u32, Vec<u8>)>,
+    ) -> Option<usize> {
+        if self.epoch != Some(epoch) {
+            return None;
+        }
+        let mut lost = 0;
+        if RECEIVE_WINDOW < base.wrapping_sub(self.next_seq) && seq_newer(base, self.next_seq) {
+            lost = base.wrapping_sub(self.next_seq) as usize - self.buffer.len();
+            self.flush_buffer();
+            self.next_seq = base;
+        }
+        while seq_newer(base, self.next_seq) {
+            match self.buffer.remove(&self.next_seq) {
+                Some(payload) => self.ready.push_back(payload),
+                None => lost += 1,
+            }
+            self.next_seq = self.next_seq.wrapping_add(1);
+        }
+        for (seq, payload) in messages {
+            if seq.wrapping_sub(self.next_seq) <= RECEIVE_WINDOW {
+                self.buffer.entry(seq).or_insert(payload);
+            }
+        }
+        while let Some(payload) = self.buffer.remove(&self.next_seq) {
+            self.ready.push_back(payload);
+            self.next_seq = self.next_seq.wrapping_add(1);
+        }
+        self.lost += lost;
+        Some(lost)
+    }
+
+    /// Makes all the buffered messages ready in order, skipping the missing ones.
+    fn flush_buffer(&mut self) {
+        let mut buffered: Vec<_> = self.buffer.drain().collect();
+        let next_seq = self.next_seq;
+        buffered.sort_by_key(|(seq, _)| seq.wrapping_sub(next_seq));
+        self.ready
+            .extend(buffered.into_iter().map(|(_, payload)| payload));
+    }
+
+    /// Returns the acknowledgement of the messages received so far in the current epoch.
+    pub fn ack(&self) -> Option<Message> {
+        let mask = (0..RECEIVE_WINDOW)
+            .filter(|i| {
+                let seq = self.next_seq.wrapping_add(i + 1);
+                self.buffer.contains_key(&seq)
+            })
+            .fold(0, |mask, i| mask | 1 << i);
+        Some(Message::ReliableAck {
+            epoch: self.epoch?,
+            next_seq: self.next_seq,
+            mask,
+        })
+    }
+
+    /// Returns the next message in order, if any.
+    pub fn recv(&mut self) -> Option<Vec<u8>> {
+        self.ready.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+
+    use super::*;
+    use crate::{protocol::DEFAULT_MTU, rng::Pcg32};
+
+    fn payload(i: u32) -> Vec<u8> {
+        // Vary the length so that the packing into datagrams varies
+        i.to_le_bytes().repeat(1 + i as usize % 7)
+    }
+
+    /// Sends `count` messages over a link that drops each datagram with `loss` and delivers
+    /// the rest in a random order, and returns the messages received in order.
+    fn transfer(count: u32, loss: f64, seed: u64) -> Vec<Vec<u8>> {
+        let mut rng = Pcg32::new(seed);
+        let mut sender = ReliableSender::new();
+        let mut receiver = ReliableReceiver::new();
+        receiver.restart(0);
+        sender.set_rtt(Duration::ZERO, Duration::ZERO);
+        for i in 0..count {
+            sender.send(payload(i));
+        }
+        let mut in_flight = vec![];
+        let mut received = vec![];
+        let start = Instant::now();
+        while !sender.is_empty() {
+            assert!(start.elapsed() < Duration::from_secs(20), "stalled");
+            while let Some(msg) = sender.message(DEFAULT_MTU) {
+                if loss <= rng.next_f64() {
+                    in_flight.push(msg);
+                }
+                // Duplicate some datagrams
+                if rng.next_f64() < 0.1 {
+                    if let Some(msg) = in_flight.last() {
+                        in_flight.push(msg.clone());
+                    }
+                }
+            }
+            // Deliver in a random order
+            while !in_flight.is_empty() {
+                let index = (rng.next_f64() * in_flight.len() as f64) as usize;
+                let Message::Reliable {
+                    epoch,
+                    base,
+                    messages,
+                } = in_flight.swap_remove(index)
+                else {
+                    unreachable!();
+                };
+                assert_eq!(receiver.on_message(epoch, base, messages), Some(0));
+                if loss <= rng.next_f64() {
+                    let Some(Message::ReliableAck {
+                        epoch,
+                        next_seq,
+                        mask,
+                    }) = receiver.ack()
+                    else {
+                        panic!("no ack");
+                    };
+                    sender.on_ack(epoch, next_seq, mask);
+                }
+            }
+            while let Some(payload) = receiver.recv() {
+                received.push(payload);
+            }
+            sleep(Duration::from_millis(5));
+        }
+        received
+    }
+
+    #[test]
+    fn delivers_in_order_without_loss() {
+        let received = transfer(500, 0., 1);
+        assert_eq!(received, (0..500).map(payload).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn delivers_exactly_once_in_order_under_loss_and_reordering() {
+        for seed in 0..3 {
+            let received = transfer(300, 0.3, seed);
+            assert_eq!(received, (0..300).map(payload).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn buffered_messages_are_not_resent() {
+        let mut sender = ReliableSender::new();
+        let mut receiver = ReliableReceiver::new();
+        receiver.restart(0);
+        for i in 0..4 {
+            sender.send(payload(i));
+        }
+        let Some(Message::Reliable {
+            epoch,
+            base,
+            mut messages,
+        }) = sender.message(DEFAULT_MTU)
+        else {
+            panic!("nothing sent");
+        };
+        assert_eq!(messages.len(), 4);
+        // The first one is lost
+        messages.remove(0);
+        receiver.on_message(epoch, base, messages);
+        assert!(receiver.recv().is_none());
+        let Some(Message::ReliableAck {
+            epoch,
+            next_seq,
+            mask,
+        }) = receiver.ack()
+        else {
+            panic!("no ack");
+        };
+        assert_eq!((next_seq, mask), (0, 0b111));
+        sender.on_ack(epoch, next_seq, mask);
+        assert_eq!(sender.pending().count(), 1);
+        sleep(MAX_RTO);
+        let Some(Message::Reliable { messages, .. }) = sender.message(DEFAULT_MTU) else {
+            panic!("not resent");
+        };
+        assert_eq!(messages, vec![(0, payload(0))]);
+    }
+
+    #[test]
+    fn messages_beyond_the_window_wait() {
+        let mut sender = ReliableSender::new();
+        for i in 0..RECEIVE_WINDOW * 2 {
+            sender.send(payload(i));
+        }
+        let mut sent = 0;
+        while let Some(Message::Reliable { messages, .. }) = sender.message(DEFAULT_MTU) {
+            sent += messages.len();
+        }
+        assert_eq!(sent, RECEIVE_WINDOW as usize + 1);
+    }
+
+    #[test]
+    fn restart_carries_over_unacknowledged_messages() {
+        let mut sender = ReliableSender::new();
+        let mut receiver = ReliableReceiver::new();
+        receiver.restart(0);
+        for i in 0..3 {
+            sender.send(payload(i));
+        }
+        // Everything is lost, and the receiver starts over with another epoch
+        while sender.message(DEFAULT_MTU).is_some() {}
+        receiver.restart(7);
+        sender.restart(7);
+        sender.send(payload(3));
+        let Some(Message::Reliable {
+            epoch,
+            base,
+            messages,
+        }) = sender.message(DEFAULT_MTU)
+        else {
+            panic!("nothing sent");
+        };
+        assert_eq!((epoch, base), (7, 0));
+        // A delayed datagram of the old epoch is ignored
+        assert_eq!(receiver.on_message(0, 0, vec![(0, payload(9))]), None);
+        assert_eq!(receiver.on_message(epoch, base, messages), Some(0));
+        let received: Vec<_> = std::iter::from_fn(|| receiver.recv()).collect();
+        assert_eq!(received, (0..4).map(payload).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn expired_messages_are_counted_as_lost() {
+        let mut sender = ReliableSender::new();
+        let mut receiver = ReliableReceiver::new();
+        receiver.restart(0);
+        for i in 0..5 {
+            sender.send(payload(i));
+        }
+        while sender.message(DEFAULT_MTU).is_some() {}
+        sleep(Duration::from_millis(20));
+        sender.expire(Duration::from_millis(10));
+        sender.send(payload(5));
+        let Some(Message::Reliable {
+            epoch,
+            base,
+            messages,
+        }) = sender.message(DEFAULT_MTU)
+        else {
+            panic!("nothing sent");
+        };
+        assert_eq!(receiver.on_message(epoch, base, messages), Some(5));
+        assert_eq!(receiver.lost(), 5);
+        assert_eq!(receiver.recv(), Some(payload(5)));
+    }
+}
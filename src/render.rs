@@ -1,9 +1,32 @@
 use eframe::{
     egui::{self, Painter, Response, Ui},
-    epaint::{pos2, vec2, Color32, PathShape, Pos2, Rect},
+    emath::RectTransform,
+    epaint::{pos2, vec2, Color32, PathShape, Pos2, Rect, Shape},
 };
 
-use crate::{object::AsObject, SCALE};
+use crate::{
+    divergence::DivergenceHistory,
+    impair::{ImpairmentConfig, ImpairmentStats},
+    object::AsObject,
+    stats::{StatsHistory, TimeSeries, HISTORY_LEN},
+    Object, SCALE,
+};
+
+/// The vertices of the triangle of an object heading to +x, relative to its position.
+const OBJECT_SHAPE: [Pos2; 3] = [pos2(10., 0.), pos2(-5., 5.), pos2(-5., -5.)];
+
+/// Returns the triangle of an object in screen coordinates, heading toward its velocity.
+fn object_shape(to_screen: &RectTransform, obj: &Object) -> Vec<Pos2> {
+    let angle = obj.velo[1].atan2(obj.velo[0]) as f32;
+    let (s, c) = angle.sin_cos();
+    let pos = pos2(obj.pos[0] as f32, obj.pos[1] as f32) * SCALE;
+    OBJECT_SHAPE
+        .iter()
+        .map(|ofs| {
+            to_screen.transform_pos(pos + vec2(ofs.x * c - ofs.y * s, ofs.x * s + ofs.y * c))
+        })
+        .collect()
+}
 
 pub fn render_objects(
     objs: &[impl AsObject],
@@ -13,28 +36,11 @@ pub fn render_objects(
 ) -> (Response, Painter) {
     let (response, painter) = ui.allocate_painter(ui.available_size(), egui::Sense::click());
 
-    let rotate = |pos: &Pos2, angle: f32| {
-        let c = angle.cos();
-        let s = angle.sin();
-        vec2(pos.x * c - pos.y * s, pos.x * s + pos.y * c)
-    };
-
     let to_screen = egui::emath::RectTransform::from_to(
         Rect::from_min_size(Pos2::ZERO, response.rect.size()),
         response.rect,
     );
 
-    let convert_to_poly = |vertices: &[Pos2], pos: Pos2, angle: f32, color: Color32| {
-        PathShape::convex_polygon(
-            vertices
-                .into_iter()
-                .map(|ofs| to_screen.transform_pos(pos + rotate(ofs, angle)))
-                .collect(),
-            color,
-            (1., Color32::BLACK),
-        )
-    };
-
     if show_updates {
         for as_obj in objs.iter() {
             let obj = as_obj.as_ref();
@@ -50,20 +56,204 @@ pub fn render_objects(
     }
 
     for (i, as_obj) in objs.iter().enumerate() {
-        let obj = as_obj.as_ref();
         let color = if Some(i) == selected {
             Color32::WHITE
         } else {
             as_obj.get_color()
         };
-        let heading = obj.velo[1].atan2(obj.velo[0]) as f32;
-        painter.add(convert_to_poly(
-            &[pos2(10., 0.), pos2(-5., 5.), pos2(-5., -5.)],
-            pos2(obj.pos[0] as f32, obj.pos[1] as f32) * SCALE,
-            heading,
+        painter.add(PathShape::convex_polygon(
+            object_shape(&to_screen, as_obj.as_ref()),
             color,
+            (1., Color32::BLACK),
         ));
     }
 
     (response, painter)
 }
+
+/// Renders a second set of objects, such as the sender's truth, as faint outlines on top of
+/// the canvas of [`render_objects`], with lines connecting each of them to its counterpart in
+/// `replicas`.
+pub fn render_ghosts(
+    ghosts: &[Option<Object>],
+    replicas: &[impl AsRef<Object>],
+    response: &Response,
+    painter: &Painter,
+) {
+    let to_screen = egui::emath::RectTransform::from_to(
+        Rect::from_min_size(Pos2::ZERO, response.rect.size()),
+        response.rect,
+    );
+    let color = Color32::from_rgba_unmultiplied(0, 0, 0, 95);
+    for (ghost, replica) in ghosts.iter().zip(replicas.iter()) {
+        let Some(ghost) = ghost else {
+            continue;
+        };
+        let replica = replica.as_ref();
+        let to_pos = |obj: &Object| {
+            to_screen.transform_pos(pos2(obj.pos[0] as f32, obj.pos[1] as f32) * SCALE)
+        };
+        painter.line_segment(
+            [to_pos(replica), to_pos(ghost)],
+            (1., Color32::from_rgba_unmultiplied(255, 0, 0, 95)),
+        );
+        painter.add(PathShape::closed_line(
+            object_shape(&to_screen, ghost),
+            (1., color),
+        ));
+    }
+}
+
+/// Renders the statistics of a link as labels and rolling plots, intended for the side panel.
+pub fn render_stats(ui: &mut Ui, stats: &StatsHistory) {
+    let latest = &stats.latest;
+    let count = |v: Option<usize>| v.map_or_else(|| "N/A".to_string(), |v| v.to_string());
+    let ms = |v: Option<f64>| v.map_or_else(|| "N/A".to_string(), |v| format!("{v:.1} ms"));
+
+    ui.label(format!(
+        "Packets: {} ({:.0}/s)",
+        latest.packets,
+        stats.packets_per_sec.last().unwrap_or(0.)
+    ));
+    plot_series(ui, &stats.packets_per_sec, Color32::from_rgb(0, 127, 255));
+    ui.label(format!(
+        "Bytes: {} ({:.0}/s)",
+        latest.bytes,
+        stats.bytes_per_sec.last().unwrap_or(0.)
+    ));
+    plot_series(ui, &stats.bytes_per_sec, Color32::from_rgb(0, 127, 127));
+    ui.label(format!(
+        "Loss: {}",
+        latest
+            .loss_rate
+            .map_or_else(|| "N/A".to_string(), |v| format!("{:.1} %", v * 100.))
+    ));
+    plot_series(ui, &stats.loss_percent, Color32::from_rgb(255, 0, 0));
+    ui.label(format!("Duplicates: {}", count(latest.duplicates)));
+    ui.label(format!("Out of order: {}", count(latest.out_of_order)));
+    ui.label(format!(
+        "RTT: {}",
+        ms(latest.rtt.map(|v| v.as_secs_f64() * 1e3))
+    ));
+    plot_series(ui, &stats.rtt_ms, Color32::from_rgb(127, 0, 255));
+    ui.label(format!(
+        "Jitter: {}",
+        ms(latest.jitter.map(|v| v.as_secs_f64() * 1e3))
+    ));
+    plot_series(ui, &stats.jitter_ms, Color32::from_rgb(127, 127, 0));
+}
+
+/// Renders the aggregate errors between the sender and the receiver, intended for the side panel.
+pub fn render_divergence(ui: &mut Ui, history: &DivergenceHistory) {
+    let Some(latest) = history.latest else {
+        ui.label("No ground truth received");
+        return;
+    };
+    ui.label(format!("Measured objects: {}", latest.samples));
+    ui.label(format!("Mean error: {:.4}", latest.mean));
+    plot_series(ui, &history.mean, Color32::from_rgb(0, 127, 255));
+    ui.label(format!("Max error: {:.4}", latest.max));
+    plot_series(ui, &history.max, Color32::from_rgb(255, 0, 0));
+    ui.label(format!(
+        "Percentiles: p50 {:.4}, p90 {:.4}, p99 {:.4}",
+        latest.p50, latest.p90, latest.p99
+    ));
+}
+
+fn plot_series(ui: &mut Ui, series: &TimeSeries, color: Color32) {
+    let (response, painter) =
+        ui.allocate_painter(vec2(ui.available_width(), 40.), egui::Sense::hover());
+    let rect = response.rect;
+    painter.rect_filled(rect, 0., Color32::from_gray(240));
+    let max = series.max();
+    if max <= 0. {
+        return;
+    }
+    let points = series
+        .values()
+        .enumerate()
+        .map(|(i, v)| {
+            pos2(
+                rect.left() + i as f32 / (HISTORY_LEN - 1) as f32 * rect.width(),
+                rect.bottom() - (v / max) as f32 * rect.height(),
+            )
+        })
+        .collect();
+    painter.add(Shape::line(points, (1., color)));
+    painter.text(
+        rect.right_top(),
+        egui::Align2::RIGHT_TOP,
+        format!("{max:.1}"),
+        egui::FontId::proportional(10.),
+        Color32::DARK_GRAY,
+    );
+}
+
+/// Renders sliders to control the network impairment simulator, and its counters.
+pub fn render_impairment(
+    ui: &mut Ui,
+    config: &mut ImpairmentConfig,
+    stats: (ImpairmentStats, ImpairmentStats),
+) {
+    for (label, value) in [
+        ("Loss:", &mut config.loss),
+        ("Duplicate:", &mut config.duplicate),
+        ("Reorder:", &mut config.reorder),
+        ("Corrupt:", &mut config.corrupt),
+    ] {
+        ui.label(label);
+        ui.add(egui::widgets::Slider::new(value, (0.)..=1.));
+    }
+    ui.label("Latency (ms):");
+    ui.add(egui::widgets::Slider::new(&mut config.latency, (0.)..=500.));
+    ui.label("Jitter (ms):");
+    ui.add(egui::widgets::Slider::new(&mut config.jitter, (0.)..=200.));
+    for (label, stats) in [("Outbound", stats.0), ("Inbound", stats.1)] {
+        ui.label(format!(
+            "{label}: {} passed, {} dropped, {} duplicated, {} reordered, {} corrupted",
+            stats.passed, stats.dropped, stats.duplicated, stats.reordered, stats.corrupted
+        ));
+    }
+}
+
+/// What is requested with the buttons of [`render_config_file`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfigFileAction {
+    Save,
+    Load,
+}
+
+impl ConfigFileAction {
+    pub fn verb(&self) -> &'static str {
+        match self {
+            Self::Save => "save",
+            Self::Load => "load",
+        }
+    }
+}
+
+/// Renders the path of a config file to edit, the buttons to save and load it, and the result
+/// of the last action.
+pub fn render_config_file(
+    ui: &mut Ui,
+    path: &mut String,
+    status: &str,
+) -> Option<ConfigFileAction> {
+    ui.horizontal(|ui| {
+        ui.label("File:");
+        ui.text_edit_singleline(path);
+    });
+    let mut action = None;
+    ui.horizontal(|ui| {
+        if ui.button("Save config").clicked() {
+            action = Some(ConfigFileAction::Save);
+        }
+        if ui.button("Load config").clicked() {
+            action = Some(ConfigFileAction::Load);
+        }
+    });
+    if !status.is_empty() {
+        ui.label(status);
+    }
+    action
+}
@@ -0,0 +1,115 @@
+//! A small portable pseudo random number generator owned by this crate.
+//!
+//! The lockstep mode requires both peers to draw exactly the same sequence of numbers
+//! from the same seed on any platform, so we do not rely on the generators of `rand`,
+//! whose algorithms may change between versions.
+
+use rand::RngCore;
+
+/// PCG-XSH-RR with 64-bit state and 32-bit output, as described in <https://www.pcg-random.org>.
+#[derive(Clone, Debug)]
+pub struct Pcg32 {
+    state: u64,
+    inc: u64,
+}
+
+impl Pcg32 {
+    const MULTIPLIER: u64 = 6364136223846793005;
+    const DEFAULT_STREAM: u64 = 0xda3e39cb94b95bdb;
+
+    pub fn new(seed: u64) -> Self {
+        Self::with_stream(seed, Self::DEFAULT_STREAM)
+    }
+
+    /// Seeds the generator like `pcg32_srandom_r` of the reference implementation.
+    /// Generators on different `stream`s give different sequences from the same seed.
+    pub fn with_stream(seed: u64, stream: u64) -> Self {
+        let mut rng = Self {
+            state: 0,
+            inc: (stream << 1) | 1,
+        };
+        rng.step();
+        rng.state = rng.state.wrapping_add(seed);
+        rng.step();
+        rng
+    }
+
+    fn step(&mut self) {
+        self.state = self
+            .state
+            .wrapping_mul(Self::MULTIPLIER)
+            .wrapping_add(self.inc);
+    }
+
+    pub fn next_f64(&mut self) -> f64 {
+        unit_f64(self)
+    }
+}
+
+/// Returns a number uniformly distributed in `[0, 1)` with 53 bits of precision.
+pub fn unit_f64(rng: &mut (impl RngCore + ?Sized)) -> f64 {
+    (rng.next_u64() >> 11) as f64 * (1. / (1u64 << 53) as f64)
+}
+
+impl RngCore for Pcg32 {
+    fn next_u32(&mut self) -> u32 {
+        let old = self.state;
+        self.step();
+        let xorshifted = (((old >> 18) ^ old) >> 27) as u32;
+        xorshifted.rotate_right((old >> 59) as u32)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let lo = self.next_u32() as u64;
+        let hi = self.next_u32() as u64;
+        (hi << 32) | lo
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(4) {
+            let bytes = self.next_u32().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_the_reference_implementation() {
+        // The first outputs of `pcg32_srandom_r(&rng, 42, 54)` in the reference `pcg32-demo`
+        let mut rng = Pcg32::with_stream(42, 54);
+        let outputs: Vec<u32> = (0..6).map(|_| rng.next_u32()).collect();
+        assert_eq!(
+            outputs,
+            [0xa15c02b7, 0x7b47f409, 0xba1d3330, 0x83d2f293, 0xbfa4784b, 0xcbed606e]
+        );
+    }
+
+    #[test]
+    fn seeds_and_streams_are_independent() {
+        let first = |mut rng: Pcg32| rng.next_u64();
+        assert_eq!(first(Pcg32::new(1)), first(Pcg32::new(1)));
+        assert_ne!(first(Pcg32::new(1)), first(Pcg32::new(2)));
+        assert_ne!(
+            first(Pcg32::with_stream(1, 1)),
+            first(Pcg32::with_stream(1, 2))
+        );
+    }
+
+    #[test]
+    fn unit_f64_is_in_range() {
+        let mut rng = Pcg32::new(0);
+        for _ in 0..10000 {
+            let v = rng.next_f64();
+            assert!((0. ..1.).contains(&v));
+        }
+    }
+}
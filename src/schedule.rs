@@ -0,0 +1,151 @@
+//! A priority accumulator to decide which objects to send under a bandwidth budget.
+//!
+//! Every object accumulates priority over time at a rate that depends on how important it
+//! is to update: how long it has not been sent, how far off the receiver's prediction is,
+//! how close it is to a point of interest and whether it is selected by the user.
+//! Each tick, the objects with the highest accumulated priority are sent as long as the
+//! budget allows, and their priority is reset. Objects of low importance still get their
+//! turn eventually, because their priority keeps growing while they wait.
+
+use std::time::Instant;
+
+use crate::{Object, SPACE_WIDTH};
+
+/// The distance from the point of interest within which objects get extra priority.
+pub const POI_RADIUS: f64 = 2.;
+
+/// The upper limit of the error to accumulate, so that an infinite error does not
+/// make the priority infinite.
+const MAX_ERROR: f64 = SPACE_WIDTH;
+
+/// The longest time span of budget that can be saved up, in seconds.
+const MAX_CREDIT_TIME: f64 = 0.1;
+
+/// The rates of priority accumulation per second.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PriorityWeights {
+    /// The priority every object accumulates regardless of its state
+    pub staleness: f64,
+    /// The priority per unit of prediction error, see [`crate::reckoning::DeadReckoning::error`]
+    pub error: f64,
+    /// The priority of an object at the point of interest, decreasing linearly to zero at
+    /// [`POI_RADIUS`]
+    pub proximity: f64,
+    /// The priority of the object selected by the user
+    pub selected: f64,
+}
+
+impl Default for PriorityWeights {
+    fn default() -> Self {
+        Self {
+            staleness: 1.,
+            error: 20.,
+            proximity: 5.,
+            selected: 50.,
+        }
+    }
+}
+
+/// The things the user is interested in, which the scheduler favors.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Interest {
+    pub poi: Option<[f64; 2]>,
+    pub selected: Option<usize>,
+}
+
+/// The priority accumulator with a token bucket of the bandwidth budget.
+pub struct Scheduler {
+    priorities: Vec<f64>,
+    /// The number of bytes that can be sent now
+    credit: f64,
+    last_tick: Instant,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self {
+            priorities: vec![],
+            credit: 0.,
+            last_tick: Instant::now(),
+        }
+    }
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn resize(&mut self, num_objects: usize) {
+        self.priorities.resize(num_objects, 0.);
+    }
+
+    /// Forgets the object `index` and moves the last one into its place.
+    pub fn swap_remove(&mut self, index: usize) {
+        self.priorities.swap_remove(index);
+    }
+
+    pub fn priority(&self, index: usize) -> Option<f64> {
+        self.priorities.get(index).copied()
+    }
+
+    /// Accumulates the priorities for the time since the last tick and adds the budget of
+    /// `bandwidth` bytes per second for the same time. Returns the number of bytes that can
+    /// be sent in this tick, which can be saved up to at least `min_credit` bytes.
+    pub fn tick(
+        &mut self,
+        objs: &[Object],
+        mut error: impl FnMut(usize, &Object) -> f64,
+        interest: &Interest,
+        weights: &PriorityWeights,
+        bandwidth: f64,
+        min_credit: usize,
+    ) -> usize {
+        let now = Instant::now();
+        let dt = (now - self.last_tick).as_secs_f64();
+        self.last_tick = now;
+        self.credit = (self.credit + bandwidth * dt)
+            .min((bandwidth * MAX_CREDIT_TIME).max(min_credit as f64));
+
+        for (index, (obj, priority)) in objs.iter().zip(self.priorities.iter_mut()).enumerate() {
+            let mut rate = weights.staleness;
+            if 0. < weights.error {
+                rate += weights.error * error(index, obj).min(MAX_ERROR);
+            }
+            if let Some(poi) = interest.poi {
+                let dist = ((obj.pos[0] - poi[0]).powi(2) + (obj.pos[1] - poi[1]).powi(2)).sqrt();
+                rate += weights.proximity * (1. - dist / POI_RADIUS).max(0.);
+            }
+            if interest.selected == Some(index) {
+                rate += weights.selected;
+            }
+            *priority += rate * dt;
+        }
+        self.credit.max(0.) as usize
+    }
+
+    /// Returns the indices of objects with positive priority, highest first, up to
+    /// `max_objects` if given.
+    pub fn order(&self, max_objects: Option<usize>) -> Vec<usize> {
+        let mut order: Vec<_> = (0..self.priorities.len())
+            .filter(|i| 0. < self.priorities[*i])
+            .collect();
+        order.sort_by(|a, b| self.priorities[*b].total_cmp(&self.priorities[*a]));
+        if let Some(max_objects) = max_objects {
+            order.truncate(max_objects);
+        }
+        order
+    }
+
+    /// Resets the priority of a sent object.
+    pub fn on_sent(&mut self, index: usize) {
+        if let Some(priority) = self.priorities.get_mut(index) {
+            *priority = 0.;
+        }
+    }
+
+    /// Consumes the budget by the bytes actually sent.
+    pub fn consume(&mut self, bytes: usize) {
+        self.credit -= bytes as f64;
+    }
+}
@@ -79,8 +79,9 @@ impl SortMap {
             hash_entry.particle_idx = i;
             hash_entry.cell_hash = cell_hash;
         }
-        self.hash_table
-            .sort_unstable_by_key(|entry| entry.cell_hash);
+        // A stable sort keeps the objects in a cell in the order of their indices, so that
+        // the scan order, and hence the rounding of the sums of forces, is reproducible.
+        self.hash_table.sort_by_key(|entry| entry.cell_hash);
 
         for i in 0..objs.len() {
             let first = self
@@ -0,0 +1,204 @@
+//! Network statistics shown in the side panels of both GUIs.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use crate::protocol::seq_newer;
+
+/// The number of samples kept in a [`TimeSeries`].
+pub const HISTORY_LEN: usize = 120;
+/// The interval between samples of the time series.
+pub const SAMPLE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The counters and estimates of a link at some point in time.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StatsSnapshot {
+    pub packets: usize,
+    pub bytes: usize,
+    /// The ratio of lost datagrams, if it can be estimated.
+    pub loss_rate: Option<f64>,
+    pub duplicates: Option<usize>,
+    pub out_of_order: Option<usize>,
+    pub rtt: Option<Duration>,
+    pub jitter: Option<Duration>,
+}
+
+/// The receiver side counters of incoming datagrams.
+#[derive(Default)]
+pub struct ReceiveStats {
+    packets: usize,
+    bytes: usize,
+    duplicates: usize,
+    out_of_order: usize,
+    first_seq: Option<u32>,
+    latest_seq: u32,
+    /// Bit `i` is set if `latest_seq - 1 - i` has been received, to detect duplicates.
+    mask: u64,
+    /// The previous relative transit time in milliseconds, for the jitter estimate.
+    last_transit: Option<f64>,
+    /// The interarrival jitter in milliseconds, as defined in RFC 3550.
+    jitter: f64,
+    rtt: Option<Duration>,
+}
+
+impl ReceiveStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a datagram with a sequence number and a timestamp, where `local_time` is the
+    /// time of arrival in milliseconds in the receiver's own clock.
+    pub fn on_packet(&mut self, seq: u32, timestamp: u32, bytes: usize, local_time: f64) {
+        self.packets += 1;
+        self.bytes += bytes;
+
+        let transit = local_time - timestamp as f64;
+        if let Some(last_transit) = self.last_transit {
+            self.jitter += ((transit - last_transit).abs() - self.jitter) / 16.;
+        }
+        self.last_transit = Some(transit);
+
+        if self.first_seq.is_none() {
+            self.first_seq = Some(seq);
+            self.latest_seq = seq;
+            return;
+        }
+        if seq == self.latest_seq {
+            self.duplicates += 1;
+        } else if seq_newer(seq, self.latest_seq) {
+            let shift = seq.wrapping_sub(self.latest_seq);
+            self.mask = if shift <= 64 {
+                (self.mask << (shift - 1) << 1) | 1 << (shift - 1)
+            } else {
+                0
+            };
+            self.latest_seq = seq;
+        } else {
+            self.out_of_order += 1;
+            let offset = self.latest_seq.wrapping_sub(seq);
+            if offset <= 64 {
+                let bit = 1 << (offset - 1);
+                if self.mask & bit != 0 {
+                    self.duplicates += 1;
+                    // A duplicate is not counted as out of order
+                    self.out_of_order -= 1;
+                }
+                self.mask |= bit;
+            }
+        }
+    }
+
+    pub fn on_rtt_sample(&mut self, sample: Duration) {
+        self.rtt = Some(match self.rtt {
+            Some(rtt) => (rtt * 7 + sample) / 8,
+            None => sample,
+        });
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        let loss_rate = self.first_seq.map(|first_seq| {
+            let expected = self.latest_seq.wrapping_sub(first_seq) as f64 + 1.;
+            let received = (self.packets - self.duplicates) as f64;
+            ((expected - received) / expected).max(0.)
+        });
+        StatsSnapshot {
+            packets: self.packets,
+            bytes: self.bytes,
+            loss_rate,
+            duplicates: Some(self.duplicates),
+            out_of_order: Some(self.out_of_order),
+            rtt: self.rtt,
+            jitter: Some(Duration::from_secs_f64(self.jitter * 1e-3)),
+        }
+    }
+}
+
+/// A rolling history of values sampled at a fixed interval.
+#[derive(Default)]
+pub struct TimeSeries {
+    values: VecDeque<f64>,
+}
+
+impl TimeSeries {
+    pub fn push(&mut self, value: f64) {
+        if HISTORY_LEN <= self.values.len() {
+            self.values.pop_front();
+        }
+        self.values.push_back(value);
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = f64> + '_ {
+        self.values.iter().copied()
+    }
+
+    pub fn last(&self) -> Option<f64> {
+        self.values.back().copied()
+    }
+
+    pub fn max(&self) -> f64 {
+        self.values.iter().copied().fold(0., f64::max)
+    }
+}
+
+/// Periodically sampled statistics of a link, to be rendered with
+/// [`crate::render_stats`].
+pub struct StatsHistory {
+    last_sample: Instant,
+    last: StatsSnapshot,
+    pub latest: StatsSnapshot,
+    pub packets_per_sec: TimeSeries,
+    pub bytes_per_sec: TimeSeries,
+    pub loss_percent: TimeSeries,
+    pub rtt_ms: TimeSeries,
+    pub jitter_ms: TimeSeries,
+}
+
+impl Default for StatsHistory {
+    fn default() -> Self {
+        Self {
+            last_sample: Instant::now(),
+            last: StatsSnapshot::default(),
+            latest: StatsSnapshot::default(),
+            packets_per_sec: TimeSeries::default(),
+            bytes_per_sec: TimeSeries::default(),
+            loss_percent: TimeSeries::default(),
+            rtt_ms: TimeSeries::default(),
+            jitter_ms: TimeSeries::default(),
+        }
+    }
+}
+
+impl StatsHistory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Updates the latest values and appends to the time series if [`SAMPLE_INTERVAL`]
+    /// has passed since the last sample.
+    pub fn update(&mut self, snapshot: StatsSnapshot) {
+        self.latest = snapshot;
+        let now = Instant::now();
+        let elapsed = (now - self.last_sample).as_secs_f64();
+        if elapsed < SAMPLE_INTERVAL.as_secs_f64() {
+            return;
+        }
+        let rate = |cur: usize, last: usize| cur.saturating_sub(last) as f64 / elapsed;
+        self.packets_per_sec
+            .push(rate(snapshot.packets, self.last.packets));
+        self.bytes_per_sec
+            .push(rate(snapshot.bytes, self.last.bytes));
+        self.loss_percent
+            .push(snapshot.loss_rate.unwrap_or(0.) * 100.);
+        self.rtt_ms
+            .push(snapshot.rtt.map_or(0., |rtt| rtt.as_secs_f64() * 1e3));
+        self.jitter_ms.push(
+            snapshot
+                .jitter
+                .map_or(0., |jitter| jitter.as_secs_f64() * 1e3),
+        );
+        self.last = snapshot;
+        self.last_sample = now;
+    }
+}
@@ -0,0 +1,1579 @@
+//! The synchronization logic of both ends, independent of the GUI and the transport.
+//!
+//! [`PatchSender`] and [`PatchReceiver`] only talk to the network through a [`Transport`],
+//! so the whole pipeline can run in a single process over a [`crate::transport::MemoryNetwork`].
+//!
+//! Objects are identified by [`EntityId`]s on the wire, and spawned and despawned with
+//! [`LifecycleEvent`]s, which are sent over a [`crate::reliable`] channel to each client.
+//! The stream starts over with each snapshot, whose id becomes the epoch, because the receiver
+//! requesting it may have restarted and lost track of the numbers. Applying an event carried
+//! over to the new epoch twice is harmless. Groups do not acknowledge, so the events are
+//! repeated to them for [`GROUP_EVENT_LIFETIME`].
+//!
+//! The same channel carries the [`BoidParams`] whenever they change, as well as at the start of
+//! each stream, so that the receivers predict the objects with the sender's model. Since they
+//! are sent right away with the next burst, a change reaches the receivers within a round trip
+//! unless the datagram is lost.
+
+use std::{
+    collections::{HashSet, VecDeque},
+    io,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    ack::{AckWindow, LinkStats, LinkTracker},
+    codec::Codec,
+    delta::{BaselineHistory, BaselineStore, ObjectDelta},
+    entity::{EntityId, EntityMap, LifecycleEvent},
+    lockstep::TickInput,
+    object::BoidParams,
+    protocol::{
+        seq_newer, ChannelMessage, Message, Patch, PatchPacker, DEFAULT_MTU, MAX_DATAGRAM_SIZE,
+    },
+    reckoning::DeadReckoning,
+    reliable::{ReliableReceiver, ReliableSender},
+    schedule::{Interest, PriorityWeights, Scheduler},
+    stats::{ReceiveStats, StatsSnapshot},
+    transport::Transport,
+    Object, ObjectWrap,
+};
+
+/// The interval to send acknowledgements back to the sender.
+pub const ACK_INTERVAL: Duration = Duration::from_millis(20);
+/// The interval to measure the round-trip time to the sender.
+pub const PING_INTERVAL: Duration = Duration::from_millis(500);
+/// How long the receiver waits for the next part of a snapshot before requesting it again.
+pub const RESYNC_TIMEOUT: Duration = Duration::from_millis(500);
+/// The jump of sequence numbers beyond which the receiver assumes it has missed too much,
+/// or the sender has restarted, and requests a snapshot.
+pub const RESYNC_SEQ_GAP: u32 = 1000;
+/// How long the sender keeps a registered client that has not sent anything.
+/// The receivers send pings at [`PING_INTERVAL`], which keep them alive.
+pub const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+/// The interval for the receiver to send hellos until the sender welcomes it.
+pub const HELLO_INTERVAL: Duration = Duration::from_millis(500);
+/// How long the receiver waits for any datagram from the sender before it assumes that
+/// the sender has forgotten it and says hello again.
+pub const SENDER_TIMEOUT: Duration = Duration::from_secs(2);
+
+fn elapsed_ms(start: Instant) -> u32 {
+    start.elapsed().as_millis() as u32
+}
+
+/// How long the lifecycle events are repeated to a group, which does not acknowledge them.
+pub const GROUP_EVENT_LIFETIME: Duration = Duration::from_secs(1);
+
+/// The number of recently despawned objects the receiver remembers, so that delayed
+/// snapshots do not bring them back.
+const DESPAWN_HISTORY: usize = 1024;
+
+/// The default bandwidth budget in bytes per second.
+pub const DEFAULT_BANDWIDTH: f64 = 50_000.;
+
+/// The parameters of sending that can be changed on the fly.
+#[derive(Clone, Copy, Debug)]
+pub struct SendOptions {
+    pub mtu: usize,
+    pub codec: Codec,
+    /// The budget of bytes per second to send
+    pub bandwidth: f64,
+    /// The maximum number of objects to patch in a burst, if any, regardless of the budget
+    pub burst_objs: Option<usize>,
+    pub weights: PriorityWeights,
+    pub interest: Interest,
+    /// If set, the objects whose prediction on the receiver is off by more than this
+    /// threshold accumulate priority in proportion to the error.
+    /// See [`DeadReckoning::error`] for the definition of the error.
+    pub error_threshold: Option<f64>,
+}
+
+impl Default for SendOptions {
+    fn default() -> Self {
+        Self {
+            mtu: DEFAULT_MTU,
+            codec: Codec::Full,
+            bandwidth: DEFAULT_BANDWIDTH,
+            burst_objs: None,
+            weights: PriorityWeights::default(),
+            interest: Interest::default(),
+            error_threshold: None,
+        }
+    }
+}
+
+/// A summary of the synchronization with a client, to be shown in the GUI.
+#[derive(Clone, Copy, Debug)]
+pub struct ClientInfo {
+    pub id: u32,
+    pub addr: SocketAddr,
+    /// Whether the client was added with [`PatchSender::add_client`] rather than registered by
+    /// a hello, in which case it never times out
+    pub fixed: bool,
+    /// Whether the address is a multicast group or a broadcast address, see
+    /// [`PatchSender::add_group`]
+    pub group: bool,
+    /// The time since the last datagram from the client
+    pub idle: Duration,
+    pub link: LinkStats,
+    /// The number of objects of which the client has confirmed some state
+    pub confirmed: usize,
+    /// The number of objects whose prediction on the client exceeded the error threshold
+    /// at the last burst
+    pub diverged: usize,
+    /// The number of objects sent so far in the snapshot being sent, if any
+    pub snapshot_progress: Option<usize>,
+    pub packets: usize,
+    pub bytes: usize,
+}
+
+/// A snapshot being sent to a client.
+struct SnapshotProgress {
+    id: u32,
+    /// The objects not sent yet, in the reverse order
+    pending: Vec<EntityId>,
+    sent: usize,
+}
+
+/// The state of the synchronization with a receiver.
+struct Client {
+    id: u32,
+    addr: SocketAddr,
+    fixed: bool,
+    group: bool,
+    last_seen: Instant,
+    /// The sequence numbers are per client, so that each one sees a contiguous sequence
+    seq: u32,
+    history: BaselineHistory,
+    link: LinkTracker,
+    reckoning: DeadReckoning,
+    /// The number of objects over the error threshold at the last burst
+    num_diverged: usize,
+    scheduler: Scheduler,
+    /// The channel of the lifecycle events and the parameters
+    channel: ReliableSender,
+    snapshot: Option<SnapshotProgress>,
+    /// The id of the last snapshot requested, to ignore duplicate requests
+    last_snapshot_id: Option<u32>,
+    packets: usize,
+    bytes: usize,
+}
+
+impl Client {
+    fn new(id: u32, addr: SocketAddr, fixed: bool, group: bool) -> Self {
+        Self {
+            id,
+            addr,
+            fixed,
+            group,
+            last_seen: Instant::now(),
+            seq: 0,
+            history: BaselineHistory::new(),
+            link: LinkTracker::new(),
+            reckoning: DeadReckoning::new(),
+            num_diverged: 0,
+            scheduler: Scheduler::new(),
+            channel: ReliableSender::new(),
+            snapshot: None,
+            last_snapshot_id: None,
+            packets: 0,
+            bytes: 0,
+        }
+    }
+
+    fn info(&self) -> ClientInfo {
+        ClientInfo {
+            id: self.id,
+            addr: self.addr,
+            fixed: self.fixed,
+            group: self.group,
+            idle: self.last_seen.elapsed(),
+            link: self.link.stats(),
+            confirmed: self.history.num_confirmed(),
+            diverged: self.num_diverged,
+            snapshot_progress: self.snapshot.as_ref().map(|snapshot| snapshot.sent),
+            packets: self.packets,
+            bytes: self.bytes,
+        }
+    }
+
+    fn on_resync_request(&mut self, snapshot_id: u32, entities: &EntityMap, params: &BoidParams) {
+        if self.last_snapshot_id != Some(snapshot_id) {
+            self.last_snapshot_id = Some(snapshot_id);
+            self.snapshot = Some(SnapshotProgress {
+                id: snapshot_id,
+                pending: entities.ids().iter().rev().copied().collect(),
+                sent: 0,
+            });
+            // The receiver may have lost all the baselines and replicas
+            self.history = BaselineHistory::new();
+            self.reckoning = DeadReckoning::new();
+            // The other receivers of a group keep following the current stream
+            if !self.group {
+                self.channel.restart(snapshot_id);
+            }
+            // The receiver may not have the parameters either
+            self.channel.send(ChannelMessage::Params(*params).encode());
+        }
+    }
+
+    fn resize(&mut self, num_objects: usize) {
+        self.history.resize(num_objects);
+        self.reckoning.resize(num_objects);
+        self.scheduler.resize(num_objects);
+    }
+
+    /// Forgets the object `index`, which was one of `num_objects`, and tells the receiver.
+    fn despawn(&mut self, index: usize, id: EntityId, num_objects: usize) {
+        self.resize(num_objects);
+        self.history.swap_remove(index);
+        self.reckoning.swap_remove(index);
+        self.scheduler.swap_remove(index);
+        self.channel
+            .send(ChannelMessage::Lifecycle(LifecycleEvent::Despawn { id }).encode());
+    }
+
+    /// The objects whose spawns have not been acknowledged, of which the receiver cannot
+    /// decode patches yet.
+    fn unacked_spawns(&self) -> HashSet<EntityId> {
+        self.channel
+            .pending()
+            .filter_map(|payload| match ChannelMessage::decode(payload) {
+                Ok(ChannelMessage::Lifecycle(LifecycleEvent::Spawn { id, .. })) => Some(id),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn send_burst(
+        &mut self,
+        transport: &mut impl Transport,
+        objs: &[Object],
+        entities: &EntityMap,
+        params: &BoidParams,
+        options: &SendOptions,
+        timestamp: u32,
+    ) -> io::Result<usize> {
+        self.resize(objs.len());
+
+        let weights = PriorityWeights {
+            error: options
+                .error_threshold
+                .map_or(0., |_| options.weights.error),
+            ..options.weights
+        };
+        if options.error_threshold.is_some() {
+            self.reckoning.predict(entities.ids(), params, timestamp);
+        }
+        let threshold = options.error_threshold.unwrap_or(f64::INFINITY);
+        let reckoning = &self.reckoning;
+        let mut num_diverged = 0;
+        let budget = self.scheduler.tick(
+            objs,
+            |index, obj| {
+                let error = reckoning.error(index, obj);
+                if threshold < error {
+                    num_diverged += 1;
+                    error
+                } else {
+                    0.
+                }
+            },
+            &options.interest,
+            &weights,
+            options.bandwidth,
+            options.mtu,
+        );
+        self.num_diverged = num_diverged;
+
+        let mut amt = 0;
+        if self.group {
+            self.channel.expire(GROUP_EVENT_LIFETIME);
+        } else if let Some(rtt) = self.link.stats().rtt {
+            self.channel.set_rtt(rtt, self.link.stats().rtt_var);
+        }
+        // The events go first regardless of the budget, so that the spawns precede the patches
+        if let Some(msg) = self.channel.message(options.mtu) {
+            self.packets += 1;
+            amt += transport.send_to(&msg.encode(0, timestamp), self.addr)?;
+        }
+
+        let mut msgs = vec![];
+        let mut used = amt;
+        // The states as the receiver would reconstruct them, in the same order as the messages
+        let mut sent_states = VecDeque::new();
+        if let Some(snapshot) = &mut self.snapshot {
+            let count = snapshot_capacity(options.mtu);
+            snapshot.pending.retain(|id| entities.contains(*id));
+            while !snapshot.pending.is_empty() {
+                let num_states = count.min(snapshot.pending.len());
+                let size = Message::SNAPSHOT_OVERHEAD + num_states * Message::SNAPSHOT_STATE_SIZE;
+                if budget < used + size {
+                    break;
+                }
+                used += size;
+                let ids = snapshot
+                    .pending
+                    .split_off(snapshot.pending.len() - num_states);
+                let mut states = Vec::with_capacity(num_states);
+                for id in ids.into_iter().rev() {
+                    let Some(index) = entities.slot(id) else {
+                        continue;
+                    };
+                    let obj = objs[index];
+                    self.reckoning.on_sent(index, obj);
+                    self.scheduler.on_sent(index);
+                    sent_states.push_back((index, obj));
+                    states.push((id, obj));
+                }
+                snapshot.sent += num_states;
+                msgs.push(Message::Snapshot {
+                    snapshot_id: snapshot.id,
+                    num_objects: objs.len(),
+                    states,
+                });
+            }
+            if snapshot.pending.is_empty() {
+                self.snapshot = None;
+            }
+        }
+
+        // Patches would take the budget the rest of the snapshot needs, which covers them anyway
+        if self.snapshot.is_none() {
+            let mut packer = PatchPacker::new(options.mtu);
+            let unacked_spawns = if self.group {
+                HashSet::new()
+            } else {
+                self.unacked_spawns()
+            };
+            for index in self.scheduler.order(options.burst_objs) {
+                if unacked_spawns.contains(&entities.ids()[index]) {
+                    continue;
+                }
+                let baseline = self.history.baseline(index);
+                let delta = ObjectDelta::new(&objs[index], baseline, options.codec);
+                let patch = Patch {
+                    id: entities.ids()[index],
+                    delta,
+                };
+                used += packer.cost(&patch);
+                if budget < used {
+                    break;
+                }
+                let state = delta.apply(baseline.map(|(_, base)| base));
+                self.reckoning.on_sent(index, state);
+                self.scheduler.on_sent(index);
+                sent_states.push_back((index, state));
+                msgs.extend(packer.push(patch));
+            }
+            msgs.extend(packer.finish());
+        }
+
+        for msg in msgs {
+            let buf = msg.encode(self.seq, timestamp);
+            let num_states = match &msg {
+                Message::Patches { patches, .. } => patches.len(),
+                Message::Snapshot { states, .. } => states.len(),
+                _ => 0,
+            };
+            self.history
+                .on_sent(self.seq, sent_states.drain(..num_states));
+            // Nobody acknowledges for a group, which would look like all datagrams were lost
+            if !self.group {
+                self.link.on_sent(self.seq);
+            }
+            self.seq = self.seq.wrapping_add(1);
+            self.packets += 1;
+            amt += transport.send_to(&buf, self.addr)?;
+        }
+        self.bytes += amt;
+        self.scheduler.consume(amt);
+        Ok(amt)
+    }
+}
+
+/// The sender side of the synchronization. It sends the objects in the order of priority
+/// under a bandwidth budget, delta-encoded against the baselines each client has acknowledged.
+///
+/// Receivers register themselves with a [`Message::Hello`] and are forgotten after
+/// [`CLIENT_TIMEOUT`] of silence. Each of them has its own baselines, budget and snapshot.
+pub struct PatchSender {
+    start: Instant,
+    clients: Vec<Client>,
+    next_client_id: u32,
+    entities: EntityMap,
+    next_entity_id: EntityId,
+    params: BoidParams,
+    /// The index of the first object of the next truth samples
+    truth_cursor: usize,
+    packets: usize,
+    bytes: usize,
+    buf: Vec<u8>,
+}
+
+impl Default for PatchSender {
+    fn default() -> Self {
+        Self {
+            start: Instant::now(),
+            clients: vec![],
+            next_client_id: 0,
+            entities: EntityMap::new(),
+            next_entity_id: 0,
+            params: BoidParams::default(),
+            truth_cursor: 0,
+            packets: 0,
+            bytes: 0,
+            buf: vec![0u8; MAX_DATAGRAM_SIZE],
+        }
+    }
+}
+
+impl PatchSender {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a client that does not need to register itself and never times out.
+    pub fn add_client(&mut self, addr: SocketAddr) {
+        if let Some(client) = self.clients.iter_mut().find(|client| client.addr == addr) {
+            client.fixed = true;
+        } else {
+            self.register(addr, true, false);
+        }
+    }
+
+    /// Adds a multicast group or a broadcast address to publish to, which any number of
+    /// receivers can listen to with constant bandwidth of the sender.
+    ///
+    /// Since the acknowledgements of individual receivers do not tell what the others have,
+    /// nothing is delta-encoded against baselines, and any receiver's resync request makes
+    /// the snapshot sent to all of them.
+    pub fn add_group(&mut self, addr: SocketAddr) {
+        self.register(addr, true, true);
+    }
+
+    fn register(&mut self, addr: SocketAddr, fixed: bool, group: bool) -> u32 {
+        let id = self.next_client_id;
+        self.next_client_id = self.next_client_id.wrapping_add(1);
+        let mut client = Client::new(id, addr, fixed, group);
+        client
+            .channel
+            .send(ChannelMessage::Params(self.params).encode());
+        self.clients.push(client);
+        id
+    }
+
+    pub fn params(&self) -> &BoidParams {
+        &self.params
+    }
+
+    /// Changes the parameters of the simulation, which are sent to the clients if they differ
+    /// from the current ones. They are also used to predict what the clients predict.
+    pub fn set_params(&mut self, params: &BoidParams) {
+        if self.params == *params {
+            return;
+        }
+        self.params = *params;
+        for client in &mut self.clients {
+            client
+                .channel
+                .send(ChannelMessage::Params(*params).encode());
+        }
+    }
+
+    /// The ids of the objects, in the order of the slice of objects to pass to
+    /// [`Self::send_burst`].
+    pub fn entities(&self) -> &EntityMap {
+        &self.entities
+    }
+
+    /// Adds an object, which the caller appends to its objects, and returns its new id.
+    /// The clients are told about it with a [`LifecycleEvent::Spawn`].
+    pub fn spawn(&mut self, obj: &Object) -> EntityId {
+        let id = self.next_entity_id;
+        self.next_entity_id = self.next_entity_id.wrapping_add(1);
+        self.entities.insert(id);
+        for client in &mut self.clients {
+            client
+                .channel
+                .send(ChannelMessage::Lifecycle(LifecycleEvent::Spawn { id, obj: *obj }).encode());
+        }
+        id
+    }
+
+    /// Removes an object and returns the index it had, which the caller removes from its
+    /// objects with [`Vec::swap_remove`]. The clients are told about it with a
+    /// [`LifecycleEvent::Despawn`].
+    pub fn despawn(&mut self, id: EntityId) -> Option<usize> {
+        let num_objects = self.entities.len();
+        let index = self.entities.remove(id)?;
+        for client in &mut self.clients {
+            client.despawn(index, id, num_objects);
+        }
+        Some(index)
+    }
+
+    pub fn clients(&self) -> Vec<ClientInfo> {
+        self.clients.iter().map(Client::info).collect()
+    }
+
+    pub fn client_addrs(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.clients.iter().map(|client| client.addr)
+    }
+
+    /// The time in milliseconds of the clock the datagrams are timestamped with, on whose
+    /// ticks the clients extrapolate the objects.
+    pub fn time(&self) -> u32 {
+        elapsed_ms(self.start)
+    }
+
+    /// The number of datagrams sent to all clients.
+    pub fn packets(&self) -> usize {
+        self.packets
+    }
+
+    /// The number of bytes sent to all clients.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Processes all the hellos, acknowledgements, pings and resync requests that have arrived,
+    /// without blocking, and forgets the clients that have timed out. Returns the other
+    /// messages and the hellos with their source addresses, for the application to handle.
+    pub fn poll(
+        &mut self,
+        transport: &mut impl Transport,
+    ) -> io::Result<Vec<(SocketAddr, Message)>> {
+        let mut unhandled = vec![];
+        while let Some((len, src)) = transport.recv_from(&mut self.buf, Duration::ZERO)? {
+            let msg = match Message::decode(&self.buf[..len]) {
+                Ok((_, msg)) => msg,
+                Err(e) => {
+                    eprintln!("Rejected a datagram from {src}: {e}");
+                    continue;
+                }
+            };
+            let mut client = self.clients.iter_mut().find(|client| client.addr == src);
+            if let Some(client) = &mut client {
+                client.last_seen = Instant::now();
+            }
+            match (msg, client) {
+                (Message::Hello, client) => {
+                    let client_id = match client {
+                        Some(client) => client.id,
+                        None => {
+                            let id = self.register(src, false, false);
+                            println!("Client {id} registered from {src}");
+                            id
+                        }
+                    };
+                    let msg = Message::Welcome { client_id };
+                    if let Err(e) = transport.send_to(&msg.encode(0, 0), src) {
+                        eprintln!("Failed to send a welcome to {src}: {e}");
+                    }
+                    unhandled.push((src, Message::Hello));
+                }
+                (
+                    Message::Ack {
+                        latest,
+                        mask,
+                        ack_delay,
+                    },
+                    Some(client),
+                ) => {
+                    let ack_delay = Duration::from_millis(ack_delay as u64);
+                    for seq in client.link.on_ack(latest, mask, ack_delay) {
+                        client.history.on_ack(seq);
+                    }
+                }
+                (Message::Ping { timestamp }, _) => {
+                    // Reply immediately, although it can be delayed by up to the rate of the loop
+                    let msg = Message::Pong { timestamp };
+                    if let Err(e) = transport.send_to(&msg.encode(0, 0), src) {
+                        eprintln!("Failed to send a pong to {src}: {e}");
+                    }
+                }
+                (Message::ResyncRequest { snapshot_id }, Some(client)) => {
+                    client.on_resync_request(snapshot_id, &self.entities, &self.params);
+                }
+                (Message::ResyncRequest { snapshot_id }, None) => {
+                    // It may be one of the receivers listening to a group
+                    for client in self.clients.iter_mut().filter(|client| client.group) {
+                        client.on_resync_request(snapshot_id, &self.entities, &self.params);
+                    }
+                }
+                (
+                    Message::ReliableAck {
+                        epoch,
+                        next_seq,
+                        mask,
+                    },
+                    Some(client),
+                ) => {
+                    client.channel.on_ack(epoch, next_seq, mask);
+                }
+                // Acknowledgements from an unknown client are meaningless, because they refer
+                // to sequence numbers of another session or a group
+                (Message::Ack { .. } | Message::ReliableAck { .. }, None) => {}
+                (msg, _) => unhandled.push((src, msg)),
+            }
+        }
+        self.clients.retain(|client| {
+            let alive = client.fixed || client.last_seen.elapsed() < CLIENT_TIMEOUT;
+            if !alive {
+                println!("Client {} at {} timed out", client.id, client.addr);
+            }
+            alive
+        });
+        Ok(unhandled)
+    }
+
+    /// Sends a message other than patches to `dest`, counting toward [`Self::bytes`].
+    pub fn send(
+        &mut self,
+        transport: &mut impl Transport,
+        dest: SocketAddr,
+        msg: &Message,
+    ) -> io::Result<usize> {
+        let amt = transport.send_to(&msg.encode(0, elapsed_ms(self.start)), dest)?;
+        if let Some(client) = self.clients.iter_mut().find(|client| client.addr == dest) {
+            client.packets += 1;
+            client.bytes += amt;
+        }
+        self.packets += 1;
+        self.bytes += amt;
+        Ok(amt)
+    }
+
+    /// Sends the objects of the highest priority to each client as far as its budget allows,
+    /// and returns the number of bytes sent. If a client has requested a snapshot, the next
+    /// parts of it are sent first, so that it is paced by the same budget.
+    /// The lifecycle events due are sent before everything else.
+    ///
+    /// `objs` must be in the order of [`Self::entities`].
+    pub fn send_burst(
+        &mut self,
+        transport: &mut impl Transport,
+        objs: &[Object],
+        options: &SendOptions,
+    ) -> io::Result<usize> {
+        debug_assert_eq!(objs.len(), self.entities.len());
+        let timestamp = elapsed_ms(self.start);
+        let mut amt = 0;
+        for client in &mut self.clients {
+            let packets = client.packets;
+            amt += client.send_burst(
+                transport,
+                objs,
+                &self.entities,
+                &self.params,
+                options,
+                timestamp,
+            )?;
+            self.packets += client.packets - packets;
+        }
+        self.bytes += amt;
+        Ok(amt)
+    }
+
+    /// Sends the true positions of as many objects as fit in a datagram to each client,
+    /// following the ones sent last time, for the receivers to measure the divergence.
+    /// It does not count toward the bandwidth budget or [`Self::bytes`].
+    pub fn send_truth(
+        &mut self,
+        transport: &mut impl Transport,
+        objs: &[Object],
+        mtu: usize,
+    ) -> io::Result<usize> {
+        let count = truth_capacity(mtu).min(objs.len());
+        if objs.len() <= self.truth_cursor {
+            self.truth_cursor = 0;
+        }
+        let indices = (self.truth_cursor..self.truth_cursor + count).map(|i| i % objs.len());
+        self.truth_cursor += count;
+        self.send_truth_samples(transport, objs, indices)
+    }
+
+    /// Sends the true states of all objects in as many datagrams as needed to each client,
+    /// as a debug stream. It does not count toward the bandwidth budget or [`Self::bytes`].
+    pub fn send_full_truth(
+        &mut self,
+        transport: &mut impl Transport,
+        objs: &[Object],
+        mtu: usize,
+    ) -> io::Result<usize> {
+        let count = truth_capacity(mtu);
+        let mut amt = 0;
+        for first in (0..objs.len()).step_by(count) {
+            let indices = first..(first + count).min(objs.len());
+            amt += self.send_truth_samples(transport, objs, indices)?;
+        }
+        Ok(amt)
+    }
+
+    fn send_truth_samples(
+        &self,
+        transport: &mut impl Transport,
+        objs: &[Object],
+        indices: impl Iterator<Item = usize>,
+    ) -> io::Result<usize> {
+        let msg = Message::Truth {
+            samples: indices
+                .map(|index| (self.entities.ids()[index], objs[index]))
+                .collect(),
+        };
+        let buf = msg.encode(0, elapsed_ms(self.start));
+        let mut amt = 0;
+        for client in &self.clients {
+            amt += transport.send_to(&buf, client.addr)?;
+        }
+        Ok(amt)
+    }
+}
+
+/// The number of states that fit in a [`Message::Snapshot`] datagram.
+fn snapshot_capacity(mtu: usize) -> usize {
+    (mtu.saturating_sub(Message::SNAPSHOT_OVERHEAD) / Message::SNAPSHOT_STATE_SIZE)
+        .clamp(1, u16::MAX as usize)
+}
+
+/// The number of samples that fit in a [`Message::Truth`] datagram.
+fn truth_capacity(mtu: usize) -> usize {
+    (mtu.saturating_sub(Message::TRUTH_OVERHEAD) / Message::TRUTH_SAMPLE_SIZE)
+        .clamp(1, u16::MAX as usize)
+}
+
+/// The object states decoded from a datagram, by the indices of the replicas.
+pub struct Update {
+    /// The number of replicas, which grows as objects are spawned
+    pub num_objects: usize,
+    pub seq: u32,
+    /// The time the states were sent, in milliseconds since the sender started
+    pub timestamp: u32,
+    pub states: Vec<(usize, Object)>,
+    /// Whether the states are a part of a snapshot or spawned objects, which replace the
+    /// replicas regardless of the sequence numbers of the patches applied before, in case the
+    /// sender has restarted.
+    pub snapshot: bool,
+}
+
+impl Update {
+    /// Applies the states to the replicas, resizing them to the number of objects.
+    /// Returns the number of states dropped because newer ones were already applied.
+    pub fn apply(&self, objs: &mut Vec<ObjectWrap>) -> usize {
+        if objs.len() != self.num_objects {
+            objs.resize(self.num_objects, ObjectWrap::default());
+        }
+        if self.snapshot {
+            for (index, obj) in &self.states {
+                if let Some(wrap) = objs.get_mut(*index) {
+                    *wrap = ObjectWrap::new(*obj, self.seq);
+                }
+            }
+            return 0;
+        }
+        self.states
+            .iter()
+            .filter(|(index, obj)| {
+                objs.get_mut(*index)
+                    .is_some_and(|wrap| !wrap.apply(*obj, self.seq))
+            })
+            .count()
+    }
+}
+
+/// What the receiver got from the sender.
+pub enum Event {
+    Update(Update),
+    /// The replica `index` was removed, and the last one moved into its place, which is to be
+    /// applied with [`Vec::swap_remove`] to everything indexed like the replicas.
+    Despawn {
+        index: usize,
+    },
+    /// True states of some objects by the indices of the replicas, see [`crate::divergence`].
+    Truth {
+        num_objects: usize,
+        timestamp: u32,
+        samples: Vec<(usize, Object)>,
+    },
+    /// Inputs of the lockstep mode, see [`crate::lockstep`].
+    LockstepInputs {
+        seed: u64,
+        num_objects: usize,
+        first_tick: u32,
+        inputs: Vec<TickInput>,
+    },
+    /// The sender's checksum of the lockstep simulation.
+    Checksum {
+        tick: u32,
+        hash: u64,
+    },
+    /// The sender's parameters of the simulation, to predict the objects with.
+    Params(BoidParams),
+}
+
+/// The progress of receiving a snapshot.
+struct Resync {
+    snapshot_id: u32,
+    /// The objects that have got a state since the resync started
+    synced: HashSet<EntityId>,
+    /// The number of objects the sender has, as of the latest part of the snapshot and the
+    /// lifecycle events since
+    num_objects: Option<usize>,
+    /// The last time the request was sent or a part of the snapshot arrived
+    last_activity: Option<Instant>,
+}
+
+impl Resync {
+    fn is_complete(&self) -> bool {
+        self.num_objects
+            .is_some_and(|num_objects| num_objects <= self.synced.len())
+    }
+}
+
+/// The receiver side of the synchronization. It decodes patches against the stored baselines
+/// and sends acknowledgements and pings back to the sender.
+///
+/// It requests a snapshot of all objects when it starts and when it detects a large gap in
+/// the sequence numbers, until every object has got a state from either the snapshot or patches.
+/// Then the replicas of objects the sender no longer has are removed.
+pub struct PatchReceiver {
+    start: Instant,
+    store: BaselineStore,
+    ack_window: AckWindow,
+    stats: ReceiveStats,
+    last_ack: Instant,
+    last_ping: Instant,
+    /// The source address of the last message from the sender, where acknowledgements are
+    /// sent to
+    sender_addr: Option<SocketAddr>,
+    /// The address of the sender to say hello to, if given
+    connect_addr: Option<SocketAddr>,
+    client_id: Option<u32>,
+    last_hello: Option<Instant>,
+    last_received: Instant,
+    /// The sequence number of the newest datagram from the sender, to detect gaps
+    latest_seq: Option<u32>,
+    resync: Option<Resync>,
+    next_snapshot_id: u32,
+    /// The id of the last snapshot requested, which becomes the epoch of the lifecycle events
+    requested_snapshot_id: Option<u32>,
+    entities: EntityMap,
+    /// The recently despawned objects, up to [`DESPAWN_HISTORY`]
+    despawned: VecDeque<EntityId>,
+    /// The channel of the lifecycle events and the parameters
+    channel: ReliableReceiver,
+    params: Option<BoidParams>,
+    /// The events to return from the following polls
+    events: VecDeque<Event>,
+    bytes: usize,
+    rejected: usize,
+    undecodable: usize,
+    buf: Vec<u8>,
+}
+
+impl Default for PatchReceiver {
+    fn default() -> Self {
+        let now = Instant::now();
+        Self {
+            start: now,
+            store: BaselineStore::new(),
+            ack_window: AckWindow::new(),
+            stats: ReceiveStats::new(),
+            last_ack: now,
+            last_ping: now,
+            sender_addr: None,
+            connect_addr: None,
+            client_id: None,
+            last_hello: None,
+            last_received: now,
+            latest_seq: None,
+            resync: None,
+            // Random, so that the requests of receivers listening to the same group differ
+            next_snapshot_id: rand::random(),
+            requested_snapshot_id: None,
+            entities: EntityMap::new(),
+            despawned: VecDeque::new(),
+            channel: ReliableReceiver::new(),
+            params: None,
+            events: VecDeque::new(),
+            bytes: 0,
+            rejected: 0,
+            undecodable: 0,
+            buf: vec![0u8; MAX_DATAGRAM_SIZE],
+        }
+    }
+}
+
+impl PatchReceiver {
+    pub fn new() -> Self {
+        let mut ret = Self::default();
+        ret.request_resync();
+        ret
+    }
+
+    /// Registers to the sender at `addr` with hellos, and again whenever it has been silent
+    /// for [`SENDER_TIMEOUT`]. Without this, the receiver waits for a sender that has
+    /// been told its address.
+    pub fn connect(&mut self, addr: SocketAddr) {
+        self.connect_addr = Some(addr);
+        self.sender_addr = Some(addr);
+    }
+
+    /// The number the sender assigned to this receiver, once it has welcomed it.
+    pub fn client_id(&self) -> Option<u32> {
+        self.client_id
+    }
+
+    /// Starts requesting a snapshot, unless it is already in progress.
+    pub fn request_resync(&mut self) {
+        if self.resync.is_none() {
+            self.resync = Some(Resync {
+                snapshot_id: self.next_snapshot_id,
+                synced: HashSet::new(),
+                num_objects: None,
+                last_activity: None,
+            });
+        }
+    }
+
+    /// The number of objects that have got a state and the total number of objects
+    /// while a snapshot is in progress, or `None` if the replicas are in sync.
+    pub fn sync_progress(&self) -> Option<(usize, usize)> {
+        self.resync.as_ref().map(|resync| {
+            let num_objects = resync.num_objects.unwrap_or(0);
+            (resync.synced.len().min(num_objects), num_objects)
+        })
+    }
+
+    /// The ids of the objects, in the order of the replicas.
+    pub fn entities(&self) -> &EntityMap {
+        &self.entities
+    }
+
+    /// The sender's parameters of the simulation, once they have arrived.
+    pub fn params(&self) -> Option<&BoidParams> {
+        self.params.as_ref()
+    }
+
+    pub fn stats(&self) -> StatsSnapshot {
+        self.stats.snapshot()
+    }
+
+    /// The number of bytes of valid datagrams received.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// The number of datagrams rejected as malformed.
+    pub fn rejected(&self) -> usize {
+        self.rejected
+    }
+
+    /// The number of patches dropped because their baselines were not available.
+    pub fn undecodable(&self) -> usize {
+        self.undecodable
+    }
+
+    /// Sends acknowledgements and pings if they are due, then waits up to `timeout` for a
+    /// datagram. Returns what it carried, if it is of interest to the application.
+    /// A datagram can result in several events, which are returned one by one without waiting.
+    pub fn poll(
+        &mut self,
+        transport: &mut impl Transport,
+        timeout: Duration,
+    ) -> io::Result<Option<Event>> {
+        if let Some(event) = self.events.pop_front() {
+            return Ok(Some(event));
+        }
+        self.send_feedback(transport);
+
+        let Some((len, src)) = transport.recv_from(&mut self.buf, timeout)? else {
+            return Ok(None);
+        };
+        let (header, msg) = match Message::decode(&self.buf[..len]) {
+            Ok(res) => res,
+            Err(e) => {
+                eprintln!("Rejected a datagram from {src}: {e}");
+                self.rejected += 1;
+                return Ok(None);
+            }
+        };
+        self.bytes += len;
+        self.last_received = Instant::now();
+
+        match msg {
+            Message::Patches { patches } => {
+                self.stats.on_packet(
+                    header.seq,
+                    header.timestamp,
+                    len,
+                    self.start.elapsed().as_secs_f64() * 1e3,
+                );
+                self.sender_addr = Some(src);
+                self.check_gap(header.seq);
+                let num_patches = patches.len();
+                let mut states = Vec::with_capacity(num_patches);
+                for Patch { id, delta } in patches {
+                    // The object may not have been spawned yet
+                    let Some(obj) = self
+                        .entities
+                        .slot(id)
+                        .and_then(|index| Some((index, self.store.decode(index, &delta)?)))
+                    else {
+                        self.undecodable += 1;
+                        continue;
+                    };
+                    let (index, obj) = obj;
+                    self.store.insert(index, header.seq, obj);
+                    states.push((index, obj));
+                    if let Some(resync) = &mut self.resync {
+                        resync.synced.insert(id);
+                    }
+                }
+                // Only acknowledge a datagram if all of its states can be used as baselines
+                if states.len() == num_patches {
+                    self.ack_window.record(header.seq);
+                }
+                self.events.push_back(Event::Update(Update {
+                    num_objects: self.entities.len(),
+                    seq: header.seq,
+                    timestamp: header.timestamp,
+                    states,
+                    snapshot: false,
+                }));
+                self.finish_resync();
+            }
+            Message::Snapshot {
+                snapshot_id,
+                num_objects,
+                states,
+            } => {
+                // A delayed part must not bring back the objects despawned since it was sent
+                let num_states = states.len();
+                let states: Vec<_> = states
+                    .into_iter()
+                    .filter(|(id, _)| !self.despawned.contains(id))
+                    .collect();
+                let Some(num_objects) = num_objects.checked_sub(num_states - states.len()) else {
+                    eprintln!(
+                        "Rejected a snapshot from {src}: more states than {num_objects} objects"
+                    );
+                    self.rejected += 1;
+                    return Ok(None);
+                };
+                self.stats.on_packet(
+                    header.seq,
+                    header.timestamp,
+                    len,
+                    self.start.elapsed().as_secs_f64() * 1e3,
+                );
+                self.sender_addr = Some(src);
+                self.check_gap(header.seq);
+                if let Some(resync) = &mut self.resync {
+                    resync.synced.extend(states.iter().map(|(id, _)| *id));
+                    resync.num_objects = Some(num_objects);
+                    if resync.snapshot_id == snapshot_id {
+                        resync.last_activity = Some(Instant::now());
+                    }
+                }
+                let states = states
+                    .into_iter()
+                    .map(|(id, obj)| {
+                        let index = self.insert_entity(id);
+                        self.store.insert(index, header.seq, obj);
+                        (index, obj)
+                    })
+                    .collect();
+                self.ack_window.record(header.seq);
+                self.events.push_back(Event::Update(Update {
+                    num_objects: self.entities.len(),
+                    seq: header.seq,
+                    timestamp: header.timestamp,
+                    states,
+                    snapshot: true,
+                }));
+                self.finish_resync();
+            }
+            Message::Truth { samples } => {
+                let samples = samples
+                    .into_iter()
+                    .filter_map(|(id, obj)| Some((self.entities.slot(id)?, obj)))
+                    .collect();
+                self.events.push_back(Event::Truth {
+                    num_objects: self.entities.len(),
+                    timestamp: header.timestamp,
+                    samples,
+                });
+            }
+            Message::LockstepInputs {
+                seed,
+                num_objects,
+                first_tick,
+                inputs,
+            } => {
+                self.sender_addr = Some(src);
+                // The lockstep simulation catches up by itself
+                self.resync = None;
+                self.events.push_back(Event::LockstepInputs {
+                    seed,
+                    num_objects,
+                    first_tick,
+                    inputs,
+                });
+            }
+            Message::Checksum { tick, hash } => {
+                self.events.push_back(Event::Checksum { tick, hash });
+            }
+            Message::Reliable {
+                epoch,
+                base,
+                messages,
+            } => {
+                self.sender_addr = Some(src);
+                self.on_reliable(epoch, base, messages, header.timestamp);
+                if let Some(msg) = self.channel.ack() {
+                    if let Err(e) = transport.send_to(&msg.encode(0, 0), src) {
+                        eprintln!("Failed to send a reliable ack to {src}: {e}");
+                    }
+                }
+            }
+            Message::Pong { timestamp } => {
+                let rtt = elapsed_ms(self.start).wrapping_sub(timestamp);
+                self.stats.on_rtt_sample(Duration::from_millis(rtt as u64));
+            }
+            Message::Welcome { client_id } => {
+                self.client_id = Some(client_id);
+            }
+            Message::Ack { .. }
+            | Message::Ping { .. }
+            | Message::LockstepAck { .. }
+            | Message::ResyncRequest { .. }
+            | Message::Hello
+            | Message::ReliableAck { .. } => {}
+        }
+        Ok(self.events.pop_front())
+    }
+
+    /// Receives the messages of the reliable channel and applies the ones that have become ready
+    /// in order.
+    fn on_reliable(
+        &mut self,
+        epoch: u32,
+        base: u32,
+        messages: Vec<(u32, Vec<u8>)>,
+        timestamp: u32,
+    ) {
+        match self.channel.epoch() {
+            Some(current) if current == epoch => {}
+            // A new stream starts with the snapshot requested last. The streams of earlier
+            // requests may still be on the way.
+            Some(_) if self.requested_snapshot_id != Some(epoch) => return,
+            _ => self.channel.restart(epoch),
+        }
+        if self.channel.on_message(epoch, base, messages) != Some(0) {
+            // Some events are no longer repeated to the group
+            self.request_resync();
+        }
+        while let Some(payload) = self.channel.recv() {
+            match ChannelMessage::decode(&payload) {
+                Ok(ChannelMessage::Lifecycle(event)) => self.on_lifecycle(event, timestamp),
+                Ok(ChannelMessage::Params(params)) => {
+                    self.params = Some(params);
+                    self.events.push_back(Event::Params(params));
+                }
+                Err(e) => {
+                    eprintln!("Rejected a message of the reliable channel: {e}");
+                    self.rejected += 1;
+                }
+            }
+        }
+        self.finish_resync();
+    }
+
+    fn on_lifecycle(&mut self, event: LifecycleEvent, timestamp: u32) {
+        match event {
+            LifecycleEvent::Spawn { id, obj } => {
+                if self.entities.contains(id) {
+                    return;
+                }
+                let index = self.insert_entity(id);
+                if let Some(resync) = &mut self.resync {
+                    resync.synced.insert(id);
+                    if let Some(num_objects) = &mut resync.num_objects {
+                        *num_objects += 1;
+                    }
+                }
+                self.events.push_back(Event::Update(Update {
+                    num_objects: self.entities.len(),
+                    seq: self.latest_seq.unwrap_or(0),
+                    timestamp,
+                    states: vec![(index, obj)],
+                    snapshot: true,
+                }));
+            }
+            LifecycleEvent::Despawn { id } => {
+                if self.remove_entity(id) {
+                    if let Some(resync) = &mut self.resync {
+                        resync.synced.remove(&id);
+                        if let Some(num_objects) = &mut resync.num_objects {
+                            *num_objects = num_objects.saturating_sub(1);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns the index of the replica of an object, adding one if it is new.
+    fn insert_entity(&mut self, id: EntityId) -> usize {
+        let index = self.entities.insert(id);
+        self.store.resize(self.entities.len());
+        index
+    }
+
+    /// Removes the replica of an object and queues the event for the application, if it exists.
+    fn remove_entity(&mut self, id: EntityId) -> bool {
+        let Some(index) = self.entities.remove(id) else {
+            return false;
+        };
+        self.store.swap_remove(index);
+        self.events.push_back(Event::Despawn { index });
+        if DESPAWN_HISTORY <= self.despawned.len() {
+            self.despawned.pop_front();
+        }
+        self.despawned.push_back(id);
+        true
+    }
+
+    /// Sends a message to the sender, if it is known.
+    pub fn reply(&self, transport: &mut impl Transport, msg: &Message) -> io::Result<()> {
+        if let Some(addr) = self.sender_addr {
+            transport.send_to(&msg.encode(0, elapsed_ms(self.start)), addr)?;
+        }
+        Ok(())
+    }
+
+    /// Starts a resync if the sequence number jumped too far from the newest one, either ahead
+    /// after a long outage or behind after the sender restarted.
+    fn check_gap(&mut self, seq: u32) {
+        let Some(latest) = self.latest_seq else {
+            self.latest_seq = Some(seq);
+            return;
+        };
+        let gap = if seq_newer(seq, latest) {
+            seq.wrapping_sub(latest)
+        } else {
+            latest.wrapping_sub(seq)
+        };
+        if RESYNC_SEQ_GAP < gap {
+            self.request_resync();
+            self.latest_seq = Some(seq);
+            // The sender may have restarted and reused the ids
+            self.despawned.clear();
+        } else if seq_newer(seq, latest) {
+            self.latest_seq = Some(seq);
+        }
+    }
+
+    /// Ends the resync if it is complete, removing the replicas of objects that got no state,
+    /// which the sender no longer has.
+    fn finish_resync(&mut self) {
+        let Some(resync) = self.resync.take_if(|resync| resync.is_complete()) else {
+            return;
+        };
+        let stale: Vec<_> = self
+            .entities
+            .ids()
+            .iter()
+            .copied()
+            .filter(|id| !resync.synced.contains(id))
+            .collect();
+        for id in stale {
+            self.remove_entity(id);
+        }
+    }
+
+    fn send_feedback(&mut self, transport: &mut impl Transport) {
+        if let Some(addr) = self.connect_addr {
+            if SENDER_TIMEOUT <= self.last_received.elapsed() {
+                self.client_id = None;
+            }
+            if self.client_id.is_none()
+                && self
+                    .last_hello
+                    .is_none_or(|time| HELLO_INTERVAL <= time.elapsed())
+            {
+                if let Err(e) = transport.send_to(&Message::Hello.encode(0, 0), addr) {
+                    eprintln!("Failed to send a hello to {addr}: {e}");
+                }
+                self.last_hello = Some(Instant::now());
+            }
+        }
+        let Some(addr) = self.sender_addr else {
+            return;
+        };
+        if let Some(resync) = &mut self.resync {
+            if resync
+                .last_activity
+                .is_none_or(|time| RESYNC_TIMEOUT <= time.elapsed())
+            {
+                // A new id makes the sender start over, in case some parts have been lost
+                resync.snapshot_id = self.next_snapshot_id;
+                resync.synced.clear();
+                resync.num_objects = None;
+                self.next_snapshot_id = self.next_snapshot_id.wrapping_add(1);
+                self.requested_snapshot_id = Some(resync.snapshot_id);
+                let msg = Message::ResyncRequest {
+                    snapshot_id: resync.snapshot_id,
+                };
+                if let Err(e) = transport.send_to(&msg.encode(0, 0), addr) {
+                    eprintln!("Failed to send a resync request to {addr}: {e}");
+                }
+                resync.last_activity = Some(Instant::now());
+            }
+        }
+        if ACK_INTERVAL <= self.last_ack.elapsed() {
+            if let Some((latest, mask, ack_delay)) = self.ack_window.take_ack() {
+                let msg = Message::Ack {
+                    latest,
+                    mask,
+                    ack_delay: ack_delay.as_millis().min(u16::MAX as u128) as u16,
+                };
+                if let Err(e) = transport.send_to(&msg.encode(0, 0), addr) {
+                    eprintln!("Failed to send an ack to {addr}: {e}");
+                }
+            }
+            self.last_ack = Instant::now();
+        }
+        if PING_INTERVAL <= self.last_ping.elapsed() {
+            let msg = Message::Ping {
+                timestamp: elapsed_ms(self.start),
+            };
+            if let Err(e) = transport.send_to(&msg.encode(0, 0), addr) {
+                eprintln!("Failed to send a ping to {addr}: {e}");
+            }
+            self.last_ping = Instant::now();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        impair::{ImpairedTransport, ImpairmentConfig},
+        transport::MemoryNetwork,
+    };
+
+    const SENDER_ADDR: &str = "10.0.0.1:34255";
+    const RECEIVER_ADDR: &str = "10.0.0.2:34254";
+
+    /// The sender and a receiver connected over a [`MemoryNetwork`], with the replicas the
+    /// receiver application would keep.
+    struct Pipeline<T, U> {
+        sender: PatchSender,
+        sender_transport: T,
+        objs: Vec<Object>,
+        receiver: PatchReceiver,
+        receiver_transport: U,
+        replicas: Vec<ObjectWrap>,
+        options: SendOptions,
+    }
+
+    impl<T: Transport, U: Transport> Pipeline<T, U> {
+        fn new(sender_transport: T, receiver_transport: U, num_objects: usize) -> Self {
+            let mut sender = PatchSender::new();
+            sender.add_client(RECEIVER_ADDR.parse().unwrap());
+            let objs: Vec<_> = (0..num_objects)
+                .map(|i| {
+                    let mut obj = Object::new([i as f64 * 0.01, 5.], [i as u8, 0, 0]);
+                    obj.velo = [0.1, -0.1];
+                    obj
+                })
+                .collect();
+            for obj in &objs {
+                sender.spawn(obj);
+            }
+            Self {
+                sender,
+                sender_transport,
+                objs,
+                receiver: PatchReceiver::new(),
+                receiver_transport,
+                replicas: vec![],
+                options: SendOptions {
+                    bandwidth: 1e7,
+                    ..SendOptions::default()
+                },
+            }
+        }
+
+        /// Runs a round of both ends like the binaries do.
+        fn step(&mut self) {
+            self.sender.poll(&mut self.sender_transport).unwrap();
+            self.sender
+                .send_burst(&mut self.sender_transport, &self.objs, &self.options)
+                .unwrap();
+            while let Some(event) = self
+                .receiver
+                .poll(&mut self.receiver_transport, Duration::from_millis(1))
+                .unwrap()
+            {
+                match event {
+                    Event::Update(update) => {
+                        update.apply(&mut self.replicas);
+                    }
+                    Event::Despawn { index } => {
+                        self.replicas.swap_remove(index);
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        /// Whether every object has a replica of the same state, which can be in another slot
+        /// because the receiver orders the replicas as they arrive.
+        fn in_sync(&self) -> bool {
+            let receiver_entities = self.receiver.entities();
+            let sender_entities = self.sender.entities();
+            self.receiver.sync_progress().is_none()
+                && receiver_entities.len() == sender_entities.len()
+                && self.replicas.len() == self.objs.len()
+                && self.replicas.iter().enumerate().all(|(slot, replica)| {
+                    let Some(obj) = receiver_entities
+                        .id(slot)
+                        .and_then(|id| sender_entities.slot(id))
+                        .map(|slot| &self.objs[slot])
+                    else {
+                        return false;
+                    };
+                    let replica = replica.as_ref();
+                    replica.pos == obj.pos && replica.velo == obj.velo
+                })
+        }
+
+        /// Steps until the replicas match the sender's objects, and returns whether they did
+        /// within `timeout`.
+        fn sync(&mut self, timeout: Duration) -> bool {
+            let start = Instant::now();
+            while start.elapsed() < timeout {
+                self.step();
+                if self.in_sync() {
+                    return true;
+                }
+            }
+            false
+        }
+    }
+
+    fn memory_pipeline(num_objects: usize) -> Pipeline<impl Transport, impl Transport> {
+        let network = MemoryNetwork::new();
+        let sender_transport = network.bind(SENDER_ADDR.parse().unwrap()).unwrap();
+        let receiver_transport = network.bind(RECEIVER_ADDR.parse().unwrap()).unwrap();
+        Pipeline::new(sender_transport, receiver_transport, num_objects)
+    }
+
+    #[test]
+    fn replicas_follow_the_sender() {
+        let mut pipeline = memory_pipeline(300);
+        assert!(pipeline.sync(Duration::from_secs(5)));
+        assert_eq!(pipeline.receiver.params(), Some(&BoidParams::default()));
+
+        // Moved objects are patched
+        for obj in &mut pipeline.objs[..100] {
+            obj.pos[1] += 1.;
+        }
+        assert!(pipeline.sync(Duration::from_secs(5)));
+        assert_eq!(pipeline.receiver.rejected(), 0);
+    }
+
+    #[test]
+    fn bursts_are_capped() {
+        let mut pipeline = memory_pipeline(100);
+        pipeline.options.burst_objs = Some(7);
+        assert!(pipeline.sync(Duration::from_secs(5)));
+
+        for obj in &mut pipeline.objs {
+            obj.pos[1] += 1.;
+        }
+        // The budget would allow all of them
+        std::thread::sleep(Duration::from_millis(20));
+        pipeline
+            .sender
+            .send_burst(
+                &mut pipeline.sender_transport,
+                &pipeline.objs,
+                &pipeline.options,
+            )
+            .unwrap();
+        let mut received = 0;
+        // Datagrams of no interest to the application, such as resent channel messages, return
+        // no event
+        for _ in 0..10 {
+            let event = pipeline
+                .receiver
+                .poll(&mut pipeline.receiver_transport, Duration::from_millis(1))
+                .unwrap();
+            if let Some(Event::Update(update)) = event {
+                received += update.states.len();
+                update.apply(&mut pipeline.replicas);
+            }
+        }
+        assert_eq!(received, 7);
+        assert!(pipeline.sync(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn lifecycle_and_params_reach_the_receiver() {
+        let mut pipeline = memory_pipeline(50);
+        assert!(pipeline.sync(Duration::from_secs(5)));
+
+        for id in [3, 10, 49] {
+            let index = pipeline.sender.despawn(id).unwrap();
+            pipeline.objs.swap_remove(index);
+        }
+        let obj = Object::new([1., 1.], [1, 2, 3]);
+        pipeline.sender.spawn(&obj);
+        pipeline.objs.push(obj);
+        let params = BoidParams {
+            cohesion: 1e-3,
+            ..BoidParams::default()
+        };
+        pipeline.sender.set_params(&params);
+
+        assert!(pipeline.sync(Duration::from_secs(5)));
+        assert!(!pipeline.receiver.entities().contains(10));
+        assert_eq!(pipeline.receiver.params(), Some(&params));
+    }
+
+    #[test]
+    fn replicas_converge_over_an_impaired_link() {
+        let network = MemoryNetwork::new();
+        let impairment = ImpairmentConfig {
+            loss: 0.2,
+            duplicate: 0.05,
+            reorder: 0.1,
+            seed: Some(1),
+            ..ImpairmentConfig::default()
+        };
+        let sender_transport = ImpairedTransport::new(
+            network.bind(SENDER_ADDR.parse().unwrap()).unwrap(),
+            impairment,
+        );
+        let receiver_transport = ImpairedTransport::new(
+            network.bind(RECEIVER_ADDR.parse().unwrap()).unwrap(),
+            ImpairmentConfig {
+                seed: Some(2),
+                ..impairment
+            },
+        );
+        let mut pipeline = Pipeline::new(sender_transport, receiver_transport, 200);
+        assert!(pipeline.sync(Duration::from_secs(10)));
+
+        let index = pipeline.sender.despawn(5).unwrap();
+        pipeline.objs.swap_remove(index);
+        assert!(pipeline.sync(Duration::from_secs(10)));
+    }
+}
@@ -0,0 +1,420 @@
+//! An abstraction of datagram transports, so that the sync logic in [`crate::sync`] does not
+//! depend on [`UdpSocket`] directly.
+//!
+//! Besides UDP, there is an in-memory [`MemoryNetwork`] to run the sender and the receiver in
+//! a single process deterministically, and [`crate::impair::ImpairedTransport`] which wraps
+//! another transport to simulate an unreliable network.
+
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind},
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, SocketAddrV6, ToSocketAddrs, UdpSocket},
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+
+pub trait Transport {
+    /// Sends a datagram to `addr`. Like UDP, succeeding does not mean it was delivered.
+    fn send_to(&mut self, buf: &[u8], addr: SocketAddr) -> io::Result<usize>;
+
+    /// Receives a datagram, waiting up to `timeout` for one to arrive.
+    /// Returns `Ok(None)` if nothing arrived. A zero `timeout` polls without blocking.
+    fn recv_from(
+        &mut self,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> io::Result<Option<(usize, SocketAddr)>>;
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn send_to(&mut self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        (**self).send_to(buf, addr)
+    }
+
+    fn recv_from(
+        &mut self,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> io::Result<Option<(usize, SocketAddr)>> {
+        (**self).recv_from(buf, timeout)
+    }
+}
+
+pub struct UdpTransport {
+    socket: UdpSocket,
+    /// The last timeout set to the socket, to avoid redundant system calls.
+    timeout: Option<Duration>,
+    /// Whether the socket is an IPv6 socket that also talks to IPv4 peers, through
+    /// IPv4-mapped IPv6 addresses which are translated at the boundary.
+    dual_stack: bool,
+}
+
+impl UdpTransport {
+    pub fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        Ok(Self::new(UdpSocket::bind(addr)?))
+    }
+
+    pub fn new(socket: UdpSocket) -> Self {
+        Self {
+            socket,
+            timeout: None,
+            dual_stack: false,
+        }
+    }
+
+    /// Binds a socket to `addr` like [`Self::bind`], but if it is an IPv6 address, the socket
+    /// accepts IPv4 peers as well. Binding to `[::]` listens on all addresses of both families.
+    pub fn bind_dual_stack(addr: SocketAddr) -> io::Result<Self> {
+        if addr.is_ipv4() {
+            return Self::bind(addr);
+        }
+        let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_only_v6(false)?;
+        socket.bind(&addr.into())?;
+        Ok(Self {
+            dual_stack: true,
+            ..Self::new(socket.into())
+        })
+    }
+
+    /// Binds a socket to receive the datagrams sent to the multicast `group` on `port`,
+    /// joining it on the interface with the address `interface`.
+    /// Other receivers on the same host can bind the same port to join the group too.
+    pub fn bind_multicast(group: Ipv4Addr, port: u16, interface: Ipv4Addr) -> io::Result<Self> {
+        let socket = Self::bind_shared(port)?;
+        socket.join_multicast_v4(&group, &interface)?;
+        Ok(Self::new(socket.into()))
+    }
+
+    /// Binds a socket to receive broadcast datagrams on `port`.
+    /// Other receivers on the same host can bind the same port too.
+    pub fn bind_broadcast(port: u16) -> io::Result<Self> {
+        Ok(Self::new(Self::bind_shared(port)?.into()))
+    }
+
+    /// Binds a socket to the wildcard address with `SO_REUSEADDR`, so that every socket
+    /// bound to the port gets a copy of multicast and broadcast datagrams.
+    fn bind_shared(port: u16) -> io::Result<Socket> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port).into())?;
+        Ok(socket)
+    }
+
+    /// Sets up the socket to send to multicast groups through the interface with the address
+    /// `interface`, reaching up to `ttl` hops. The datagrams are looped back to the receivers
+    /// on this host as well.
+    pub fn set_multicast_sender(&self, ttl: u32, interface: Ipv4Addr) -> io::Result<()> {
+        let socket = SockRef::from(&self.socket);
+        socket.set_multicast_if_v4(&interface)?;
+        socket.set_multicast_ttl_v4(ttl)?;
+        socket.set_multicast_loop_v4(true)
+    }
+
+    pub fn socket(&self) -> &UdpSocket {
+        &self.socket
+    }
+}
+
+impl Transport for UdpTransport {
+    fn send_to(&mut self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        let addr = match addr {
+            SocketAddr::V4(v4) if self.dual_stack => {
+                SocketAddrV6::new(v4.ip().to_ipv6_mapped(), v4.port(), 0, 0).into()
+            }
+            addr => addr,
+        };
+        match self.socket.send_to(buf, addr) {
+            // The socket buffer is full, which is no different from a lost packet
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(0),
+            res => res,
+        }
+    }
+
+    fn recv_from(
+        &mut self,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> io::Result<Option<(usize, SocketAddr)>> {
+        if self.timeout != Some(timeout) {
+            if timeout.is_zero() {
+                self.socket.set_nonblocking(true)?;
+            } else {
+                self.socket.set_nonblocking(false)?;
+                self.socket.set_read_timeout(Some(timeout))?;
+            }
+            self.timeout = Some(timeout);
+        }
+        match self.socket.recv_from(buf) {
+            Ok((len, SocketAddr::V6(v6))) if self.dual_stack => {
+                // Report IPv4 peers by their IPv4 addresses, as they were given to send_to
+                let src = match v6.ip().to_ipv4_mapped() {
+                    Some(ip) => SocketAddr::new(ip.into(), v6.port()),
+                    None => v6.into(),
+                };
+                Ok(Some((len, src)))
+            }
+            Ok(res) => Ok(Some(res)),
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => Ok(None),
+            // Windows reports ICMP port unreachable of a previous send as an error on receive
+            Err(e) if e.kind() == ErrorKind::ConnectionReset => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+type Datagram = (Vec<u8>, SocketAddr);
+
+/// An in-memory network that routes datagrams between [`MemoryTransport`]s by address.
+/// Datagrams sent to an address nobody is bound to are silently discarded.
+#[derive(Clone, Default)]
+pub struct MemoryNetwork {
+    endpoints: Arc<Mutex<HashMap<SocketAddr, Sender<Datagram>>>>,
+}
+
+impl MemoryNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn bind(&self, addr: SocketAddr) -> io::Result<MemoryTransport> {
+        let mut endpoints = self.endpoints.lock().unwrap();
+        if endpoints.contains_key(&addr) {
+            return Err(io::Error::new(
+                ErrorKind::AddrInUse,
+                format!("{addr} is already bound"),
+            ));
+        }
+        let (tx, rx) = mpsc::channel();
+        endpoints.insert(addr, tx);
+        Ok(MemoryTransport {
+            network: self.clone(),
+            addr,
+            rx,
+        })
+    }
+}
+
+pub struct MemoryTransport {
+    network: MemoryNetwork,
+    addr: SocketAddr,
+    rx: Receiver<Datagram>,
+}
+
+impl Transport for MemoryTransport {
+    fn send_to(&mut self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        if let Some(tx) = self.network.endpoints.lock().unwrap().get(&addr) {
+            // The receiving end may have been dropped, which is the same as nobody listening
+            let _ = tx.send((buf.to_vec(), self.addr));
+        }
+        Ok(buf.len())
+    }
+
+    fn recv_from(
+        &mut self,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> io::Result<Option<(usize, SocketAddr)>> {
+        let res = if timeout.is_zero() {
+            self.rx.try_recv().map_err(|e| match e {
+                TryRecvError::Empty => RecvTimeoutError::Timeout,
+                TryRecvError::Disconnected => RecvTimeoutError::Disconnected,
+            })
+        } else {
+            self.rx.recv_timeout(timeout)
+        };
+        match res {
+            Ok((data, src)) => {
+                // Truncate like UDP does if the buffer is too small
+                let len = data.len().min(buf.len());
+                buf[..len].copy_from_slice(&data[..len]);
+                Ok(Some((len, src)))
+            }
+            Err(_) => Ok(None),
+        }
+    }
+}
+
+impl Drop for MemoryTransport {
+    fn drop(&mut self) {
+        self.network.endpoints.lock().unwrap().remove(&self.addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv6Addr;
+
+    use crate::impair::{ImpairedTransport, ImpairmentConfig};
+
+    const WAIT: Duration = Duration::from_secs(1);
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], port))
+    }
+
+    fn recv(transport: &mut impl Transport, timeout: Duration) -> Option<(Vec<u8>, SocketAddr)> {
+        let mut buf = [0u8; 64];
+        let (len, src) = transport.recv_from(&mut buf, timeout).unwrap()?;
+        Some((buf[..len].to_vec(), src))
+    }
+
+    #[test]
+    fn memory_network_routes_by_address() {
+        let network = MemoryNetwork::new();
+        let mut a = network.bind(addr(1)).unwrap();
+        let mut b = network.bind(addr(2)).unwrap();
+        let mut c = network.bind(addr(3)).unwrap();
+
+        assert_eq!(a.send_to(b"to b", addr(2)).unwrap(), 4);
+        assert_eq!(c.send_to(b"to a", addr(1)).unwrap(), 4);
+        // Nobody is listening, which is not an error
+        assert_eq!(a.send_to(b"lost", addr(4)).unwrap(), 4);
+
+        assert_eq!(recv(&mut b, WAIT), Some((b"to b".to_vec(), addr(1))));
+        assert_eq!(recv(&mut a, WAIT), Some((b"to a".to_vec(), addr(3))));
+        assert_eq!(recv(&mut b, Duration::ZERO), None);
+        assert_eq!(recv(&mut c, Duration::ZERO), None);
+    }
+
+    #[test]
+    fn memory_network_keeps_the_order_and_truncates() {
+        let network = MemoryNetwork::new();
+        let mut a = network.bind(addr(1)).unwrap();
+        let mut b = network.bind(addr(2)).unwrap();
+        for i in 0..10u8 {
+            a.send_to(&[i; 100], addr(2)).unwrap();
+        }
+        for i in 0..10u8 {
+            let (data, _) = recv(&mut b, Duration::ZERO).unwrap();
+            assert_eq!(data, [i; 64]);
+        }
+        assert_eq!(recv(&mut b, Duration::from_millis(10)), None);
+    }
+
+    #[test]
+    fn memory_network_binds_each_address_once() {
+        let network = MemoryNetwork::new();
+        let a = network.bind(addr(1)).unwrap();
+        let err = network.bind(addr(1)).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::AddrInUse);
+        drop(a);
+
+        // Dropping a transport releases its address and stops the delivery to it
+        let mut b = network.bind(addr(2)).unwrap();
+        b.send_to(b"x", addr(1)).unwrap();
+        let mut a = network.bind(addr(1)).unwrap();
+        assert_eq!(recv(&mut a, Duration::ZERO), None);
+    }
+
+    #[test]
+    fn impaired_transport_passes_datagrams_without_impairment() {
+        let network = MemoryNetwork::new();
+        let config = ImpairmentConfig {
+            seed: Some(0),
+            ..ImpairmentConfig::default()
+        };
+        let mut a = ImpairedTransport::new(network.bind(addr(1)).unwrap(), config);
+        let mut b = ImpairedTransport::new(network.bind(addr(2)).unwrap(), config);
+        a.send_to(b"hello", addr(2)).unwrap();
+        assert_eq!(recv(&mut b, WAIT), Some((b"hello".to_vec(), addr(1))));
+        assert_eq!(a.stats().0.passed, 1);
+        assert_eq!(b.stats().1.passed, 1);
+    }
+
+    #[test]
+    fn impaired_transport_drops_and_delays() {
+        let network = MemoryNetwork::new();
+        let mut a = network.bind(addr(1)).unwrap();
+        let mut lossy = ImpairedTransport::new(
+            network.bind(addr(2)).unwrap(),
+            ImpairmentConfig {
+                loss: 1.,
+                seed: Some(0),
+                ..ImpairmentConfig::default()
+            },
+        );
+        // Sending reports success even if the datagram is dropped
+        assert_eq!(lossy.send_to(b"x", addr(1)).unwrap(), 1);
+        a.send_to(b"y", addr(2)).unwrap();
+        assert_eq!(recv(&mut a, Duration::from_millis(10)), None);
+        assert_eq!(recv(&mut lossy, Duration::from_millis(10)), None);
+        assert_eq!(lossy.stats().0.dropped, 1);
+        assert_eq!(lossy.stats().1.dropped, 1);
+
+        let mut slow = ImpairedTransport::new(
+            network.bind(addr(3)).unwrap(),
+            ImpairmentConfig {
+                latency: 50.,
+                seed: Some(0),
+                ..ImpairmentConfig::default()
+            },
+        );
+        a.send_to(b"z", addr(3)).unwrap();
+        assert_eq!(recv(&mut slow, Duration::ZERO), None);
+        assert_eq!(recv(&mut slow, WAIT), Some((b"z".to_vec(), addr(1))));
+    }
+
+    #[test]
+    fn udp_transport_loopback() {
+        let mut a = UdpTransport::bind("127.0.0.1:0").unwrap();
+        let mut b = UdpTransport::bind("127.0.0.1:0").unwrap();
+        let a_addr = a.socket().local_addr().unwrap();
+        let b_addr = b.socket().local_addr().unwrap();
+
+        assert_eq!(recv(&mut b, Duration::ZERO), None);
+        a.send_to(b"ping", b_addr).unwrap();
+        assert_eq!(recv(&mut b, WAIT), Some((b"ping".to_vec(), a_addr)));
+        b.send_to(b"pong", a_addr).unwrap();
+        assert_eq!(recv(&mut a, WAIT), Some((b"pong".to_vec(), b_addr)));
+    }
+
+    #[test]
+    fn dual_stack_udp_transport_maps_ipv4_peers() {
+        let mut dual = UdpTransport::bind_dual_stack("[::]:0".parse().unwrap()).unwrap();
+        let port = dual.socket().local_addr().unwrap().port();
+        let mut v4 = UdpTransport::bind("127.0.0.1:0").unwrap();
+        let v4_addr = v4.socket().local_addr().unwrap();
+
+        // IPv4 peers are reported and addressed by their IPv4 addresses, not IPv4-mapped ones
+        v4.send_to(b"from v4", SocketAddr::from(([127, 0, 0, 1], port)))
+            .unwrap();
+        assert_eq!(recv(&mut dual, WAIT), Some((b"from v4".to_vec(), v4_addr)));
+        dual.send_to(b"to v4", v4_addr).unwrap();
+        assert_eq!(
+            recv(&mut v4, WAIT),
+            Some((b"to v4".to_vec(), SocketAddr::from(([127, 0, 0, 1], port))))
+        );
+
+        // IPv6 peers are left as they are
+        let mut v6 = UdpTransport::bind("[::1]:0").unwrap();
+        let v6_addr = v6.socket().local_addr().unwrap();
+        v6.send_to(
+            b"from v6",
+            SocketAddr::new(Ipv6Addr::LOCALHOST.into(), port),
+        )
+        .unwrap();
+        assert_eq!(recv(&mut dual, WAIT), Some((b"from v6".to_vec(), v6_addr)));
+        dual.send_to(b"to v6", v6_addr).unwrap();
+        assert_eq!(
+            recv(&mut v6, WAIT),
+            Some((
+                b"to v6".to_vec(),
+                SocketAddr::new(Ipv6Addr::LOCALHOST.into(), port)
+            ))
+        );
+    }
+
+    #[test]
+    fn dual_stack_binding_to_ipv4_is_plain() {
+        let transport = UdpTransport::bind_dual_stack("127.0.0.1:0".parse().unwrap()).unwrap();
+        assert!(!transport.dual_stack);
+        assert!(transport.socket().local_addr().unwrap().is_ipv4());
+    }
+}
@@ -0,0 +1,219 @@
+//! Primitives for the canonical wire encoding.
+//!
+//! Everything on the wire is little-endian with a fixed width, regardless of the
+//! host's endianness or word size, so that e.g. a 32-bit ARM receiver can talk
+//! to a 64-bit x86 sender. Indices and counts are sent as `u32` instead of `usize`.
+
+use crate::{protocol::ProtocolError, Object};
+
+/// The size of an [`Object`] on the wire: 4 `f64`s for `pos` and `velo`, and 3 bytes of color.
+pub const OBJECT_WIRE_SIZE: usize = 4 * 8 + 3;
+
+/// Returns the 32-bit FNV-1a hash of `buf`, which detects any corruption confined to a byte.
+pub fn checksum(buf: &[u8]) -> u32 {
+    buf.iter().fold(0x811c9dc5, |hash, byte| {
+        (hash ^ *byte as u32).wrapping_mul(0x01000193)
+    })
+}
+
+#[derive(Default)]
+pub struct WireWriter {
+    buf: Vec<u8>,
+}
+
+impl WireWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.buf
+    }
+
+    /// Appends the [`checksum`] of everything written so far.
+    pub fn checksum(&mut self) {
+        self.u32(checksum(&self.buf));
+    }
+
+    pub fn u8(&mut self, v: u8) {
+        self.buf.push(v);
+    }
+
+    pub fn u16(&mut self, v: u16) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, v: u32) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, v: u64) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn f64(&mut self, v: f64) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn bytes(&mut self, v: &[u8]) {
+        self.buf.extend_from_slice(v);
+    }
+
+    pub fn object(&mut self, obj: &Object) {
+        for v in obj.pos.iter().chain(obj.velo.iter()) {
+            self.f64(*v);
+        }
+        self.bytes(&obj.color);
+    }
+}
+
+/// A cursor over a received buffer that fails with [`ProtocolError::Truncated`]
+/// instead of panicking when the buffer is too short.
+pub struct WireReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    /// The offset of `buf` in the whole datagram, used only for error reporting.
+    base: usize,
+}
+
+impl<'a> WireReader<'a> {
+    pub fn new(buf: &'a [u8], base: usize) -> Self {
+        Self { buf, pos: 0, base }
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.buf.len() - self.pos
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], ProtocolError> {
+        if self.remaining() < len {
+            return Err(ProtocolError::Truncated {
+                expected: self.base + self.pos + len,
+                actual: self.base + self.buf.len(),
+            });
+        }
+        let ret = &self.buf[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(ret)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], ProtocolError> {
+        let mut ret = [0u8; N];
+        ret.copy_from_slice(self.bytes(N)?);
+        Ok(ret)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, ProtocolError> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, ProtocolError> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, ProtocolError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, ProtocolError> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    pub fn f64(&mut self) -> Result<f64, ProtocolError> {
+        Ok(f64::from_le_bytes(self.array()?))
+    }
+
+    pub fn object(&mut self) -> Result<Object, ProtocolError> {
+        let mut obj = Object::default();
+        for v in obj.pos.iter_mut().chain(obj.velo.iter_mut()) {
+            *v = self.f64()?;
+        }
+        obj.color = self.array()?;
+        Ok(obj)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn little_endian_layout() {
+        let mut writer = WireWriter::new();
+        writer.u8(0x01);
+        writer.u16(0x0302);
+        writer.u32(0x07060504);
+        writer.u64(0x0f0e0d0c0b0a0908);
+        assert_eq!(writer.into_inner(), (1..=15).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn primitives_round_trip() {
+        let mut writer = WireWriter::new();
+        writer.u8(u8::MAX);
+        writer.u16(0xbeef);
+        writer.u32(u32::MAX - 1);
+        writer.u64(u64::MAX - 2);
+        writer.f64(-1.5e-300);
+        writer.bytes(b"abc");
+        let buf = writer.into_inner();
+        let mut reader = WireReader::new(&buf, 0);
+        assert_eq!(reader.u8(), Ok(u8::MAX));
+        assert_eq!(reader.u16(), Ok(0xbeef));
+        assert_eq!(reader.u32(), Ok(u32::MAX - 1));
+        assert_eq!(reader.u64(), Ok(u64::MAX - 2));
+        assert_eq!(reader.f64(), Ok(-1.5e-300));
+        assert_eq!(reader.bytes(3), Ok(&b"abc"[..]));
+        assert_eq!(reader.remaining(), 0);
+    }
+
+    #[test]
+    fn object_round_trip() {
+        let mut obj = Object::new([1.25, 9.5], [10, 20, 30]);
+        obj.velo = [-0.3, 0.4];
+        let mut writer = WireWriter::new();
+        writer.object(&obj);
+        let buf = writer.into_inner();
+        assert_eq!(buf.len(), OBJECT_WIRE_SIZE);
+        let decoded = WireReader::new(&buf, 0).object().unwrap();
+        assert_eq!(decoded.pos, obj.pos);
+        assert_eq!(decoded.velo, obj.velo);
+        assert_eq!(decoded.color, obj.color);
+    }
+
+    #[test]
+    fn truncated_reports_offsets_in_datagram() {
+        let buf = [0u8; 6];
+        let mut reader = WireReader::new(&buf, 16);
+        assert_eq!(reader.u32(), Ok(0));
+        assert_eq!(
+            reader.u32(),
+            Err(ProtocolError::Truncated {
+                expected: 24,
+                actual: 22
+            })
+        );
+        // A failed read does not consume anything
+        assert_eq!(reader.remaining(), 2);
+        assert!(WireReader::new(&buf, 0).object().is_err());
+    }
+
+    #[test]
+    fn checksum_detects_a_flipped_bit() {
+        let buf = b"patchjuggler".to_vec();
+        let sum = checksum(&buf);
+        for i in 0..buf.len() * 8 {
+            let mut corrupted = buf.clone();
+            corrupted[i / 8] ^= 1 << (i % 8);
+            assert_ne!(checksum(&corrupted), sum);
+        }
+    }
+}